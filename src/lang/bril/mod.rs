@@ -0,0 +1,930 @@
+//! Runs programs in Bril, the Big Red Intermediate Language
+//! (https://capra.cs.cornell.edu/bril/), from the JSON that `bril2json` makes
+//! of them, as read by `bril-rs`.
+//!
+//! This follows `brili`, the reference interpreter, for the core language and
+//! the SSA, memory, float and speculation extensions, and counts the
+//! instructions it runs like `brili -p` does. Labels aren't instructions, so
+//! don't count.
+
+use bril_rs::{Code, EffectOps, Function, Instruction, Literal, Program, Type, ValueOps};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    fs::File,
+    io::{self, BufReader, Write},
+    process,
+};
+
+/// The most values a single `alloc` can make room for.
+pub const MAX_ALLOCATION: i64 = 1 << 24;
+
+/// Reads a program from the JSON at `path`. JSON that isn't a program is an
+/// error, where `bril_rs::load_program_from_read` would panic.
+pub fn load(path: &str) -> io::Result<Program> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(serde_json::from_reader(file)?)
+}
+
+/// Runs the program in the JSON file at `path`, passing `args` to its `main`,
+/// and reports the instruction count on stderr. Exits with status 1 if the
+/// program can't be read or goes wrong.
+pub fn run_file(path: &str, args: &[String]) {
+    let program = load(path).unwrap_or_else(|e| {
+        eprintln!("could not read {}: {}", path, e);
+        process::exit(1);
+    });
+    let stdout = io::stdout();
+    match run(&program, args, stdout.lock()) {
+        Ok(instructions) => eprintln!("total_dyn_inst: {}", instructions),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs `program`'s `main`, reading its arguments from `args` and printing to
+/// `out`, and returns how many instructions it ran. Everything it allocates
+/// has to have been freed by the time it's done.
+pub fn run<W: Write>(program: &Program, args: &[String], out: W) -> Result<u64, BrilError> {
+    let functions: Functions<'_> = program
+        .functions
+        .iter()
+        .map(|function| (function.name.as_str(), Callee::new(function)))
+        .collect();
+    let main = functions.get("main").ok_or(BrilErrorKind::NoMain)?;
+    let params = &main.function.args;
+    if params.len() != args.len() {
+        return Err(BrilErrorKind::ArgumentCount {
+            expected: params.len(),
+            found: args.len(),
+        }
+        .into());
+    }
+    let values = params
+        .iter()
+        .zip(args)
+        .map(|(param, arg)| {
+            Value::parse(arg, &param.arg_type)
+                .ok_or_else(|| BrilErrorKind::BadArgument(arg.clone()))
+        })
+        .collect::<Result<_, _>>()?;
+
+    let mut machine = Machine {
+        heap: Vec::new(),
+        out,
+        instructions: 0,
+    };
+    machine.call(&functions, main, main.bind(values)?)?;
+    match machine.heap.iter().filter(|cells| cells.is_some()).count() {
+        0 => Ok(machine.instructions),
+        leaked => Err(BrilErrorKind::Leak(leaked).into()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    Float(f64),
+    Pointer(Pointer),
+}
+
+/// A place in one of the heap's allocations. `ptradd` can move it anywhere;
+/// it's only checked once it's used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pointer {
+    allocation: usize,
+    offset: i64,
+}
+
+impl Value {
+    /// `bril2json` writes whole floats like `2.0` as `2`, so the type decides.
+    fn from_literal(literal: &Literal, ty: &Type) -> Self {
+        match (literal, ty) {
+            (Literal::Int(i), Type::Float) => Value::Float(*i as f64),
+            (Literal::Int(i), _) => Value::Int(*i),
+            (Literal::Bool(b), _) => Value::Bool(*b),
+            (Literal::Float(x), _) => Value::Float(*x),
+        }
+    }
+
+    /// Reads an argument to `main`. There's no way to write a pointer.
+    fn parse(arg: &str, ty: &Type) -> Option<Self> {
+        match ty {
+            Type::Int => arg.parse().ok().map(Value::Int),
+            Type::Bool => arg.parse().ok().map(Value::Bool),
+            Type::Float => arg.parse().ok().map(Value::Float),
+            Type::Pointer(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Prints values like `brili` does, which shows floats with JavaScript's
+    /// `toFixed(17)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Float(x) if x.is_nan() => write!(f, "NaN"),
+            Value::Float(x) if x.is_infinite() => {
+                write!(f, "{}Infinity", if *x < 0.0 { "-" } else { "" })
+            }
+            Value::Float(x) => write!(f, "{:.17}", x),
+            Value::Pointer(p) => write!(f, "ptr({}, {})", p.allocation, p.offset),
+        }
+    }
+}
+
+/// Where an error happened: the index of the instruction in its function's
+/// `instrs`, labels included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub function: String,
+    pub index: usize,
+}
+
+/// Errors from before or after the program runs have no location.
+#[derive(Debug)]
+pub struct BrilError {
+    pub location: Option<Location>,
+    pub kind: BrilErrorKind,
+}
+
+#[derive(Debug)]
+pub enum BrilErrorKind {
+    NoMain,
+    /// An argument to `main` that can't be read as the type it should be.
+    BadArgument(String),
+    ArgumentCount {
+        expected: usize,
+        found: usize,
+    },
+    UnknownFunction(String),
+    UnknownLabel(String),
+    /// A variable used before anything has set it.
+    Undefined(String),
+    /// An instruction without the arguments, functions or labels it needs.
+    MissingOperand,
+    WrongType {
+        variable: String,
+        expected: &'static str,
+    },
+    /// A function called for its value that didn't return one.
+    NoReturnValue,
+    DivisionByZero,
+    /// An `alloc` of less than one value, or more than `MAX_ALLOCATION`.
+    BadAllocation(i64),
+    /// A pointer outside of its allocation, or into one that's been freed.
+    BadPointer,
+    /// A `load` from somewhere nothing has been stored yet.
+    Uninitialized,
+    /// A `free` of anything but the start of a live allocation.
+    BadFree,
+    /// How many allocations were never freed.
+    Leak(usize),
+    /// A `commit` or `guard` outside of any speculation.
+    NotSpeculating,
+    /// Returning would leave a speculation that couldn't then be undone.
+    ReturnWhileSpeculating,
+    Io(io::Error),
+}
+
+impl BrilError {
+    /// Records where the error happened, unless it already knows: errors in
+    /// a call are from the function called.
+    fn at(mut self, function: &str, index: usize) -> Self {
+        self.location.get_or_insert_with(|| Location {
+            function: function.to_string(),
+            index,
+        });
+        self
+    }
+}
+
+impl From<BrilErrorKind> for BrilError {
+    fn from(kind: BrilErrorKind) -> Self {
+        BrilError {
+            location: None,
+            kind,
+        }
+    }
+}
+
+impl From<io::Error> for BrilErrorKind {
+    fn from(e: io::Error) -> Self {
+        BrilErrorKind::Io(e)
+    }
+}
+
+impl fmt::Display for BrilErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use BrilErrorKind::*;
+        match self {
+            NoMain => write!(f, "there's no `main` function"),
+            BadArgument(arg) => write!(f, "`{}` isn't a valid argument", arg),
+            ArgumentCount { expected, found } => {
+                write!(f, "expected {} arguments, but got {}", expected, found)
+            }
+            UnknownFunction(name) => write!(f, "unknown function `@{}`", name),
+            UnknownLabel(label) => write!(f, "unknown label `.{}`", label),
+            Undefined(variable) => write!(f, "undefined variable `{}`", variable),
+            MissingOperand => write!(f, "missing operand"),
+            WrongType { variable, expected } => {
+                write!(f, "`{}` should be of type {}", variable, expected)
+            }
+            NoReturnValue => write!(f, "the function called didn't return a value"),
+            DivisionByZero => write!(f, "division by zero"),
+            BadAllocation(size) => write!(f, "can't allocate {} values", size),
+            BadPointer => write!(f, "pointer out of bounds"),
+            Uninitialized => write!(f, "load from uninitialized memory"),
+            BadFree => write!(f, "can only free the start of a live allocation"),
+            Leak(count) => write!(f, "{} allocations were never freed", count),
+            NotSpeculating => write!(f, "not speculating"),
+            ReturnWhileSpeculating => write!(f, "can't return while speculating"),
+            Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl fmt::Display for BrilError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(Location { function, index }) => {
+                write!(f, "@{}, instruction {}: {}", function, index, self.kind)
+            }
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for BrilError {}
+
+type Functions<'p> = HashMap<&'p str, Callee<'p>>;
+
+type Env<'p> = HashMap<&'p str, Value>;
+
+/// A function, along with where each of its labels is.
+struct Callee<'p> {
+    function: &'p Function,
+    labels: HashMap<&'p str, usize>,
+}
+
+impl<'p> Callee<'p> {
+    fn new(function: &'p Function) -> Self {
+        let labels = function
+            .instrs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, code)| match code {
+                Code::Label { label } => Some((label.as_str(), index)),
+                Code::Instruction(_) => None,
+            })
+            .collect();
+        Callee { function, labels }
+    }
+
+    /// Makes the environment the function starts off with.
+    fn bind(&self, args: Vec<Value>) -> Result<Env<'p>, BrilErrorKind> {
+        let params = &self.function.args;
+        if params.len() != args.len() {
+            return Err(BrilErrorKind::ArgumentCount {
+                expected: params.len(),
+                found: args.len(),
+            });
+        }
+        Ok(params.iter().map(|p| p.name.as_str()).zip(args).collect())
+    }
+}
+
+struct Frame<'p> {
+    env: Env<'p>,
+    /// The label of the block running, if it has one.
+    label: Option<&'p str>,
+    /// The label of the block before it, that `phi`s choose by.
+    last_label: Option<&'p str>,
+    /// The environments to go back to if a guard fails, innermost last.
+    speculations: Vec<Env<'p>>,
+}
+
+impl<'p> Frame<'p> {
+    fn get(&self, args: &[String], i: usize) -> Result<Value, BrilErrorKind> {
+        let name = args.get(i).ok_or(BrilErrorKind::MissingOperand)?;
+        let value = self.env.get(name.as_str()).copied();
+        value.ok_or_else(|| BrilErrorKind::Undefined(name.clone()))
+    }
+
+    fn typed<T>(
+        &self,
+        args: &[String],
+        i: usize,
+        expected: &'static str,
+        unwrap: impl Fn(Value) -> Option<T>,
+    ) -> Result<T, BrilErrorKind> {
+        unwrap(self.get(args, i)?).ok_or_else(|| BrilErrorKind::WrongType {
+            variable: args[i].clone(),
+            expected,
+        })
+    }
+
+    fn int(&self, args: &[String], i: usize) -> Result<i64, BrilErrorKind> {
+        self.typed(args, i, "int", |value| match value {
+            Value::Int(i) => Some(i),
+            _ => None,
+        })
+    }
+
+    fn bool(&self, args: &[String], i: usize) -> Result<bool, BrilErrorKind> {
+        self.typed(args, i, "bool", |value| match value {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        })
+    }
+
+    fn float(&self, args: &[String], i: usize) -> Result<f64, BrilErrorKind> {
+        self.typed(args, i, "float", |value| match value {
+            Value::Float(x) => Some(x),
+            _ => None,
+        })
+    }
+
+    fn pointer(&self, args: &[String], i: usize) -> Result<Pointer, BrilErrorKind> {
+        self.typed(args, i, "ptr", |value| match value {
+            Value::Pointer(p) => Some(p),
+            _ => None,
+        })
+    }
+}
+
+/// What to do after an instruction.
+enum Flow<'p> {
+    Next,
+    Jump(&'p str),
+    Return(Option<Value>),
+}
+
+struct Machine<W> {
+    /// Allocations are never reused, so that pointers into freed ones can be
+    /// caught: they're just emptied.
+    heap: Vec<Option<Vec<Option<Value>>>>,
+    out: W,
+    instructions: u64,
+}
+
+impl<W: Write> Machine<W> {
+    fn call<'p>(
+        &mut self,
+        functions: &'p Functions<'p>,
+        callee: &'p Callee<'p>,
+        env: Env<'p>,
+    ) -> Result<Option<Value>, BrilError> {
+        let function = callee.function;
+        let mut frame = Frame {
+            env,
+            label: None,
+            last_label: None,
+            speculations: Vec::new(),
+        };
+        let mut pc = 0;
+        while let Some(code) = function.instrs.get(pc) {
+            let instr = match code {
+                Code::Label { label } => {
+                    frame.last_label = frame.label.replace(label);
+                    pc += 1;
+                    continue;
+                }
+                Code::Instruction(instr) => instr,
+            };
+            self.instructions += 1;
+            let flow = self
+                .execute(functions, &mut frame, instr)
+                .map_err(|e| e.at(&function.name, pc))?;
+            match flow {
+                Flow::Next => pc += 1,
+                Flow::Jump(label) => match callee.labels.get(label) {
+                    Some(&index) => pc = index,
+                    None => {
+                        let kind = BrilErrorKind::UnknownLabel(label.to_string());
+                        return Err(BrilError::from(kind).at(&function.name, pc));
+                    }
+                },
+                Flow::Return(value) => return Ok(value),
+            }
+        }
+        Ok(None)
+    }
+
+    fn execute<'p>(
+        &mut self,
+        functions: &'p Functions<'p>,
+        frame: &mut Frame<'p>,
+        instr: &'p Instruction,
+    ) -> Result<Flow<'p>, BrilError> {
+        match instr {
+            Instruction::Constant {
+                dest,
+                const_type,
+                value,
+                ..
+            } => {
+                let value = Value::from_literal(value, const_type);
+                frame.env.insert(dest, value);
+            }
+            Instruction::Value {
+                args,
+                dest,
+                funcs,
+                labels,
+                op,
+                ..
+            } => match self.value(functions, frame, op, args, funcs, labels)? {
+                Some(value) => {
+                    frame.env.insert(dest, value);
+                }
+                None => {
+                    frame.env.remove(dest.as_str());
+                }
+            },
+            Instruction::Effect {
+                args,
+                funcs,
+                labels,
+                op,
+            } => return self.effect(functions, frame, op, args, funcs, labels),
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Works out the value of a value operation. Only a `phi` can come out
+    /// without one, when there's no value for the way it was reached.
+    fn value<'p>(
+        &mut self,
+        functions: &'p Functions<'p>,
+        frame: &Frame<'p>,
+        op: &ValueOps,
+        args: &[String],
+        funcs: &[String],
+        labels: &[String],
+    ) -> Result<Option<Value>, BrilError> {
+        use ValueOps::*;
+        let int = |i| frame.int(args, i);
+        let bool = |i| frame.bool(args, i);
+        let float = |i| frame.float(args, i);
+        let value = match op {
+            Add => Value::Int(int(0)?.wrapping_add(int(1)?)),
+            Sub => Value::Int(int(0)?.wrapping_sub(int(1)?)),
+            Mul => Value::Int(int(0)?.wrapping_mul(int(1)?)),
+            Div => match (int(0)?, int(1)?) {
+                (_, 0) => return Err(BrilErrorKind::DivisionByZero.into()),
+                (a, b) => Value::Int(a.wrapping_div(b)),
+            },
+            Eq => Value::Bool(int(0)? == int(1)?),
+            Lt => Value::Bool(int(0)? < int(1)?),
+            Gt => Value::Bool(int(0)? > int(1)?),
+            Le => Value::Bool(int(0)? <= int(1)?),
+            Ge => Value::Bool(int(0)? >= int(1)?),
+            Not => Value::Bool(!bool(0)?),
+            And => Value::Bool(bool(0)? & bool(1)?),
+            Or => Value::Bool(bool(0)? | bool(1)?),
+            Call => {
+                let value = self.call_function(functions, frame, args, funcs)?;
+                value.ok_or(BrilErrorKind::NoReturnValue)?
+            }
+            Id => frame.get(args, 0)?,
+            Phi => {
+                let arg = frame
+                    .last_label
+                    .and_then(|last| labels.iter().position(|label| label == last))
+                    .and_then(|i| args.get(i));
+                return Ok(arg.and_then(|arg| frame.env.get(arg.as_str()).copied()));
+            }
+            Fadd => Value::Float(float(0)? + float(1)?),
+            Fsub => Value::Float(float(0)? - float(1)?),
+            Fmul => Value::Float(float(0)? * float(1)?),
+            Fdiv => Value::Float(float(0)? / float(1)?),
+            Feq => Value::Bool(float(0)? == float(1)?),
+            Flt => Value::Bool(float(0)? < float(1)?),
+            Fgt => Value::Bool(float(0)? > float(1)?),
+            Fle => Value::Bool(float(0)? <= float(1)?),
+            Fge => Value::Bool(float(0)? >= float(1)?),
+            Alloc => {
+                let size = int(0)?;
+                if !(1..=MAX_ALLOCATION).contains(&size) {
+                    return Err(BrilErrorKind::BadAllocation(size).into());
+                }
+                self.heap.push(Some(vec![None; size as usize]));
+                Value::Pointer(Pointer {
+                    allocation: self.heap.len() - 1,
+                    offset: 0,
+                })
+            }
+            Load => {
+                let cell = *self.cell(frame.pointer(args, 0)?)?;
+                cell.ok_or(BrilErrorKind::Uninitialized)?
+            }
+            PtrAdd => {
+                let pointer = frame.pointer(args, 0)?;
+                Value::Pointer(Pointer {
+                    offset: pointer.offset.wrapping_add(int(1)?),
+                    ..pointer
+                })
+            }
+        };
+        Ok(Some(value))
+    }
+
+    fn effect<'p>(
+        &mut self,
+        functions: &'p Functions<'p>,
+        frame: &mut Frame<'p>,
+        op: &EffectOps,
+        args: &[String],
+        funcs: &[String],
+        labels: &'p [String],
+    ) -> Result<Flow<'p>, BrilError> {
+        let label = |i: usize| {
+            let label = labels.get(i).ok_or(BrilErrorKind::MissingOperand)?;
+            Ok::<_, BrilErrorKind>(Flow::Jump(label))
+        };
+        match op {
+            EffectOps::Jump => return Ok(label(0)?),
+            EffectOps::Branch => return Ok(label(if frame.bool(args, 0)? { 0 } else { 1 })?),
+            EffectOps::Call => {
+                self.call_function(functions, frame, args, funcs)?;
+            }
+            EffectOps::Return => {
+                if !frame.speculations.is_empty() {
+                    return Err(BrilErrorKind::ReturnWhileSpeculating.into());
+                }
+                let value = if args.is_empty() {
+                    None
+                } else {
+                    Some(frame.get(args, 0)?)
+                };
+                return Ok(Flow::Return(value));
+            }
+            EffectOps::Print => {
+                let values = (0..args.len())
+                    .map(|i| frame.get(args, i).map(|value| value.to_string()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                writeln!(self.out, "{}", values.join(" ")).map_err(BrilErrorKind::from)?;
+            }
+            EffectOps::Nop => {}
+            EffectOps::Store => {
+                let value = frame.get(args, 1)?;
+                *self.cell(frame.pointer(args, 0)?)? = Some(value);
+            }
+            EffectOps::Free => {
+                let pointer = frame.pointer(args, 0)?;
+                match self.heap.get_mut(pointer.allocation) {
+                    Some(cells @ Some(_)) if pointer.offset == 0 => *cells = None,
+                    _ => return Err(BrilErrorKind::BadFree.into()),
+                }
+            }
+            EffectOps::Speculate => frame.speculations.push(frame.env.clone()),
+            EffectOps::Commit => {
+                frame
+                    .speculations
+                    .pop()
+                    .ok_or(BrilErrorKind::NotSpeculating)?;
+            }
+            EffectOps::Guard => {
+                if frame.speculations.is_empty() {
+                    return Err(BrilErrorKind::NotSpeculating.into());
+                }
+                // Failing a guard undoes the speculation: but only its effect
+                // on variables, like in `brili`, not on memory.
+                if !frame.bool(args, 0)? {
+                    let flow = label(0)?;
+                    frame.env = frame.speculations.pop().unwrap();
+                    return Ok(flow);
+                }
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn call_function<'p>(
+        &mut self,
+        functions: &'p Functions<'p>,
+        frame: &Frame<'p>,
+        args: &[String],
+        funcs: &[String],
+    ) -> Result<Option<Value>, BrilError> {
+        let name = funcs.first().ok_or(BrilErrorKind::MissingOperand)?;
+        let callee = functions
+            .get(name.as_str())
+            .ok_or_else(|| BrilErrorKind::UnknownFunction(name.clone()))?;
+        let values = (0..args.len())
+            .map(|i| frame.get(args, i))
+            .collect::<Result<_, _>>()?;
+        let env = callee.bind(values)?;
+        self.call(functions, callee, env)
+    }
+
+    fn cell(&mut self, pointer: Pointer) -> Result<&mut Option<Value>, BrilErrorKind> {
+        let cells = self
+            .heap
+            .get_mut(pointer.allocation)
+            .and_then(Option::as_mut);
+        let offset = usize::try_from(pointer.offset).ok();
+        cells
+            .zip(offset)
+            .and_then(|(cells, offset)| cells.get_mut(offset))
+            .ok_or(BrilErrorKind::BadPointer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, path::Path, process::Command};
+
+    /// Runs a program, returning what it printed and how many instructions it
+    /// ran.
+    fn run_json(json: &str, args: &[&str]) -> Result<(String, u64), BrilError> {
+        let program = serde_json::from_str(json).unwrap();
+        let args: Vec<_> = args.iter().map(|arg| arg.to_string()).collect();
+        let mut out = Vec::new();
+        let instructions = run(&program, &args, &mut out)?;
+        Ok((String::from_utf8(out).unwrap(), instructions))
+    }
+
+    fn run_fixture(json: &str, args: &[&str]) -> (String, u64) {
+        run_json(json, args).unwrap()
+    }
+
+    /// A `main` with the given instructions, in JSON.
+    fn main(instrs: &str) -> String {
+        format!(
+            r#"{{"functions": [{{"name": "main", "instrs": [{}]}}]}}"#,
+            instrs
+        )
+    }
+
+    /// These are small programs written for these tests, so their counts are
+    /// worked out by hand. `runs_benchmarks` runs some of Bril's own.
+    #[test]
+    fn runs_fixtures() {
+        let factorial = include_str!("fixtures/factorial.json");
+        // 2 instructions in `main`, 7 for each `fact` that recurses and 4 for
+        // the one that doesn't.
+        assert_eq!(run_fixture(factorial, &["5"]), ("120\n".into(), 34));
+        assert_eq!(
+            run_fixture(factorial, &["20"]),
+            ("2432902008176640000\n".into(), 139)
+        );
+        // Ints wrap around.
+        assert_eq!(run_fixture(factorial, &["21"]).0, "-4249290049419214848\n");
+
+        let sum_squares = include_str!("fixtures/sum-squares.json");
+        assert_eq!(run_fixture(sum_squares, &["10"]), ("285\n".into(), 152));
+        assert_eq!(run_fixture(sum_squares, &["1"]), ("0\n".into(), 26));
+
+        let sqrt = include_str!("fixtures/sqrt.json");
+        assert_eq!(
+            run_fixture(sqrt, &["2"]),
+            ("1.41421356237309492\n".into(), 50)
+        );
+
+        let speculate = include_str!("fixtures/speculate.json");
+        assert_eq!(run_fixture(speculate, &["true"]), ("11\n".into(), 14));
+        // The guard fails, so y goes back to what it was.
+        assert_eq!(run_fixture(speculate, &["false"]), ("20\n".into(), 12));
+    }
+
+    /// The arguments from a benchmark's `# ARGS:` line.
+    fn benchmark_args(source: &str) -> Vec<String> {
+        source
+            .lines()
+            .find_map(|line| line.strip_prefix("# ARGS:"))
+            .map_or(Vec::new(), |args| {
+                args.split_whitespace().map(String::from).collect()
+            })
+    }
+
+    /// Runs a benchmark, checking what it prints against its `.out` file and
+    /// how many instructions it runs against its `.prof` file, if it has one.
+    fn check_benchmark(
+        program: &Program,
+        args: &[String],
+        expected: &str,
+        prof: Option<&str>,
+    ) -> Result<(), String> {
+        let mut out = Vec::new();
+        let instructions = run(program, args, &mut out).map_err(|e| e.to_string())?;
+        let out = String::from_utf8_lossy(&out);
+        if out != expected {
+            return Err(format!("printed {:?}, not {:?}", out, expected));
+        }
+        match prof {
+            Some(prof) if prof.trim() != format!("total_dyn_inst: {}", instructions) => Err(
+                format!("ran {} instructions, not {}", instructions, prof.trim()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Programs from Bril's `benchmarks/` directory, in `fixtures/benchmarks`
+    /// as both text and JSON. They were written out without a Bril checkout to
+    /// hand, and their `.out` and `.prof` files come from a separate model of
+    /// `brili -p` rather than from `brili` itself, so `runs_bril_benchmarks`
+    /// is still the one to run against a checkout.
+    #[test]
+    fn runs_benchmarks() {
+        macro_rules! benchmark {
+            ($name:literal) => {
+                (
+                    $name,
+                    include_str!(concat!("fixtures/benchmarks/", $name, ".bril")),
+                    include_str!(concat!("fixtures/benchmarks/", $name, ".json")),
+                    include_str!(concat!("fixtures/benchmarks/", $name, ".out")),
+                    include_str!(concat!("fixtures/benchmarks/", $name, ".prof")),
+                )
+            };
+        }
+        let benchmarks = [
+            benchmark!("loopfact"),
+            benchmark!("recfact"),
+            benchmark!("gcd"),
+            benchmark!("sqrt"),
+            benchmark!("bubblesort"),
+        ];
+        for &(name, source, json, expected, prof) in &benchmarks {
+            let program = serde_json::from_str(json).unwrap();
+            let args = benchmark_args(source);
+            if let Err(e) = check_benchmark(&program, &args, expected, Some(prof)) {
+                panic!("{}: {}", name, e);
+            }
+        }
+    }
+
+    /// Runs the benchmarks in the Bril checkout that `bril-rs` comes from
+    /// against the `.out` and `.prof` files checked in with them, which are
+    /// what `brili -p` prints. Needs `bril2json` on the path. Benchmarks using
+    /// extensions `bril-rs` can't read are skipped.
+    #[test]
+    #[ignore]
+    fn runs_bril_benchmarks() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("bril/benchmarks");
+        let mut ran = 0;
+        let mut failures = Vec::new();
+        for dir in fs::read_dir(&root).expect("benchmarks should be in the bril checkout") {
+            for entry in fs::read_dir(dir.unwrap().path()).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension() != Some("bril".as_ref()) {
+                    continue;
+                }
+                let expected = match fs::read_to_string(path.with_extension("out")) {
+                    Ok(expected) => expected,
+                    Err(_) => continue,
+                };
+                let json = Command::new("bril2json")
+                    .stdin(File::open(&path).unwrap())
+                    .output()
+                    .expect("bril2json should be on the path")
+                    .stdout;
+                let program: Program = match serde_json::from_slice(&json) {
+                    Ok(program) => program,
+                    Err(e) => {
+                        eprintln!("skipping {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                let args = benchmark_args(&fs::read_to_string(&path).unwrap());
+                let prof = fs::read_to_string(path.with_extension("prof")).ok();
+                if let Err(e) = check_benchmark(&program, &args, &expected, prof.as_deref()) {
+                    failures.push(format!("{}: {}", path.display(), e));
+                }
+                ran += 1;
+            }
+        }
+        assert!(ran > 0, "no benchmarks in {}", root.display());
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn loading_bad_json_is_an_error() {
+        let path = env::temp_dir().join(format!("bril_test_{}.json", process::id()));
+        fs::write(
+            &path,
+            r#"{"functions": [{"name": "main", "instrs": [{"op": 5}]}]}"#,
+        )
+        .unwrap();
+        let e = load(path.to_str().unwrap()).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reports_where_errors_happen() {
+        let factorial = include_str!("fixtures/factorial.json");
+        assert!(matches!(
+            run_json(factorial, &["five"]).unwrap_err().kind,
+            BrilErrorKind::BadArgument(arg) if arg == "five"
+        ));
+        assert!(matches!(
+            run_json(factorial, &[]).unwrap_err().kind,
+            BrilErrorKind::ArgumentCount {
+                expected: 1,
+                found: 0
+            }
+        ));
+
+        let divide = main(
+            r#"{"label": "start"},
+            {"op": "const", "dest": "x", "type": "int", "value": 1},
+            {"op": "const", "dest": "zero", "type": "int", "value": 0},
+            {"op": "div", "dest": "y", "type": "int", "args": ["x", "zero"]}"#,
+        );
+        let e = run_json(&divide, &[]).unwrap_err();
+        assert!(matches!(e.kind, BrilErrorKind::DivisionByZero));
+        assert_eq!(
+            e.location,
+            Some(Location {
+                function: "main".into(),
+                index: 3
+            })
+        );
+        assert_eq!(e.to_string(), "@main, instruction 3: division by zero");
+
+        let undefined = main(r#"{"op": "print", "args": ["x"]}"#);
+        assert!(matches!(
+            run_json(&undefined, &[]).unwrap_err().kind,
+            BrilErrorKind::Undefined(x) if x == "x"
+        ));
+        let wrong_type = main(
+            r#"{"op": "const", "dest": "b", "type": "bool", "value": true},
+            {"op": "add", "dest": "c", "type": "int", "args": ["b", "b"]}"#,
+        );
+        assert!(matches!(
+            run_json(&wrong_type, &[]).unwrap_err().kind,
+            BrilErrorKind::WrongType {
+                expected: "int",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn checks_memory() {
+        let alloc = r#"{"op": "const", "dest": "n", "type": "int", "value": 2},
+            {"op": "alloc", "dest": "p", "type": {"ptr": "int"}, "args": ["n"]}"#;
+        let leak = main(alloc);
+        assert!(matches!(
+            run_json(&leak, &[]).unwrap_err().kind,
+            BrilErrorKind::Leak(1)
+        ));
+
+        let uninitialized = main(&format!(
+            r#"{}, {{"op": "load", "dest": "x", "type": "int", "args": ["p"]}}"#,
+            alloc
+        ));
+        assert!(matches!(
+            run_json(&uninitialized, &[]).unwrap_err().kind,
+            BrilErrorKind::Uninitialized
+        ));
+
+        let out_of_bounds = main(&format!(
+            r#"{},
+            {{"op": "ptradd", "dest": "q", "type": {{"ptr": "int"}}, "args": ["p", "n"]}},
+            {{"op": "store", "args": ["q", "n"]}}"#,
+            alloc
+        ));
+        assert!(matches!(
+            run_json(&out_of_bounds, &[]).unwrap_err().kind,
+            BrilErrorKind::BadPointer
+        ));
+
+        let use_after_free = main(&format!(
+            r#"{},
+            {{"op": "free", "args": ["p"]}},
+            {{"op": "store", "args": ["p", "n"]}}"#,
+            alloc
+        ));
+        let e = run_json(&use_after_free, &[]).unwrap_err();
+        assert!(matches!(e.kind, BrilErrorKind::BadPointer));
+        assert_eq!(e.location.unwrap().index, 3);
+    }
+
+    #[test]
+    fn phis_pick_the_way_they_were_reached() {
+        let instrs = r#"{"op": "const", "dest": "a", "type": "int", "value": 1},
+            {"op": "jmp", "labels": ["b"]},
+            {"label": "a"},
+            {"op": "const", "dest": "b", "type": "int", "value": 2},
+            {"label": "b"},
+            {"op": "phi", "dest": "x", "type": "int", "args": ["a", "b"], "labels": ["start", "a"]},
+            {"op": "print", "args": ["x"]}"#;
+        let program = main(&format!(r#"{{"label": "start"}}, {}"#, instrs));
+        assert_eq!(run_json(&program, &[]).unwrap(), ("1\n".into(), 4));
+        // Without a label to say where it came from, `x` isn't set at all.
+        assert!(matches!(
+            run_json(&main(instrs), &[]).unwrap_err().kind,
+            BrilErrorKind::Undefined(x) if x == "x"
+        ));
+    }
+}
@@ -0,0 +1,217 @@
+//! A worklist solver for dataflow analyses over the blocks of a `Cfg`.
+//!
+//! An analysis says what it knows at each point of a program as a `Fact`,
+//! how an instruction changes that, and how to combine the facts from two
+//! paths that meet. The solver applies those until nothing changes, which
+//! happens as long as facts only ever move one way up a lattice of finite
+//! height.
+
+use super::stack_lang::{Block, BlockId, Cfg, Instruction};
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Facts flow from the entry, like which definitions reach where.
+    Forward,
+    /// Facts flow back from the exits, like which registers are live.
+    Backward,
+}
+
+pub trait Analysis<T> {
+    type Fact: Clone + PartialEq;
+
+    const DIRECTION: Direction;
+
+    /// What's known where the analysis starts: going into the entry block
+    /// going forwards, or coming out of the exits going backwards.
+    fn boundary(&self) -> Self::Fact;
+
+    /// The bottom of the lattice, that every other fact starts as. Joining it
+    /// with anything has to leave that thing unchanged.
+    fn bottom(&self) -> Self::Fact;
+
+    /// Combines the facts from two paths into `into`.
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact);
+
+    /// Updates `fact` for the instruction at `index`, which it flows through
+    /// in the analysis' direction: so going backwards, `fact` is what holds
+    /// after the instruction, and should become what holds before it.
+    fn transfer(&self, instr: &T, index: usize, fact: &mut Self::Fact);
+}
+
+/// The facts at the start and end of every block, in program order whichever
+/// way the analysis went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Solution<F> {
+    pub before: Vec<F>,
+    pub after: Vec<F>,
+}
+
+pub fn solve<T: Instruction, A: Analysis<T>>(cfg: &Cfg<T>, analysis: &A) -> Solution<A::Fact> {
+    let n = cfg.blocks.len();
+    let mut solution = Solution {
+        before: vec![analysis.bottom(); n],
+        after: vec![analysis.bottom(); n],
+    };
+    let forward = A::DIRECTION == Direction::Forward;
+
+    // Visiting blocks in reverse postorder (or its reverse, going backwards)
+    // means most of them see their inputs settled first. Blocks that can't be
+    // reached are added after, to get a solution too.
+    let mut order = cfg.reverse_postorder();
+    let mut queued = vec![false; n];
+    for &id in &order {
+        queued[id] = true;
+    }
+    order.extend((0..n).filter(|&id| !queued[id]));
+    if !forward {
+        order.reverse();
+    }
+    let mut worklist: VecDeque<BlockId> = order.into();
+    queued = vec![true; n];
+
+    while let Some(id) = worklist.pop_front() {
+        queued[id] = false;
+        // Going backwards, everything's the other way round: facts come in
+        // from successors, at the end of the block, and go out to predecessors.
+        let (inputs, outputs, is_boundary, ends) = if forward {
+            let is_entry = Some(id) == cfg.entry();
+            let ends = (&mut solution.before, &mut solution.after);
+            (cfg.predecessors(id), cfg.successors(id), is_entry, ends)
+        } else {
+            let inputs = cfg.successors(id);
+            let ends = (&mut solution.after, &mut solution.before);
+            (inputs, cfg.predecessors(id), inputs.is_empty(), ends)
+        };
+        let (starts, ends) = ends;
+
+        let mut fact = if is_boundary {
+            analysis.boundary()
+        } else {
+            analysis.bottom()
+        };
+        for &input in inputs {
+            analysis.join(&mut fact, &ends[input]);
+        }
+        starts[id] = fact.clone();
+
+        transfer_block(analysis, &cfg.blocks[id], &mut fact, |_, _| {});
+        if ends[id] != fact {
+            ends[id] = fact;
+            for &output in outputs {
+                if !queued[output] {
+                    queued[output] = true;
+                    worklist.push_back(output);
+                }
+            }
+        }
+    }
+    solution
+}
+
+/// Runs `fact` through `block` in the analysis' direction, calling `each`
+/// with the index of every instruction and the fact just before it.
+fn transfer_block<T, A: Analysis<T>>(
+    analysis: &A,
+    block: &Block<T>,
+    fact: &mut A::Fact,
+    mut each: impl FnMut(usize, &A::Fact),
+) {
+    let indexes = block.start..block.end();
+    match A::DIRECTION {
+        Direction::Forward => {
+            for (index, instr) in indexes.zip(block.iter()) {
+                each(index, fact);
+                analysis.transfer(instr, index, fact);
+            }
+        }
+        Direction::Backward => {
+            for (index, instr) in indexes.zip(block.iter()).rev() {
+                analysis.transfer(instr, index, fact);
+                each(index, fact);
+            }
+        }
+    }
+}
+
+impl<F: Clone> Solution<F> {
+    /// The fact just before each instruction in the program, worked out by
+    /// going through each block again from the end the analysis started at.
+    pub fn before_each<T: Instruction, A: Analysis<T, Fact = F>>(
+        &self,
+        cfg: &Cfg<T>,
+        analysis: &A,
+    ) -> Vec<F> {
+        let len = cfg.blocks.last().map_or(0, |block| block.end());
+        let mut facts = vec![analysis.bottom(); len];
+        for (id, block) in cfg.blocks.iter().enumerate() {
+            let mut fact = match A::DIRECTION {
+                Direction::Forward => self.before[id].clone(),
+                Direction::Backward => self.after[id].clone(),
+            };
+            transfer_block(analysis, block, &mut fact, |index, fact| {
+                facts[index] = fact.clone()
+            });
+        }
+        facts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::stack_machine::Instruction::{self as Sm, *};
+    use std::collections::BTreeSet;
+
+    /// Which Prints might have run by each point.
+    struct Printed;
+
+    impl Analysis<Sm> for Printed {
+        type Fact = BTreeSet<usize>;
+
+        const DIRECTION: Direction = Direction::Forward;
+
+        fn boundary(&self) -> Self::Fact {
+            BTreeSet::new()
+        }
+
+        fn bottom(&self) -> Self::Fact {
+            BTreeSet::new()
+        }
+
+        fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+            into.extend(other);
+        }
+
+        fn transfer(&self, instr: &Sm, index: usize, fact: &mut Self::Fact) {
+            if *instr == Print {
+                fact.insert(index);
+            }
+        }
+    }
+
+    #[test]
+    fn solves_around_loops() {
+        let program = [
+            Push(vec![1]), // 0
+            Print,
+            Push(vec![1]), // 2: loop
+            Branch(5),
+            Print,
+            Push(vec![1]), // 5
+            Branch(2),
+            Print,
+        ];
+        let cfg = Cfg::new(&program);
+        let solution = solve(&cfg, &Printed);
+        let set = |indexes: &[usize]| indexes.iter().copied().collect::<BTreeSet<_>>();
+        // The loop's Print can have run by the time it comes back round.
+        assert_eq!(solution.before[1], set(&[1, 4]));
+        assert_eq!(solution.after[3], set(&[1, 4]));
+        assert_eq!(solution.after[4], set(&[1, 4, 7]));
+        let before_each = solution.before_each(&cfg, &Printed);
+        assert_eq!(before_each.len(), program.len());
+        assert_eq!(before_each[1], set(&[]));
+        assert_eq!(before_each[7], set(&[1, 4]));
+    }
+}
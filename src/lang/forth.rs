@@ -0,0 +1,652 @@
+//! A small Forth on top of `StackMachine`.
+//!
+//! Words live in a dictionary, which `: name ... ;` adds to. Inside a
+//! definition words are compiled rather than run, except for IMMEDIATE ones,
+//! which run straight away: that's how `if`/`else`/`then` and `do`/`loop` get
+//! to compile jumps into the word being defined.
+//!
+//! ```text
+//! : fact ( n -- n! ) dup 1 > if dup 1 - recurse * else drop 1 then ;
+//! 5 fact .  \ prints 120
+//! ```
+//!
+//! Numbers are `op_bytes` wide, as usual for the `StackMachine`, and so are
+//! flags: all ones for true and zero for false, like its comparisons push, so
+//! `and`, `or` and `invert` work on them too.
+//!
+//! `forth [bytes]` runs it interactively, a line at a time.
+
+use super::stack_machine::{number, LangErrorKind, Operation, StackError, StackMachine};
+use num_bigint::BigUint;
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+    mem,
+    rc::Rc,
+};
+
+/// How deeply words may call each other, so that runaway recursion is an
+/// error rather than a crash.
+const MAX_DEPTH: usize = 1000;
+
+type Builtin = fn(&mut Forth) -> Result<(), ForthError>;
+
+/// Evaluates stdin a line at a time, with numbers `bytes` wide.
+pub fn run(bytes: u8) {
+    let mut forth = match Forth::new(bytes) {
+        Some(forth) => forth,
+        None => return eprintln!("numbers need at least one byte"),
+    };
+    let stdin = io::stdin();
+    if let Err(e) = forth.repl(stdin.lock(), &mut io::stdout()) {
+        eprintln!("error: {}", e);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForthError {
+    Stack(StackError),
+    UnknownWord(String),
+    /// A number too big for the width of the machine's values.
+    OutOfRange(String),
+    /// A word like `if` or `;` used outside of a definition.
+    CompileOnly(String),
+    /// `:` inside a definition.
+    NestedDefinition,
+    /// An `else` or `then` without an `if`, a `loop` without a `do`, or a `;`
+    /// with one of those still open.
+    Unbalanced(String),
+    /// A word that needed the next bit of input, but didn't get it.
+    MissingInput(String),
+    /// `i` or `j` outside of enough loops.
+    NotInLoop(String),
+    TooDeep,
+}
+
+impl fmt::Display for ForthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ForthError::*;
+        match self {
+            Stack(e) => write!(f, "{}", e),
+            UnknownWord(word) => write!(f, "unknown word `{}`", word),
+            OutOfRange(number) => write!(f, "`{}` is too big", number),
+            CompileOnly(word) => write!(f, "`{}` can only be used in a definition", word),
+            NestedDefinition => write!(f, "definitions can't be nested"),
+            Unbalanced(word) => write!(f, "unbalanced `{}`", word),
+            MissingInput(word) => write!(f, "`{}` ran out of input", word),
+            NotInLoop(word) => write!(f, "`{}` used outside of a loop", word),
+            TooDeep => write!(f, "words nested more than {} deep", MAX_DEPTH),
+        }
+    }
+}
+
+impl std::error::Error for ForthError {}
+
+impl From<StackError> for ForthError {
+    fn from(e: StackError) -> Self {
+        ForthError::Stack(e)
+    }
+}
+
+/// What a user-defined word compiles to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Op {
+    Literal(Vec<u8>),
+    /// Runs the word at this index in the dictionary.
+    Word(usize),
+    /// Pops an `op_bytes` wide flag and jumps if it's zero.
+    BranchIfZero(usize),
+    Jump(usize),
+    /// Pops a start and a limit, and starts a loop.
+    Do,
+    /// Steps the innermost loop, jumping back to the start of its body unless
+    /// it's finished.
+    Loop(usize),
+    Print(String),
+}
+
+#[derive(Clone)]
+enum Body {
+    /// A `StackMachine` operation on values `op_bytes` wide.
+    Operation(Operation),
+    Builtin(Builtin),
+    Code(Rc<[Op]>),
+}
+
+#[derive(Clone)]
+struct Word {
+    name: String,
+    immediate: bool,
+    body: Body,
+}
+
+/// A word being compiled.
+struct Definition {
+    name: String,
+    code: Vec<Op>,
+    /// The code indexes of the `if`s, `else`s and `do`s that are waiting for
+    /// their `then`, `then` or `loop`.
+    control: Vec<usize>,
+}
+
+pub struct Forth {
+    sm: StackMachine,
+    dictionary: Vec<Word>,
+    compiling: Option<Definition>,
+    /// The index and limit of each loop that's running, innermost last.
+    loops: Vec<(BigUint, BigUint)>,
+    depth: usize,
+    /// The input being evaluated, and how much of it has been read.
+    input: String,
+    position: usize,
+    output: String,
+}
+
+impl Forth {
+    /// A Forth with just the built-in words, and numbers `bytes` wide. There
+    /// has to be at least one byte to a number.
+    pub fn new(bytes: u8) -> Option<Self> {
+        if bytes == 0 {
+            return None;
+        }
+        let mut sm = StackMachine::new();
+        sm.set_op_bytes(bytes);
+        let mut forth = Self {
+            sm,
+            dictionary: Vec::new(),
+            compiling: None,
+            loops: Vec::new(),
+            depth: 0,
+            input: String::new(),
+            position: 0,
+            output: String::new(),
+        };
+        for &(name, operation) in OPERATIONS {
+            forth.add_builtin(name, false, Body::Operation(operation));
+        }
+        for &(name, builtin) in BUILTINS {
+            forth.add_builtin(name, false, Body::Builtin(builtin));
+        }
+        for &(name, builtin) in IMMEDIATE_BUILTINS {
+            forth.add_builtin(name, true, Body::Builtin(builtin));
+        }
+        Some(forth)
+    }
+
+    fn add_builtin(&mut self, name: &str, immediate: bool, body: Body) {
+        self.dictionary.push(Word {
+            name: name.to_string(),
+            immediate,
+            body,
+        });
+    }
+
+    pub fn stack(&self) -> &StackMachine {
+        &self.sm
+    }
+
+    /// Interprets `source`, returning what it printed. On an error, any
+    /// unfinished definition is thrown away.
+    pub fn eval(&mut self, source: &str) -> Result<String, ForthError> {
+        self.input = source.to_string();
+        self.position = 0;
+        let result = self.interpret();
+        if result.is_err() {
+            self.compiling = None;
+            self.loops.clear();
+            self.depth = 0;
+        }
+        let output = mem::take(&mut self.output);
+        result.map(|_| output)
+    }
+
+    /// Evaluates `input` a line at a time, printing what each line prints and
+    /// then `ok`, or the error it ran into. Definitions can span lines.
+    pub fn repl(&mut self, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        for line in input.lines() {
+            match self.eval(&line?) {
+                Ok(output) => writeln!(out, "{} ok", output)?,
+                Err(e) => writeln!(out, "error: {}", e)?,
+            }
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    fn interpret(&mut self) -> Result<(), ForthError> {
+        while let Some(word) = self.next_word() {
+            let compiling = self.compiling.is_some();
+            match self.find(&word) {
+                Some(i) if compiling && !self.dictionary[i].immediate => self.compile(Op::Word(i)),
+                Some(i) => self.execute(i)?,
+                None => {
+                    let literal = number(&word, self.sm.op_bytes()).map_err(|e| match e {
+                        LangErrorKind::OutOfRange(_) => ForthError::OutOfRange(word),
+                        _ => ForthError::UnknownWord(word),
+                    })?;
+                    if compiling {
+                        self.compile(Op::Literal(literal));
+                    } else {
+                        self.sm.extend_from_slice(&literal);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The newest word called `name`, as later definitions hide earlier ones.
+    fn find(&self, name: &str) -> Option<usize> {
+        self.dictionary.iter().rposition(|word| word.name == name)
+    }
+
+    /// Reads the next whitespace-separated word of input.
+    fn next_word(&mut self) -> Option<String> {
+        let rest = &self.input[self.position..];
+        let start = rest.find(|c: char| !c.is_whitespace())?;
+        let len = rest[start..]
+            .find(char::is_whitespace)
+            .unwrap_or(rest.len() - start);
+        self.position += start + len;
+        Some(rest[start..start + len].to_string())
+    }
+
+    /// Reads input up to `end`, skipping the space before it. Returns None if
+    /// `end` never comes.
+    fn read_until(&mut self, end: char) -> Option<String> {
+        let rest = &self.input[self.position..];
+        let rest = rest.strip_prefix(char::is_whitespace).unwrap_or(rest);
+        let len = rest.find(end)?;
+        let text = rest[..len].to_string();
+        self.position = self.input.len() - rest.len() + len + end.len_utf8();
+        Some(text)
+    }
+
+    fn execute(&mut self, word: usize) -> Result<(), ForthError> {
+        if self.depth == MAX_DEPTH {
+            return Err(ForthError::TooDeep);
+        }
+        self.depth += 1;
+        let result = match self.dictionary[word].body.clone() {
+            Body::Operation(operation) => {
+                let bytes = self.sm.op_bytes();
+                operation(&mut self.sm, bytes).map_err(ForthError::from)
+            }
+            Body::Builtin(builtin) => builtin(self),
+            Body::Code(code) => self.run(&code),
+        };
+        self.depth -= 1;
+        result
+    }
+
+    fn run(&mut self, code: &[Op]) -> Result<(), ForthError> {
+        let bytes = self.sm.op_bytes();
+        let mut pc = 0;
+        while pc < code.len() {
+            pc += 1;
+            match &code[pc - 1] {
+                Op::Literal(value) => self.sm.extend_from_slice(value),
+                &Op::Word(word) => self.execute(word)?,
+                &Op::BranchIfZero(target) => {
+                    if self.sm.pop_n(bytes)?.iter().all(|&byte| byte == 0) {
+                        pc = target;
+                    }
+                }
+                &Op::Jump(target) => pc = target,
+                Op::Do => {
+                    let (limit, start) = self.sm.pop_pair(bytes)?;
+                    let (limit, start) = (
+                        BigUint::from_bytes_be(&limit),
+                        BigUint::from_bytes_be(&start),
+                    );
+                    self.loops.push((start, limit));
+                }
+                &Op::Loop(body) => {
+                    let (index, limit) = self.loops.last_mut().expect("a loop is running");
+                    *index += 1u8;
+                    if index < limit {
+                        pc = body;
+                    } else {
+                        self.loops.pop();
+                    }
+                }
+                Op::Print(text) => self.output.push_str(text),
+            }
+        }
+        Ok(())
+    }
+
+    fn compile(&mut self, op: Op) {
+        self.definition().code.push(op);
+    }
+
+    /// The word being compiled. Only for use by compile-only words, which
+    /// check for one first.
+    fn definition(&mut self) -> &mut Definition {
+        self.compiling.as_mut().expect("a word is being compiled")
+    }
+
+    /// Fails unless a word is being compiled.
+    fn compile_only(&self, word: &str) -> Result<(), ForthError> {
+        match self.compiling {
+            Some(_) => Ok(()),
+            None => Err(ForthError::CompileOnly(word.to_string())),
+        }
+    }
+
+    /// Pops the last open `if`, `else` or `do`, checking it's one of `kinds`.
+    fn pop_control(&mut self, word: &str, kinds: fn(&Op) -> bool) -> Result<usize, ForthError> {
+        self.compile_only(word)?;
+        let definition = self.definition();
+        match definition.control.last() {
+            Some(&i) if kinds(&definition.code[i]) => {
+                definition.control.pop();
+                Ok(i)
+            }
+            _ => Err(ForthError::Unbalanced(word.to_string())),
+        }
+    }
+
+    /// Pushes the index of the loop `depth` loops out from the innermost.
+    fn push_index(&mut self, word: &str, depth: usize) -> Result<(), ForthError> {
+        let i = self
+            .loops
+            .len()
+            .checked_sub(depth + 1)
+            .ok_or_else(|| ForthError::NotInLoop(word.to_string()))?;
+        let digits = self.loops[i].0.to_bytes_be();
+        // The index is always below the limit, so it fits.
+        let padding = self.sm.op_bytes() as usize - digits.len();
+        self.sm.extend(vec![0; padding]);
+        self.sm.extend(digits);
+        Ok(())
+    }
+}
+
+const OPERATIONS: &[(&str, Operation)] = &[
+    ("+", StackMachine::add),
+    ("-", StackMachine::sub),
+    ("*", StackMachine::mul),
+    ("/", StackMachine::div),
+    ("mod", StackMachine::rem),
+    ("and", StackMachine::and),
+    ("or", StackMachine::or),
+    ("xor", StackMachine::xor),
+    ("invert", StackMachine::not),
+    ("=", StackMachine::eq),
+    ("<", StackMachine::lt),
+    (">", StackMachine::gt),
+    ("<=", StackMachine::lte),
+    (">=", StackMachine::gte),
+    ("dup", StackMachine::dup_n),
+    ("drop", StackMachine::drop_n),
+    ("swap", StackMachine::swap_n),
+    ("over", StackMachine::over_n),
+];
+
+const BUILTINS: &[(&str, Builtin)] = &[
+    ("rot", |f| {
+        // ( a b c -- b c a )
+        let bytes = f.sm.op_bytes();
+        f.sm.check_depth(bytes as usize * 3)?;
+        let (b, c) = f.sm.pop_pair(bytes)?;
+        let a = f.sm.pop_n(bytes)?;
+        f.sm.extend(b.into_iter().chain(c).chain(a));
+        Ok(())
+    }),
+    (".", |f| {
+        let value = f.sm.pop_n(f.sm.op_bytes())?;
+        f.output += &format!("{} ", BigUint::from_bytes_be(&value));
+        Ok(())
+    }),
+    ("emit", |f| {
+        let value = f.sm.pop_n(f.sm.op_bytes())?;
+        f.output.push(*value.last().unwrap_or(&0) as char);
+        Ok(())
+    }),
+    ("cr", |f| {
+        f.output.push('\n');
+        Ok(())
+    }),
+    ("i", |f| f.push_index("i", 0)),
+    ("j", |f| f.push_index("j", 1)),
+    ("immediate", |f| {
+        // Marks the word defined most recently.
+        f.dictionary.last_mut().unwrap().immediate = true;
+        Ok(())
+    }),
+];
+
+/// Words that run even while compiling. `:` is one so that it can complain
+/// about being nested.
+const IMMEDIATE_BUILTINS: &[(&str, Builtin)] = &[
+    (":", |f| {
+        if f.compiling.is_some() {
+            return Err(ForthError::NestedDefinition);
+        }
+        let name = f
+            .next_word()
+            .ok_or_else(|| ForthError::MissingInput(":".to_string()))?;
+        f.compiling = Some(Definition {
+            name,
+            code: Vec::new(),
+            control: Vec::new(),
+        });
+        Ok(())
+    }),
+    (";", |f| {
+        f.compile_only(";")?;
+        if !f.definition().control.is_empty() {
+            return Err(ForthError::Unbalanced(";".to_string()));
+        }
+        let definition = f.compiling.take().unwrap();
+        f.dictionary.push(Word {
+            name: definition.name,
+            immediate: false,
+            body: Body::Code(definition.code.into()),
+        });
+        Ok(())
+    }),
+    ("if", |f| {
+        f.compile_only("if")?;
+        let definition = f.definition();
+        definition.control.push(definition.code.len());
+        definition.code.push(Op::BranchIfZero(0));
+        Ok(())
+    }),
+    ("else", |f| {
+        let i = f.pop_control("else", |op| matches!(op, Op::BranchIfZero(_)))?;
+        let definition = f.definition();
+        definition.control.push(definition.code.len());
+        definition.code.push(Op::Jump(0));
+        // A false `if` jumps to just after the jump over the `else` part.
+        definition.code[i] = Op::BranchIfZero(definition.code.len());
+        Ok(())
+    }),
+    ("then", |f| {
+        let i = f.pop_control("then", |op| matches!(op, Op::BranchIfZero(_) | Op::Jump(_)))?;
+        let definition = f.definition();
+        let end = definition.code.len();
+        definition.code[i] = match definition.code[i] {
+            Op::BranchIfZero(_) => Op::BranchIfZero(end),
+            _ => Op::Jump(end),
+        };
+        Ok(())
+    }),
+    ("do", |f| {
+        f.compile_only("do")?;
+        let definition = f.definition();
+        definition.control.push(definition.code.len());
+        definition.code.push(Op::Do);
+        Ok(())
+    }),
+    ("loop", |f| {
+        let i = f.pop_control("loop", |op| *op == Op::Do)?;
+        f.compile(Op::Loop(i + 1));
+        Ok(())
+    }),
+    ("recurse", |f| {
+        f.compile_only("recurse")?;
+        // The word being defined goes at the end of the dictionary.
+        let word = f.dictionary.len();
+        f.compile(Op::Word(word));
+        Ok(())
+    }),
+    (".\"", |f| {
+        let text = f
+            .read_until('"')
+            .ok_or_else(|| ForthError::MissingInput(".\"".to_string()))?;
+        match f.compiling {
+            Some(_) => f.compile(Op::Print(text)),
+            None => f.output.push_str(&text),
+        }
+        Ok(())
+    }),
+    ("(", |f| {
+        f.read_until(')')
+            .map(|_| ())
+            .ok_or_else(|| ForthError::MissingInput("(".to_string()))
+    }),
+    ("\\", |f| {
+        // Comments run to the end of the line.
+        if f.read_until('\n').is_none() {
+            f.position = f.input.len();
+        }
+        Ok(())
+    }),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(forth: &mut Forth, source: &str) -> String {
+        forth.eval(source).unwrap()
+    }
+
+    #[test]
+    fn does_arithmetic() {
+        let mut forth = Forth::new(2).unwrap();
+        assert_eq!(eval(&mut forth, "1 2 + 3 * . 7 2 mod ."), "9 1 ");
+        assert_eq!(eval(&mut forth, "1 2 3 rot . . ."), "1 3 2 ");
+        assert_eq!(eval(&mut forth, "0 1 - ."), "65535 ");
+        assert_eq!(eval(&mut forth, "72 emit 105 emit cr"), "Hi\n");
+        assert_eq!(forth.stack().sp(), 0);
+    }
+
+    #[test]
+    fn flags_are_as_wide_as_numbers() {
+        let mut forth = Forth::new(2).unwrap();
+        assert_eq!(eval(&mut forth, "1 1 = . 1 2 = ."), "65535 0 ");
+        assert_eq!(
+            eval(&mut forth, "1 1 = 2 2 = and . 1 2 < invert ."),
+            "65535 0 "
+        );
+        eval(&mut forth, ": t 5 if 7 then ;");
+        assert_eq!(eval(&mut forth, "t ."), "7 ");
+        assert_eq!(forth.stack().sp(), 0);
+    }
+
+    #[test]
+    fn defines_words() {
+        let mut forth = Forth::new(2).unwrap();
+        eval(
+            &mut forth,
+            ": square ( n -- n*n ) dup * ;\n: cube dup square * ;",
+        );
+        assert_eq!(eval(&mut forth, "3 square . 3 cube ."), "9 27 ");
+        // Redefining a word doesn't change the words already using it.
+        eval(&mut forth, ": square drop 0 ; \\ that's not right");
+        assert_eq!(eval(&mut forth, "3 square . 3 cube ."), "0 27 ");
+    }
+
+    #[test]
+    fn computes_factorials() {
+        let mut forth = Forth::new(2).unwrap();
+        eval(
+            &mut forth,
+            ": fact dup 1 > if dup 1 - recurse * else drop 1 then ;",
+        );
+        assert_eq!(eval(&mut forth, "5 fact . 1 fact . 0 fact ."), "120 1 1 ");
+        eval(&mut forth, ": fact2 1 swap 1 + 1 do i * loop ;");
+        assert_eq!(eval(&mut forth, "5 fact2 . 8 fact2 ."), "120 40320 ");
+    }
+
+    #[test]
+    fn computes_fibonacci_numbers() {
+        let mut forth = Forth::new(4).unwrap();
+        eval(
+            &mut forth,
+            ": fib dup 2 < if else dup 1 - recurse swap 2 - recurse + then ;",
+        );
+        eval(&mut forth, ": fib2 0 1 rot 0 do over + swap loop drop ;");
+        assert_eq!(eval(&mut forth, "10 fib . 20 fib ."), "55 6765 ");
+        assert_eq!(eval(&mut forth, "10 fib2 . 40 fib2 ."), "55 102334155 ");
+    }
+
+    #[test]
+    fn loops_nest() {
+        let mut forth = Forth::new(2).unwrap();
+        eval(&mut forth, ": squares 5 0 do i i * . loop ;");
+        assert_eq!(eval(&mut forth, "squares"), "0 1 4 9 16 ");
+        eval(&mut forth, ": table 3 1 do 3 1 do i j * . loop cr loop ;");
+        assert_eq!(eval(&mut forth, "table"), "1 2 \n2 4 \n");
+    }
+
+    #[test]
+    fn runs_immediate_words_while_compiling() {
+        let mut forth = Forth::new(2).unwrap();
+        eval(&mut forth, ": announce .\" compiling!\" ; immediate");
+        assert_eq!(eval(&mut forth, ": word announce 1 ;"), "compiling!");
+        assert_eq!(eval(&mut forth, "word ."), "1 ");
+        assert_eq!(eval(&mut forth, ".\" hello, world\""), "hello, world");
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut forth = Forth::new(2).unwrap();
+        let mut err = |source| forth.eval(source).unwrap_err();
+        assert_eq!(
+            err("frobnicate"),
+            ForthError::UnknownWord("frobnicate".into())
+        );
+        assert_eq!(err("65536"), ForthError::OutOfRange("65536".into()));
+        assert_eq!(err("1 if"), ForthError::CompileOnly("if".into()));
+        assert_eq!(err(": x then ;"), ForthError::Unbalanced("then".into()));
+        assert_eq!(err(": x 1 if ;"), ForthError::Unbalanced(";".into()));
+        assert_eq!(
+            err(": x do 1 if loop"),
+            ForthError::Unbalanced("loop".into())
+        );
+        assert_eq!(err(": x : y"), ForthError::NestedDefinition);
+        assert_eq!(err(":"), ForthError::MissingInput(":".into()));
+        assert_eq!(err("i"), ForthError::NotInLoop("i".into()));
+        assert_eq!(err(": forever recurse ; forever"), ForthError::TooDeep);
+        assert!(matches!(
+            Forth::new(2).unwrap().eval("1 +"),
+            Err(ForthError::Stack(StackError::Underflow { .. }))
+        ));
+
+        // A failed definition is thrown away, and the next one starts afresh.
+        assert_eq!(
+            err(": half 2 / oops ;"),
+            ForthError::UnknownWord("oops".into())
+        );
+        assert_eq!(err("4 half"), ForthError::UnknownWord("half".into()));
+        assert_eq!(eval(&mut forth, ": half 2 / ; 4 half ."), "2 ");
+
+        assert!(Forth::new(0).is_none());
+    }
+
+    #[test]
+    fn runs_a_line_at_a_time() {
+        let mut forth = Forth::new(2).unwrap();
+        let input = ": fact dup 1 > if dup 1 - recurse *\n else drop 1 then ;\n5 fact .\nfrob\n";
+        let mut out = Vec::new();
+        forth.repl(input.as_bytes(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            " ok\n ok\n120  ok\nerror: unknown word `frob`\n"
+        );
+    }
+}
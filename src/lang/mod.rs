@@ -1,9 +1,15 @@
+pub mod bril;
+pub mod dataflow;
+pub mod forth;
 mod lispy_interp;
 mod pest_lisp;
-mod stack_lang;
+mod stack_format;
+pub mod stack_lang;
 mod stack_machine;
 mod vm;
 
+pub use stack_machine::run_file;
+
 pub fn main() {
     // lispy_interp::run();
     pest_lisp::main();
@@ -0,0 +1,201 @@
+//! Ways of looking at the bytes on a `StackMachine`'s stack.
+//!
+//! The same bytes mean different numbers depending on how they're read: as
+//! words how many bytes wide, in which order, and as what kind of number.
+//! `0xFF 0xFE` is 65534 as an unsigned big-endian word, -2 as a signed one,
+//! and 65279 if it's little-endian.
+
+use num_bigint::{BigInt, BigUint};
+use std::{convert::TryInto, fmt, str::FromStr};
+use strum_macros::{EnumString, EnumVariantNames};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "lowercase")]
+pub enum StackFormat {
+    /// Unsigned, and as wide as words are: they're read as `BigUint`s.
+    Decimal,
+    #[strum(serialize = "0b", serialize = "binary")]
+    Binary,
+    #[strum(serialize = "0x", serialize = "lowerhex")]
+    LowerHex,
+    #[strum(serialize = "0X", serialize = "upperhex")]
+    UpperHex,
+    /// Two's complement.
+    Signed,
+    /// IEEE 754 `f32`s and `f64`s, for words 4 and 8 bytes wide.
+    Float,
+}
+
+impl From<&str> for StackFormat {
+    fn from(val: &str) -> Self {
+        StackFormat::from_str(val).unwrap_or(StackFormat::Decimal)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "lowercase")]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+/// How the stack is shown: split into `word` byte words from the top down,
+/// each read with the given endianness and format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackView {
+    pub format: StackFormat,
+    word: u8,
+    pub endian: Endian,
+}
+
+impl Default for StackView {
+    /// The stack as it really is: a byte at a time.
+    fn default() -> Self {
+        Self {
+            format: StackFormat::Decimal,
+            word: 1,
+            endian: Endian::Big,
+        }
+    }
+}
+
+impl StackView {
+    pub fn word(&self) -> u8 {
+        self.word
+    }
+
+    /// Words have to be at least a byte wide, so 0 is ignored.
+    pub fn set_word(&mut self, bytes: u8) {
+        self.word = bytes.max(1);
+    }
+
+    /// Renders `stack` a word at a time, bottom first. Words are taken from
+    /// the top, so if the bottom of the stack doesn't make up a whole word,
+    /// those bytes are shown as they are.
+    pub fn render(&self, stack: &[u8]) -> String {
+        let words: Vec<_> = stack
+            .rchunks(self.word as usize)
+            .rev()
+            .map(|word| {
+                if word.len() == self.word as usize {
+                    self.render_word(word)
+                } else {
+                    format!("{:?}", word)
+                }
+            })
+            .collect();
+        format!("[{}]", words.join(", "))
+    }
+
+    /// Floats of any other width than 4 or 8 bytes are shown as plain bytes.
+    fn render_word(&self, word: &[u8]) -> String {
+        let mut word = word.to_vec();
+        if self.endian == Endian::Little {
+            word.reverse();
+        }
+        let unsigned = || BigUint::from_bytes_be(&word);
+        let (bits, nibbles) = (word.len() * 8, word.len() * 2);
+        match self.format {
+            StackFormat::Decimal => unsigned().to_string(),
+            StackFormat::Binary => format!("{:0>1$b}", unsigned(), bits),
+            StackFormat::LowerHex => format!("{:0>1$x}", unsigned(), nibbles),
+            StackFormat::UpperHex => format!("{:0>1$X}", unsigned(), nibbles),
+            StackFormat::Signed => BigInt::from_signed_bytes_be(&word).to_string(),
+            StackFormat::Float => match word.len() {
+                4 => f32::from_be_bytes(word[..].try_into().unwrap()).to_string(),
+                8 => f64::from_be_bytes(word[..].try_into().unwrap()).to_string(),
+                _ => format!("{:?}", word),
+            },
+        }
+    }
+}
+
+impl fmt::Display for StackView {
+    /// Describes the view, like "signed 2 byte little-endian words".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = match self.format {
+            StackFormat::Decimal => "unsigned",
+            StackFormat::Binary => "binary",
+            StackFormat::LowerHex | StackFormat::UpperHex => "hex",
+            StackFormat::Signed => "signed",
+            StackFormat::Float => "float",
+        };
+        let endian = match self.endian {
+            Endian::Big => "big",
+            Endian::Little => "little",
+        };
+        match self.word {
+            1 => write!(f, "{} bytes", format),
+            n => write!(f, "{} {} byte {}-endian words", format, n, endian),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(format: StackFormat, word: u8, endian: Endian) -> StackView {
+        let mut view = StackView {
+            format,
+            endian,
+            ..StackView::default()
+        };
+        view.set_word(word);
+        view
+    }
+
+    #[test]
+    fn the_same_bytes_mean_different_numbers() {
+        use StackFormat::*;
+        let stack = [0xFF, 0xFE];
+        let render = |format, word, endian| view(format, word, endian).render(&stack);
+        assert_eq!(StackView::default().render(&stack), "[255, 254]");
+        assert_eq!(render(Decimal, 2, Endian::Big), "[65534]");
+        assert_eq!(render(Decimal, 2, Endian::Little), "[65279]");
+        assert_eq!(render(Signed, 2, Endian::Big), "[-2]");
+        assert_eq!(render(Signed, 1, Endian::Big), "[-1, -2]");
+        assert_eq!(render(Signed, 2, Endian::Little), "[-257]");
+        assert_eq!(render(Binary, 1, Endian::Big), "[11111111, 11111110]");
+        assert_eq!(render(UpperHex, 2, Endian::Little), "[FEFF]");
+        assert_eq!(
+            view(LowerHex, 2, Endian::Big).render(&[0, 1, 0, 0x20]),
+            "[0001, 0020]"
+        );
+    }
+
+    #[test]
+    fn words_are_taken_from_the_top() {
+        let view = view(StackFormat::Decimal, 2, Endian::Big);
+        assert_eq!(view.render(&[7, 1, 0, 0, 2]), "[[7], 256, 2]");
+        assert_eq!(view.render(&[]), "[]");
+        assert_eq!(
+            StackView::default().to_string(),
+            "unsigned bytes".to_string()
+        );
+        assert_eq!(view.to_string(), "unsigned 2 byte big-endian words");
+    }
+
+    #[test]
+    fn renders_floats() {
+        let mut stack = 1.5f32.to_be_bytes().to_vec();
+        stack.extend_from_slice(&(-0.25f64).to_le_bytes());
+        let floats = view(StackFormat::Float, 4, Endian::Big);
+        assert_eq!(floats.render(&stack[..4]), "[1.5]");
+        let floats = view(StackFormat::Float, 8, Endian::Little);
+        assert_eq!(floats.render(&stack[4..]), "[-0.25]");
+        // Other widths don't make floats.
+        let floats = view(StackFormat::Float, 2, Endian::Big);
+        assert_eq!(floats.render(&[1, 2]), "[[1, 2]]");
+    }
+
+    #[test]
+    fn words_are_wide_enough_for_anything() {
+        let view = view(StackFormat::Decimal, 16, Endian::Big);
+        assert_eq!(view.render(&[0xFF; 16]), format!("[{}]", u128::MAX));
+        let mut signed = view;
+        signed.format = StackFormat::Signed;
+        signed.set_word(0);
+        assert_eq!(signed.word(), 1);
+    }
+}
@@ -1,6 +1,12 @@
 //! I had some ideas for how to implement this so wanted to start from a clean slate.
+//!
+//! Programs are split into basic blocks, which are then joined up into a
+//! control-flow graph. Anything implementing `Instruction` can be analysed
+//! this way, whichever instruction set it's from.
 
 use std::{
+    collections::BTreeSet,
+    fmt::{Debug, Write},
     mem,
     ops::{Deref, DerefMut},
 };
@@ -16,40 +22,469 @@ use strum_macros::EnumString;
 // }
 
 /// A (basic) block of instructions.
-struct Block<T>(Vec<T>);
+pub struct Block<T> {
+    /// The index in the program of the block's first instruction.
+    pub start: usize,
+    instructions: Vec<T>,
+}
 
 impl<T> Block<T> {
-    fn new() -> Self {
-        Self(Vec::new())
+    fn new(start: usize) -> Self {
+        Self {
+            start,
+            instructions: Vec::new(),
+        }
+    }
+
+    /// One past the index of the block's last instruction.
+    pub fn end(&self) -> usize {
+        self.start + self.len()
     }
 }
 
 impl<T> Deref for Block<T> {
     type Target = Vec<T>;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.instructions
     }
 }
 
 impl<T> DerefMut for Block<T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.instructions
     }
 }
 
-trait Instruction {
+/// What a control-flow graph needs to know about an instruction. Jump targets
+/// are indexes into the program, so they can be worked out for absolute and
+/// relative jumps alike. Calls aren't control flow as far as a CFG is
+/// concerned: they come back to the next instruction.
+pub trait Instruction {
+    /// Whether the instruction can jump or stop, and so ends a block.
     fn is_control(&self) -> bool;
+
+    /// Where the instruction at `index` can jump to. Jumps that can't be
+    /// worked out from the instruction alone, like those to an address in a
+    /// register, aren't included.
+    fn jump_targets(&self, index: usize) -> Vec<usize> {
+        Vec::new()
+    }
+
+    /// Whether execution can carry on to the next instruction afterwards.
+    fn falls_through(&self) -> bool {
+        true
+    }
 }
 
-fn blocks_from_instructions<I: Clone + Instruction>(instructions: &[I]) -> Vec<Block<I>> {
+/// Splits `instructions` into blocks, which start at the first instruction,
+/// at any jump target, and after every control instruction.
+pub fn blocks_from_instructions<I: Clone + Instruction>(instructions: &[I]) -> Vec<Block<I>> {
+    let mut leaders = vec![false; instructions.len()];
+    for (i, instr) in instructions.iter().enumerate() {
+        for target in instr.jump_targets(i) {
+            if let Some(leader) = leaders.get_mut(target) {
+                *leader = true;
+            }
+        }
+    }
+
     let mut res = Vec::new();
-    let mut block = Block::new();
-    for instr in instructions.iter().cloned() {
+    let mut block = Block::new(0);
+    for (i, instr) in instructions.iter().cloned().enumerate() {
+        if leaders[i] && !block.is_empty() {
+            res.push(mem::replace(&mut block, Block::new(i)));
+        }
         let is_control = instr.is_control();
         block.push(instr);
         if is_control {
-            res.push(mem::replace(&mut block, Block::new()));
+            res.push(mem::replace(&mut block, Block::new(i + 1)));
         }
     }
+    if !block.is_empty() {
+        res.push(block);
+    }
     res
 }
+
+pub type BlockId = usize;
+
+/// A control-flow graph. Blocks are in program order, so the entry is block 0,
+/// and an edge goes wherever control can go next. Jumps outside the program
+/// just end it, as do the last instructions, so don't make edges.
+pub struct Cfg<T> {
+    pub blocks: Vec<Block<T>>,
+    successors: Vec<Vec<BlockId>>,
+    predecessors: Vec<Vec<BlockId>>,
+}
+
+impl<T: Clone + Instruction> Cfg<T> {
+    pub fn new(instructions: &[T]) -> Self {
+        let blocks = blocks_from_instructions(instructions);
+        let starts: Vec<_> = blocks.iter().map(|block| block.start).collect();
+        let block_at = |i| starts.binary_search(&i).ok();
+
+        let mut successors = vec![Vec::new(); blocks.len()];
+        let mut predecessors = vec![Vec::new(); blocks.len()];
+        for (id, block) in blocks.iter().enumerate() {
+            let last = block.end() - 1;
+            let mut next = block[block.len() - 1].jump_targets(last);
+            if block[block.len() - 1].falls_through() {
+                next.push(block.end());
+            }
+            for to in next.into_iter().filter_map(block_at) {
+                if !successors[id].contains(&to) {
+                    successors[id].push(to);
+                    predecessors[to].push(id);
+                }
+            }
+        }
+        Self {
+            blocks,
+            successors,
+            predecessors,
+        }
+    }
+}
+
+impl<T> Cfg<T> {
+    /// None for an empty program.
+    pub fn entry(&self) -> Option<BlockId> {
+        if self.blocks.is_empty() {
+            None
+        } else {
+            Some(0)
+        }
+    }
+
+    /// The blocks that end the program.
+    pub fn exits(&self) -> Vec<BlockId> {
+        (0..self.blocks.len())
+            .filter(|&id| self.successors[id].is_empty())
+            .collect()
+    }
+
+    pub fn successors(&self, id: BlockId) -> &[BlockId] {
+        &self.successors[id]
+    }
+
+    pub fn predecessors(&self, id: BlockId) -> &[BlockId] {
+        &self.predecessors[id]
+    }
+
+    /// The block containing the instruction at `index`.
+    pub fn block_of(&self, index: usize) -> Option<BlockId> {
+        let id = self.blocks.partition_point(|block| block.start <= index);
+        id.checked_sub(1)
+            .filter(|&id| index < self.blocks[id].end())
+    }
+
+    /// The blocks reachable from the entry, each before its successors except
+    /// along back edges.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut order = Vec::new();
+        let mut visited = vec![false; self.blocks.len()];
+        // Each block, with how many of its successors have been looked at.
+        let mut stack: Vec<(BlockId, usize)> = self.entry().into_iter().map(|id| (id, 0)).collect();
+        if let Some(entry) = self.entry() {
+            visited[entry] = true;
+        }
+        while let Some((id, next)) = stack.pop() {
+            match self.successors[id].get(next) {
+                Some(&to) => {
+                    stack.push((id, next + 1));
+                    if !visited[to] {
+                        visited[to] = true;
+                        stack.push((to, 0));
+                    }
+                }
+                None => order.push(id),
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /// The dominator tree, by Cooper, Harvey and Kennedy's "A Simple, Fast
+    /// Dominance Algorithm".
+    pub fn dominators(&self) -> Dominators {
+        let order = self.reverse_postorder();
+        let mut position = vec![usize::MAX; self.blocks.len()];
+        for (i, &id) in order.iter().enumerate() {
+            position[id] = i;
+        }
+        let mut idom = vec![None; self.blocks.len()];
+        let entry = match order.first() {
+            Some(&entry) => entry,
+            None => return Dominators { idom },
+        };
+        idom[entry] = Some(entry);
+
+        let intersect = |idom: &[Option<BlockId>], mut a: BlockId, mut b: BlockId| {
+            while a != b {
+                while position[a] > position[b] {
+                    a = idom[a].unwrap();
+                }
+                while position[b] > position[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &id in &order[1..] {
+                let mut processed = self.predecessors[id].iter().filter(|&&p| idom[p].is_some());
+                let first = *processed.next().expect("an earlier predecessor");
+                let new = processed.fold(first, |new, &p| intersect(&idom, p, new));
+                if idom[id] != Some(new) {
+                    idom[id] = Some(new);
+                    changed = true;
+                }
+            }
+        }
+        idom[entry] = None;
+        Dominators { idom }
+    }
+
+    /// The natural loops, one per header, outermost first. Loops that aren't
+    /// entered through a single header (irreducible ones) aren't found.
+    pub fn loops(&self) -> Vec<Loop> {
+        let dominators = self.dominators();
+        let mut loops: Vec<Loop> = Vec::new();
+        for id in self.reverse_postorder() {
+            for &header in &self.successors[id] {
+                if !dominators.dominates(header, id) {
+                    continue;
+                }
+                // Everything that reaches the back edge without going through
+                // the header is in the loop.
+                let mut body = BTreeSet::new();
+                body.insert(header);
+                let mut stack = vec![id];
+                while let Some(block) = stack.pop() {
+                    if body.insert(block) {
+                        stack.extend(&self.predecessors[block]);
+                    }
+                }
+                match loops.iter_mut().find(|l| l.header == header) {
+                    Some(l) => l.body.extend(body),
+                    None => loops.push(Loop { header, body }),
+                }
+            }
+        }
+        loops.sort_by_key(|l| std::cmp::Reverse(l.body.len()));
+        loops
+    }
+}
+
+impl<T: Debug> Cfg<T> {
+    /// Renders the graph in Graphviz's DOT language, with each block's
+    /// instructions listed by their index in the program.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+        for (id, block) in self.blocks.iter().enumerate() {
+            let mut label = format!("B{}\\l", id);
+            for (i, instr) in block.iter().enumerate() {
+                let text = format!("{}: {:?}", block.start + i, instr);
+                label += &text.replace('\\', "\\\\").replace('"', "\\\"");
+                label += "\\l";
+            }
+            writeln!(dot, "    b{} [label=\"{}\"];", id, label).unwrap();
+        }
+        for (id, successors) in self.successors.iter().enumerate() {
+            for to in successors {
+                writeln!(dot, "    b{} -> b{};", id, to).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Which blocks dominate which: every path from the entry to a block goes
+/// through its dominators.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dominators {
+    idom: Vec<Option<BlockId>>,
+}
+
+impl Dominators {
+    /// The closest strict dominator of `id`. None for the entry, and blocks
+    /// that can't be reached.
+    pub fn immediate(&self, id: BlockId) -> Option<BlockId> {
+        self.idom[id]
+    }
+
+    /// Whether `a` dominates `b`, which every block does itself.
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        let mut block = Some(b);
+        while let Some(id) = block {
+            if id == a {
+                return true;
+            }
+            block = self.idom[id];
+        }
+        false
+    }
+
+    /// The blocks `id` immediately dominates: its children in the tree.
+    pub fn children(&self, id: BlockId) -> Vec<BlockId> {
+        (0..self.idom.len())
+            .filter(|&child| self.idom[child] == Some(id))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    /// The block every way into the loop goes through.
+    pub header: BlockId,
+    /// Every block in the loop, including the header.
+    pub body: BTreeSet<BlockId>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::stack_machine::Instruction::{self as Sm, *};
+
+    /// Prints 3, 2, 1 and then 0 if the counter ever reaches it.
+    fn countdown() -> Vec<Sm> {
+        vec![
+            Push(vec![3]), // 0
+            Dup,           // 1: loop
+            Print,
+            Push(vec![1]),
+            Sub,
+            Dup, // 5
+            Push(vec![0]),
+            Gt,
+            Branch(1),
+            Print,
+            Jmp(11), // 10
+        ]
+    }
+
+    fn starts(blocks: &[Block<Sm>]) -> Vec<usize> {
+        blocks.iter().map(|block| block.start).collect()
+    }
+
+    #[test]
+    fn splits_at_leaders() {
+        let blocks = blocks_from_instructions(&countdown());
+        assert_eq!(starts(&blocks), vec![0, 1, 9]);
+        assert_eq!(blocks[1].end(), 9);
+        // The last instructions make a block even without ending in a jump.
+        let blocks = blocks_from_instructions(&[Push(vec![1]), Jmp(3), Noop, Noop]);
+        assert_eq!(starts(&blocks), vec![0, 2, 3]);
+        assert!(blocks_from_instructions::<Sm>(&[]).is_empty());
+    }
+
+    #[test]
+    fn joins_blocks_with_edges() {
+        let cfg = Cfg::new(&countdown());
+        assert_eq!(cfg.entry(), Some(0));
+        assert_eq!(cfg.successors(0), &[1]);
+        assert_eq!(cfg.successors(1), &[1, 2]);
+        assert_eq!(cfg.predecessors(1), &[0, 1]);
+        // Jumping to the very end of the program ends it.
+        assert_eq!(cfg.successors(2), &[] as &[BlockId]);
+        assert_eq!(cfg.exits(), vec![2]);
+        assert_eq!(cfg.block_of(5), Some(1));
+        assert_eq!(cfg.block_of(11), None);
+    }
+
+    /// An if/else inside a loop, with a call and a return that the loop
+    /// doesn't see:
+    ///
+    /// ```text
+    /// B0 -> B1 -> B2 -> B4 -> B1
+    ///         \-> B3 -/   \-> B5
+    /// ```
+    fn nested() -> Vec<Sm> {
+        vec![
+            Push(vec![0]), // 0: B0
+            Dup,           // 1: B1
+            Branch(5),
+            Call(10), // 3: B2
+            Jmp(6),
+            Noop,      // 5: B3
+            Dup,       // 6: B4
+            Branch(1), //
+            Drop,      // 8: B5
+            Jmp(11),
+            Return, // 10: B6, only reachable by the call
+        ]
+    }
+
+    #[test]
+    fn finds_dominators() {
+        let cfg = Cfg::new(&nested());
+        assert_eq!(starts(&cfg.blocks), vec![0, 1, 3, 5, 6, 8, 10]);
+        assert_eq!(cfg.reverse_postorder(), vec![0, 1, 2, 3, 4, 5]);
+        let dominators = cfg.dominators();
+        let idoms: Vec<_> = (0..7).map(|id| dominators.immediate(id)).collect();
+        assert_eq!(
+            idoms,
+            vec![None, Some(0), Some(1), Some(1), Some(1), Some(4), None]
+        );
+        assert!(dominators.dominates(1, 5));
+        assert!(dominators.dominates(4, 4));
+        assert!(!dominators.dominates(2, 4));
+        assert_eq!(dominators.children(1), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn finds_loops() {
+        let cfg = Cfg::new(&nested());
+        let body = |ids: &[BlockId]| ids.iter().copied().collect();
+        assert_eq!(
+            cfg.loops(),
+            vec![Loop {
+                header: 1,
+                body: body(&[1, 2, 3, 4])
+            }]
+        );
+
+        // A loop inside another.
+        let cfg = Cfg::new(&[
+            Noop,      // B0
+            Noop,      // B1: outer
+            Dup,       // B2: inner
+            Branch(2), //
+            Dup,       // B3
+            Branch(1), //
+        ]);
+        assert_eq!(
+            cfg.loops(),
+            vec![
+                Loop {
+                    header: 1,
+                    body: body(&[1, 2, 3])
+                },
+                Loop {
+                    header: 2,
+                    body: body(&[2])
+                },
+            ]
+        );
+        assert!(Cfg::new(&[Noop, Jmp(0)]).loops()[0].body.contains(&0));
+    }
+
+    #[test]
+    fn exports_dot() {
+        let cfg = Cfg::new(&[Push(vec![1]), Branch(0), Jmp(5)]);
+        assert_eq!(
+            cfg.to_dot(),
+            "digraph cfg {
+    node [shape=box, fontname=monospace];
+    b0 [label=\"B0\\l0: Push([1])\\l1: Branch(0)\\l\"];
+    b1 [label=\"B1\\l2: Jmp(5)\\l\"];
+    b0 -> b0;
+    b0 -> b1;
+}
+"
+        );
+    }
+}
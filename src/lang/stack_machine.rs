@@ -1,37 +1,25 @@
-use crate::bytes;
-use bytes::Bytes;
+use super::{
+    stack_format::{StackFormat, StackView},
+    stack_lang,
+};
 use io::{Read, Write};
+use num_bigint::BigUint;
 use std::{
     borrow::Cow,
-    cmp, io, mem,
+    cmp::{self, Ordering},
+    collections::HashMap,
+    fmt, fs, io, mem,
     ops::{Add, Deref, DerefMut},
+    process,
     str::FromStr,
 };
-use strum_macros::{EnumString, EnumVariantNames};
 
 /* The idea with a stack machine will be to... */
-#[derive(Debug, PartialEq, EnumString, EnumVariantNames)]
-#[strum(serialize_all = "lowercase")]
-enum StackFormat {
-    Decimal,
-    #[strum(serialize = "0b", serialize = "binary")]
-    Binary,
-    #[strum(serialize = "0x", serialize = "lowerhex")]
-    LowerHex,
-    #[strum(serialize = "0X", serialize = "upperhex")]
-    UpperHex,
-}
-
-impl From<&str> for StackFormat {
-    fn from(val: &str) -> Self {
-        StackFormat::from_str(val).unwrap_or(StackFormat::Decimal)
-    }
-}
 
 pub fn run() {
     let mut input = String::new();
     let mut sm = StackMachine::new();
-    let mut format = StackFormat::Decimal;
+    let mut view = StackView::default();
 
     println!("Welcome!");
 
@@ -54,40 +42,16 @@ pub fn run() {
             Err(e) => println!("error: {}", e),
         }
 
-        let mut args = input.split_whitespace();
-
-        match args.next().unwrap_or("") {
-            s @ ("0x" | "0X" | "0b") => format = s.into(),
-            "format" => {
-                args.next().map(|s| format = s.into());
-            }
-            "bp" => println!("{:#016x}", sm.bp()),
-            "sp" => println!("{:#016x}", sm.sp()),
-            "pop" => {
-                sm.pop().map(|x| println!("{}", x));
-            }
-            "add" => sm.add(get_op_bytes(args, &sm)),
-            "dup" => n_times(args, |_| sm.dup()),
-            // Try to interpret numbers.
-            s => {
-                if let Ok(n) = s.parse::<u8>() {
-                    sm.push(n);
-                } else if s.starts_with("0x") {
-                    let hex = s.strip_prefix("0x").unwrap();
-                    u8::from_str_radix(hex, 16).map(|x| sm.push(x));
-                } else {
-                    continue;
-                }
-            }
+        let mut line = LangInterpreter::from_line(input.as_str(), &mut sm);
+        line.view = view;
+        let result = line.interpret();
+        view = line.view;
+        if let Err(e) = result {
+            println!("error: {}", e.kind);
+            continue;
         }
 
-        let bytes = Bytes::from_slice(&sm.stack);
-        match format {
-            StackFormat::Decimal => println!("Stack: {:?}", sm.stack),
-            StackFormat::Binary => println!("Stack (0b): {:0>8b}", bytes),
-            StackFormat::LowerHex => println!("Stack (0x): {:0>2x}", bytes),
-            StackFormat::UpperHex => println!("Stack (0X): {:0>2X}", bytes),
-        }
+        println!("Stack ({}): {}", view, view.render(&sm.stack));
     }
 }
 
@@ -95,7 +59,7 @@ struct LangInterpreter<'sm> {
     input: String,
     multiline: bool,
     sm: &'sm mut StackMachine,
-    format: StackFormat,
+    view: StackView,
 }
 
 impl<'sm> LangInterpreter<'sm> {
@@ -104,7 +68,7 @@ impl<'sm> LangInterpreter<'sm> {
         Self {
             input: program.into().into(),
             multiline: true,
-            format: StackFormat::Decimal,
+            view: StackView::default(),
             sm,
         }
     }
@@ -116,19 +80,32 @@ impl<'sm> LangInterpreter<'sm> {
         this
     }
 
-    pub fn interpret(&mut self) {
+    pub fn interpret(&mut self) -> Result<(), LangError> {
         if self.multiline {
-            todo!()
+            self.interpret_program(&mut io::stdout())
         } else {
             self.interpret_line()
         }
     }
 
+    /// Compiles the whole program and then runs it, printing to `out`.
+    fn interpret_program(&mut self, out: &mut impl Write) -> Result<(), LangError> {
+        let program = compile(&self.input, self.sm.op_bytes)?;
+        Executor::new(self.sm)
+            .run(&program.instructions, out)
+            .map_err(|e| LangError {
+                line: program.lines[e.pc],
+                kind: LangErrorKind::Exec(e.kind),
+            })
+    }
+
     /// This could be made pure, but not gonna.
-    fn interpret_line(&mut self) {
+    fn interpret_line(&mut self) -> Result<(), LangError> {
         let mut args = self.input.split_whitespace();
         let sm = &mut self.sm;
-        let format = &mut self.format;
+        let view = &mut self.view;
+        let error = |kind| LangError { line: 1, kind };
+        let exec_error = |e: StackError| error(LangErrorKind::Exec(e.into()));
 
         // if let Some(first) = args.next() {
 
@@ -137,30 +114,299 @@ impl<'sm> LangInterpreter<'sm> {
         // }
 
         match args.next().unwrap_or("") {
-            s @ ("0x" | "0X" | "0b") => *format = s.into(),
-            "format" => {
-                args.next().map(|s| *format = s.into());
-            }
+            s @ ("0x" | "0X" | "0b") => view.format = s.into(),
+            s @ ("format" | "word" | "endian") => set_view(view, s, args),
             "bp" => println!("{:#016x}", sm.bp()),
             "sp" => println!("{:#016x}", sm.sp()),
             "pop" => {
-                sm.pop().map(|x| println!("{}", x));
+                if let Some(x) = sm.pop() {
+                    println!("{}", x);
+                }
             }
-            "add" => sm.add(get_op_bytes(args, &sm)),
             "dup" => n_times(args, |_| sm.dup()),
+            "heap" => println!("Heap: {:?}", sm.heap()),
+            s @ ("enter" | "leave" | "get" | "set") => {
+                frame_operation(sm, s, args).map_err(exec_error)?
+            }
+            s if operation(s).is_some() => {
+                let (op, bytes) = (operation(s).unwrap(), get_op_bytes(args, sm));
+                op(sm, bytes).map_err(exec_error)?;
+            }
+            "" => {}
             // Try to interpret numbers.
             s => {
+                let hex = s.strip_prefix("0x");
                 if let Ok(n) = s.parse::<u8>() {
                     sm.push(n);
-                } else if s.starts_with("0x") {
-                    let hex = s.strip_prefix("0x").unwrap();
-                    u8::from_str_radix(hex, 16).map(|x| sm.push(x));
+                } else if let Some(x) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    sm.push(x);
+                } else {
+                    return Err(error(LangErrorKind::UnknownWord(s.to_string())));
                 }
             }
         }
+        Ok(())
+    }
+}
+
+/// Runs the stack language program in the file at `path`, with values `bytes`
+/// wide. Exits with status 1 if the program can't be read or goes wrong.
+pub fn run_file(path: &str, bytes: u8) {
+    let source = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("could not read {}: {}", path, e);
+        process::exit(1);
+    });
+    let mut sm = StackMachine::new();
+    sm.op_bytes = bytes;
+    if let Err(e) = LangInterpreter::new(source, &mut sm).interpret() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[derive(Debug)]
+pub struct LangError {
+    pub line: usize,
+    pub kind: LangErrorKind,
+}
+
+#[derive(Debug)]
+pub enum LangErrorKind {
+    UnknownWord(String),
+    /// A number too big for the width of the machine's values.
+    OutOfRange(String),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    /// A jump, branch or call without a label to go to.
+    MissingLabel(String),
+    /// An `enter`, `leave`, `get` or `set` without the counts it needs.
+    MissingCount(String),
+    UnterminatedString,
+    /// Strings are pushed with a one byte length, so can't be any longer.
+    StringTooLong(usize),
+    Exec(ExecErrorKind),
+}
+
+impl fmt::Display for LangErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use LangErrorKind::*;
+        match self {
+            UnknownWord(word) => write!(f, "unknown word `{}`", word),
+            OutOfRange(number) => write!(f, "`{}` is too big", number),
+            UndefinedLabel(label) => write!(f, "undefined label `{}`", label),
+            DuplicateLabel(label) => write!(f, "label `{}` is already defined", label),
+            MissingLabel(word) => write!(f, "`{}` needs a label", word),
+            MissingCount(word) => write!(f, "`{}` needs a count", word),
+            UnterminatedString => write!(f, "unterminated string"),
+            StringTooLong(len) => write!(f, "strings can be 255 bytes at most, not {}", len),
+            Exec(e) => write!(f, "{}", e),
+        }
     }
 }
 
+impl fmt::Display for LangError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl std::error::Error for LangError {}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Word(&'a str),
+    Label(&'a str),
+    Str(String),
+}
+
+/// Splits a line into tokens, up to any `//` comment.
+fn tokenize(line: &str) -> Result<Vec<Token<'_>>, LangErrorKind> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() && !rest.starts_with("//") {
+        if let Some(string) = rest.strip_prefix('"') {
+            let mut text = String::new();
+            let mut chars = string.char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 1,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => text.push('\n'),
+                        Some((_, 't')) => text.push('\t'),
+                        Some((_, c)) => text.push(c),
+                        None => return Err(LangErrorKind::UnterminatedString),
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => return Err(LangErrorKind::UnterminatedString),
+                }
+            };
+            tokens.push(Token::Str(text));
+            rest = &string[end..];
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let word = &rest[..end];
+            tokens.push(match word.strip_suffix(':') {
+                Some(label) if !label.is_empty() => Token::Label(label),
+                _ => Token::Word(word),
+            });
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// A compiled program, and the line each instruction came from.
+struct Program {
+    instructions: Vec<Instruction>,
+    lines: Vec<usize>,
+}
+
+/// Compiles a program in the stack language. Numbers are pushed `bytes` wide.
+fn compile(source: &str, bytes: u8) -> Result<Program, LangError> {
+    let mut tokens = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line_tokens = tokenize(line).map_err(|kind| LangError { line: i + 1, kind })?;
+        tokens.extend(line_tokens.into_iter().map(|token| (i + 1, token)));
+    }
+
+    let mut program = Program {
+        instructions: Vec::new(),
+        lines: Vec::new(),
+    };
+    let mut labels = HashMap::new();
+    // Jumps to patch once every label is known, as (instruction, label, line).
+    let mut jumps = Vec::new();
+    let mut tokens = tokens.into_iter();
+    while let Some((line, token)) = tokens.next() {
+        let error = |kind| LangError { line, kind };
+        let instruction = match token {
+            Token::Label(label) => {
+                if labels.insert(label, program.instructions.len()).is_some() {
+                    return Err(error(LangErrorKind::DuplicateLabel(label.to_string())));
+                }
+                continue;
+            }
+            Token::Str(text) => {
+                if text.len() > u8::MAX as usize {
+                    return Err(error(LangErrorKind::StringTooLong(text.len())));
+                }
+                let mut bytes = text.into_bytes();
+                bytes.push(bytes.len() as u8);
+                Instruction::Push(bytes)
+            }
+            Token::Word(word @ ("jmp" | "branch" | "call")) => {
+                match tokens.next() {
+                    Some((_, Token::Word(label))) => {
+                        jumps.push((program.instructions.len(), label, line))
+                    }
+                    _ => return Err(error(LangErrorKind::MissingLabel(word.to_string()))),
+                }
+                match word {
+                    "jmp" => Instruction::Jmp(0),
+                    "branch" => Instruction::Branch(0),
+                    _ => Instruction::Call(0),
+                }
+            }
+            Token::Word(word @ ("enter" | "leave" | "get" | "set")) => {
+                let mut count = || match tokens.next() {
+                    Some((_, Token::Word(n))) => n.parse().ok(),
+                    _ => None,
+                };
+                let missing = || error(LangErrorKind::MissingCount(word.to_string()));
+                match word {
+                    "enter" => {
+                        let args = count().ok_or_else(missing)?;
+                        let locals = count().ok_or_else(missing)?;
+                        Instruction::Enter { args, locals }
+                    }
+                    "leave" => Instruction::Leave(count().ok_or_else(missing)?),
+                    "get" => Instruction::Get(count().ok_or_else(missing)?),
+                    _ => Instruction::Set(count().ok_or_else(missing)?),
+                }
+            }
+            Token::Word(word) => match word_instruction(word) {
+                Some(instruction) => instruction,
+                None => Instruction::Push(number(word, bytes).map_err(error)?),
+            },
+        };
+        program.instructions.push(instruction);
+        program.lines.push(line);
+    }
+
+    for (i, label, line) in jumps {
+        let target = *labels.get(label).ok_or_else(|| LangError {
+            line,
+            kind: LangErrorKind::UndefinedLabel(label.to_string()),
+        })?;
+        match &mut program.instructions[i] {
+            Instruction::Jmp(to) | Instruction::Branch(to) | Instruction::Call(to) => *to = target,
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+    Ok(program)
+}
+
+/// The instruction for a word that doesn't take any arguments.
+fn word_instruction(word: &str) -> Option<Instruction> {
+    use Instruction::*;
+    Some(match word {
+        "dup" => Dup,
+        "drop" => Drop,
+        "swap" => Swap,
+        "over" => Over,
+        "add" => Add,
+        "sub" => Sub,
+        "mul" => Mul,
+        "div" => Div,
+        "mod" => Mod,
+        "eq" => Eq,
+        "lt" => Lt,
+        "gt" => Gt,
+        "lte" => Lte,
+        "gte" => Gte,
+        "not" => Not,
+        "and" => And,
+        "or" => Or,
+        "xor" => Xor,
+        "shl" => Shl,
+        "shr" => Shr,
+        "alloc" => Alloc,
+        "load" => Load,
+        "store" => Store,
+        "return" => Return,
+        "print" => Print,
+        "type" => Type,
+        "noop" => Noop,
+        _ => return None,
+    })
+}
+
+/// Parses a decimal or `0x` hex number into `bytes` big-endian bytes.
+pub(super) fn number(word: &str, bytes: u8) -> Result<Vec<u8>, LangErrorKind> {
+    let parsed = match word.strip_prefix("0x") {
+        Some(hex) => BigUint::parse_bytes(hex.as_bytes(), 16),
+        None => BigUint::parse_bytes(word.as_bytes(), 10),
+    };
+    let digits = parsed
+        .ok_or_else(|| LangErrorKind::UnknownWord(word.to_string()))?
+        .to_bytes_be();
+    let padding = (bytes as usize)
+        .checked_sub(digits.len())
+        .ok_or_else(|| LangErrorKind::OutOfRange(word.to_string()))?;
+    Ok([vec![0; padding], digits].concat())
+}
+
+/// Handles `format <name>`, `word <bytes>` and `endian <big|little>`, which
+/// change how the REPL shows the stack. Anything unrecognised is ignored.
+fn set_view<'a>(view: &mut StackView, setting: &str, args: impl Iterator<Item = &'a str>) {
+    match setting {
+        "format" => with_next_arg(args, |format: StackFormat| view.format = format),
+        "word" => with_next_arg(args, |bytes| view.set_word(bytes)),
+        _ => with_next_arg(args, |endian| view.endian = endian),
+    };
+}
+
 /// Allows repeating an operation based upon a second arg.
 fn n_times<'a>(mut args: impl Iterator<Item = &'a str>, mut f: impl FnMut(u8)) {
     with_next_arg(args, |times: u8| {
@@ -179,31 +425,148 @@ fn with_next_arg<'a, T: FromStr, U>(
     mut args: impl Iterator<Item = &'a str>,
     mut f: impl FnMut(T) -> U,
 ) -> Option<U> {
-    get_next_arg(args).map(|x| f(x))
+    get_next_arg(args).map(f)
 }
 
 fn get_op_bytes<'a>(mut args: impl Iterator<Item = &'a str>, sm: &StackMachine) -> u8 {
     get_next_arg(args).unwrap_or(sm.op_bytes)
 }
 
+/// A StackMachine operation that works on operands `bytes` wide.
+pub(super) type Operation = fn(&mut StackMachine, u8) -> Result<(), StackError>;
+
+/// Runs `enter args locals`, `leave results`, `get local` or `set local` from
+/// the REPL. Missing counts are 0.
+fn frame_operation<'a>(
+    sm: &mut StackMachine,
+    name: &str,
+    mut args: impl Iterator<Item = &'a str>,
+) -> Result<(), StackError> {
+    let mut count = || get_next_arg(&mut args).unwrap_or(0);
+    let bytes = sm.op_bytes;
+    match name {
+        "enter" => {
+            let args = count();
+            sm.enter(args, count(), bytes)
+        }
+        "leave" => sm.leave(count(), bytes),
+        "get" => sm.get(count(), bytes),
+        _ => sm.set(count(), bytes),
+    }
+}
+
+/// Looks up the arithmetic, logic, comparison or memory operation called
+/// `name`.
+fn operation(name: &str) -> Option<Operation> {
+    Some(match name {
+        "add" => StackMachine::add,
+        "sub" => StackMachine::sub,
+        "mul" => StackMachine::mul,
+        "div" => StackMachine::div,
+        "mod" => StackMachine::rem,
+        "and" => StackMachine::and,
+        "or" => StackMachine::or,
+        "xor" => StackMachine::xor,
+        "not" => StackMachine::not,
+        "shl" => StackMachine::shl,
+        "shr" => StackMachine::shr,
+        "eq" => StackMachine::eq,
+        "lt" => StackMachine::lt,
+        "gt" => StackMachine::gt,
+        "lte" => StackMachine::lte,
+        "gte" => StackMachine::gte,
+        "alloc" => StackMachine::alloc,
+        "load" => StackMachine::load,
+        "store" => StackMachine::store,
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// An operation needed more bytes than the stack has.
+    Underflow {
+        needed: usize,
+        found: usize,
+    },
+    DivideByZero,
+    /// A load or store outside of the heap.
+    BadAddress(usize),
+    /// An allocation past the end of the addressable heap.
+    OutOfMemory,
+    /// Leaving a frame without having entered one.
+    NoFrame,
+    /// A local that isn't in the current frame.
+    BadLocal(usize),
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackError::Underflow { needed, found } => write!(
+                f,
+                "stack underflow: needed {} bytes but only {} are on the stack",
+                needed, found
+            ),
+            StackError::DivideByZero => write!(f, "division by zero"),
+            StackError::BadAddress(address) => {
+                write!(f, "address {:#x} is outside of the heap", address)
+            }
+            StackError::OutOfMemory => write!(f, "out of memory"),
+            StackError::NoFrame => write!(f, "leaving a frame that was never entered"),
+            StackError::BadLocal(local) => write!(f, "no local {} in this frame", local),
+        }
+    }
+}
+
+impl std::error::Error for StackError {}
+
+/// A stack of bytes, with operations on numbers of any width.
+///
+/// Operands are big-endian numbers `bytes` wide. Binary operations pop the top
+/// operand `b`, then the one beneath it `a`, and push `a op b`: so `a b sub`
+/// is `a - b`. Results wrap around at the operand width, like Rust's
+/// `wrapping_*` methods for the integer type of that width.
+///
+/// Beside the stack there's a heap: byte-addressed memory that values can be
+/// loaded from and stored to, with addresses that are values like any other.
+/// The stack itself is split into frames, one per call, and the values in the
+/// current frame can be addressed as locals.
 pub struct StackMachine {
     stack: Vec<u8>,
+    heap: Vec<u8>,
+    bp: usize,
+    /// The base pointers of the frames beneath the current one.
+    frames: Vec<usize>,
     /// The number of bytes an operation acts on as a default.
     op_bytes: u8,
 }
 
+/// The most the heap can grow to, however wide addresses are.
+const MAX_HEAP: usize = 1 << 24;
+
+/// The most the stack can grow to when making room for locals.
+const MAX_STACK: usize = 1 << 24;
+
 impl StackMachine {
     pub fn new() -> Self {
         Self {
             stack: Vec::new(),
+            heap: Vec::new(),
+            bp: 0,
+            frames: Vec::new(),
             op_bytes: 1,
         }
     }
 
-    /// Base pointer: the place the stack begins. We will for simplicity always
-    /// choose this to begin at 0.
+    /// Base pointer: where the current frame begins. Outside of any frame,
+    /// that's the bottom of the stack.
     pub fn bp(&self) -> usize {
-        0
+        self.bp
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
     }
 
     /// Stack pointer: the top of the stack, which is where the *next* element
@@ -224,32 +587,205 @@ impl StackMachine {
     //     self.stack.pop()
     // }
 
-    fn pop_n(&mut self, bytes: u8) -> Vec<u8> {
-        self.stack.split_off(self.sp() - bytes as usize)
-    }
-
-    pub fn add(&mut self, bytes: u8) {
-        let mut overflow: u8 = 0;
-        let vec = self
-            .pop_n(bytes)
-            .into_iter()
-            .zip(self.pop_n(bytes).into_iter())
-            .rev() // Big endian, start with the least significant first.
-            .map(
-                |(b1, b2)| match b1.checked_add(b2).and_then(|x| x.checked_add(overflow)) {
-                    Some(n) => {
-                        overflow = 0;
-                        n
-                    }
-                    None => {
-                        let res = b1.wrapping_add(b2).wrapping_add(overflow);
-                        overflow = 1;
-                        res
-                    }
-                },
-            );
+    /// The width operations use when they aren't told otherwise.
+    pub fn op_bytes(&self) -> u8 {
+        self.op_bytes
+    }
+
+    pub fn set_op_bytes(&mut self, bytes: u8) {
+        self.op_bytes = bytes;
+    }
+
+    pub fn pop_n(&mut self, bytes: u8) -> Result<Vec<u8>, StackError> {
+        self.check_depth(bytes as usize)?;
+        Ok(self.stack.split_off(self.sp() - bytes as usize))
+    }
+
+    /// Pops `b` and then `a`, returning `(a, b)`. Nothing is popped if there
+    /// isn't enough for both.
+    pub fn pop_pair(&mut self, bytes: u8) -> Result<(Vec<u8>, Vec<u8>), StackError> {
+        self.check_depth(bytes as usize * 2)?;
+        let b = self.pop_n(bytes)?;
+        let a = self.pop_n(bytes)?;
+        Ok((a, b))
+    }
+
+    pub(super) fn check_depth(&self, needed: usize) -> Result<(), StackError> {
+        if needed > self.sp() {
+            return Err(StackError::Underflow {
+                needed,
+                found: self.sp(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, bytes: u8) -> Result<(), StackError> {
+        let (mut a, b) = self.pop_pair(bytes)?;
+        add_in_place(&mut a, &b);
+        self.stack.extend(a);
+        Ok(())
+    }
+
+    pub fn sub(&mut self, bytes: u8) -> Result<(), StackError> {
+        let (mut a, b) = self.pop_pair(bytes)?;
+        sub_in_place(&mut a, &b);
+        self.stack.extend(a);
+        Ok(())
+    }
+
+    pub fn mul(&mut self, bytes: u8) -> Result<(), StackError> {
+        let (a, b) = self.pop_pair(bytes)?;
+        // Long multiplication, keeping only the low `bytes` of the product.
+        let n = a.len();
+        let mut product = vec![0; n];
+        for (i, &x) in a.iter().rev().enumerate() {
+            let mut carry = 0;
+            for (j, &y) in b.iter().rev().take(n - i).enumerate() {
+                let k = n - 1 - (i + j);
+                let sum = product[k] as u16 + x as u16 * y as u16 + carry;
+                product[k] = sum as u8;
+                carry = sum >> 8;
+            }
+        }
+        self.stack.extend(product);
+        Ok(())
+    }
+
+    /// Unsigned division, rounding towards zero.
+    pub fn div(&mut self, bytes: u8) -> Result<(), StackError> {
+        let (quotient, _) = self.divide(bytes)?;
+        self.stack.extend(quotient);
+        Ok(())
+    }
+
+    /// The remainder of unsigned division.
+    pub fn rem(&mut self, bytes: u8) -> Result<(), StackError> {
+        let (_, remainder) = self.divide(bytes)?;
+        self.stack.extend(remainder);
+        Ok(())
+    }
+
+    /// Pops the operands and returns the quotient and remainder, or leaves
+    /// them be when dividing by zero.
+    fn divide(&mut self, bytes: u8) -> Result<(Vec<u8>, Vec<u8>), StackError> {
+        self.check_depth(bytes as usize * 2)?;
+        if self.stack[self.sp() - bytes as usize..]
+            .iter()
+            .all(|&x| x == 0)
+        {
+            return Err(StackError::DivideByZero);
+        }
+        let (a, b) = self.pop_pair(bytes)?;
+
+        // Long division, a bit at a time. The remainder gets an extra byte, as
+        // it can briefly be shifted past the width of the operands.
+        let divisor = [&[0], &b[..]].concat();
+        let mut remainder = vec![0; divisor.len()];
+        let mut quotient = vec![0; a.len()];
+        for bit in 0..a.len() * 8 {
+            let mask = 0x80 >> (bit % 8);
+            shl1_in_place(&mut remainder, a[bit / 8] & mask != 0);
+            if remainder >= divisor {
+                sub_in_place(&mut remainder, &divisor);
+                quotient[bit / 8] |= mask;
+            }
+        }
+        Ok((quotient, remainder.split_off(1)))
+    }
+
+    pub fn and(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.bitwise(bytes, |a, b| a & b)
+    }
+
+    pub fn or(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.bitwise(bytes, |a, b| a | b)
+    }
+
+    pub fn xor(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.bitwise(bytes, |a, b| a ^ b)
+    }
+
+    pub fn not(&mut self, bytes: u8) -> Result<(), StackError> {
+        let a = self.pop_n(bytes)?;
+        self.stack.extend(a.into_iter().map(|x| !x));
+        Ok(())
+    }
+
+    fn bitwise(&mut self, bytes: u8, op: impl Fn(u8, u8) -> u8) -> Result<(), StackError> {
+        let (a, b) = self.pop_pair(bytes)?;
+        self.stack
+            .extend(a.into_iter().zip(b).map(|(a, b)| op(a, b)));
+        Ok(())
+    }
+
+    /// Shifts left by the number of bits on top of the stack, which is as wide
+    /// as the value shifted.
+    pub fn shl(&mut self, bytes: u8) -> Result<(), StackError> {
+        let (a, shift) = self.pop_shift(bytes)?;
+        let (skip, bits) = (shift / 8, shift % 8);
+        // Each result byte is made from two neighbouring bytes further right.
+        let byte = |i: usize| a.get(i + skip).copied().unwrap_or(0) as u16;
+        let shifted = (0..a.len()).map(|i| ((byte(i) << 8 | byte(i + 1)) >> (8 - bits)) as u8);
+        self.stack.extend(shifted.collect::<Vec<_>>());
+        Ok(())
+    }
+
+    /// A logical shift right by the number of bits on top of the stack.
+    pub fn shr(&mut self, bytes: u8) -> Result<(), StackError> {
+        let (a, shift) = self.pop_shift(bytes)?;
+        let (skip, bits) = (shift / 8, shift % 8);
+        // Each result byte is made from two neighbouring bytes further left.
+        let byte = |i: usize| match i.checked_sub(skip) {
+            Some(i) => a[i] as u16,
+            None => 0,
+        };
+        let shifted = (0..a.len()).map(|i| {
+            let before = if i == 0 { 0 } else { byte(i - 1) };
+            ((before << 8 | byte(i)) >> bits) as u8
+        });
+        self.stack.extend(shifted.collect::<Vec<_>>());
+        Ok(())
+    }
+
+    /// Shifting by the width of the value or more leaves 0.
+    fn pop_shift(&mut self, bytes: u8) -> Result<(Vec<u8>, usize), StackError> {
+        self.check_depth(bytes as usize * 2)?;
+        let shift = self.peek_usize(bytes)?.min(bytes as usize * 8);
+        let (a, _) = self.pop_pair(bytes)?;
+        Ok((a, shift))
+    }
+
+    /// Comparisons push a flag as wide as their operands: all ones if true,
+    /// and zero if not, so that flags work with the bitwise operations.
+    pub fn eq(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.compare(bytes, |o| o == Ordering::Equal)
+    }
+
+    pub fn lt(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.compare(bytes, |o| o == Ordering::Less)
+    }
+
+    pub fn gt(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.compare(bytes, |o| o == Ordering::Greater)
+    }
+
+    pub fn lte(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.compare(bytes, |o| o != Ordering::Greater)
+    }
+
+    pub fn gte(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.compare(bytes, |o| o != Ordering::Less)
+    }
 
-        self.stack.extend(vec);
+    /// Compares the operands as unsigned numbers.
+    fn compare(&mut self, bytes: u8, test: impl Fn(Ordering) -> bool) -> Result<(), StackError> {
+        let (a, b) = self.pop_pair(bytes)?;
+        // Big-endian numbers of the same width compare like their bytes.
+        let flag = if test(a.cmp(&b)) { 0xFF } else { 0 };
+        let len = self.stack.len() + bytes as usize;
+        self.stack.resize(len, flag);
+        Ok(())
     }
 
     pub fn dup(&mut self) {
@@ -257,6 +793,198 @@ impl StackMachine {
             self.stack.push(x);
         }
     }
+
+    /// Duplicates the top value `bytes` wide.
+    pub fn dup_n(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.check_depth(bytes as usize)?;
+        let top = self.stack[self.sp() - bytes as usize..].to_vec();
+        self.stack.extend(top);
+        Ok(())
+    }
+
+    /// Discards the top value `bytes` wide.
+    pub fn drop_n(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.pop_n(bytes).map(|_| ())
+    }
+
+    /// Swaps the top two values `bytes` wide.
+    pub fn swap_n(&mut self, bytes: u8) -> Result<(), StackError> {
+        let (a, b) = self.pop_pair(bytes)?;
+        self.stack.extend(b);
+        self.stack.extend(a);
+        Ok(())
+    }
+
+    /// Pushes a copy of the value beneath the top one, both `bytes` wide.
+    pub fn over_n(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.check_depth(bytes as usize * 2)?;
+        let start = self.sp() - bytes as usize * 2;
+        let second = self.stack[start..start + bytes as usize].to_vec();
+        self.stack.extend(second);
+        Ok(())
+    }
+
+    /// Pops a size, and pushes the address of that many new zeroed bytes of
+    /// heap. Memory is never freed.
+    pub fn alloc(&mut self, bytes: u8) -> Result<(), StackError> {
+        let size = self.peek_usize(bytes)?;
+        let address = self.heap.len();
+        let end = address.checked_add(size).ok_or(StackError::OutOfMemory)?;
+        let pointer = usize_to_bytes(address, bytes).ok_or(StackError::OutOfMemory)?;
+        if end > MAX_HEAP {
+            return Err(StackError::OutOfMemory);
+        }
+        self.heap.resize(end, 0);
+        self.drop_n(bytes)?;
+        self.stack.extend(pointer);
+        Ok(())
+    }
+
+    /// Pops an address, and pushes the value at it.
+    pub fn load(&mut self, bytes: u8) -> Result<(), StackError> {
+        let address = self.heap_address(bytes)?;
+        self.drop_n(bytes)?;
+        let value = self.heap[address..address + bytes as usize].to_vec();
+        self.stack.extend(value);
+        Ok(())
+    }
+
+    /// Pops an address, and then a value to store at it: `value address store`.
+    pub fn store(&mut self, bytes: u8) -> Result<(), StackError> {
+        self.check_depth(bytes as usize * 2)?;
+        let address = self.heap_address(bytes)?;
+        let (value, _) = self.pop_pair(bytes)?;
+        self.heap[address..address + bytes as usize].copy_from_slice(&value);
+        Ok(())
+    }
+
+    /// The address on top of the stack, if there's a value `bytes` wide there.
+    fn heap_address(&self, bytes: u8) -> Result<usize, StackError> {
+        let address = self.peek_usize(bytes)?;
+        match address.checked_add(bytes as usize) {
+            Some(end) if end <= self.heap.len() => Ok(address),
+            _ => Err(StackError::BadAddress(address)),
+        }
+    }
+
+    /// The value on top of the stack as a usize, or usize::MAX if it's bigger.
+    fn peek_usize(&self, bytes: u8) -> Result<usize, StackError> {
+        self.check_depth(bytes as usize)?;
+        let value = &self.stack[self.sp() - bytes as usize..];
+        let digits = value.iter().skip_while(|&&x| x == 0);
+        Ok(digits.fold(0, |n: usize, &x| {
+            n.checked_mul(256)
+                .and_then(|n| n.checked_add(x as usize))
+                .unwrap_or(usize::MAX)
+        }))
+    }
+
+    /// Starts a new frame, made of the top `args` values and then `locals`
+    /// zeroed ones. They're locals 0 to `args + locals - 1` in the frame.
+    pub fn enter(&mut self, args: usize, locals: usize, bytes: u8) -> Result<(), StackError> {
+        let args = args.saturating_mul(bytes as usize);
+        self.check_depth(args)?;
+        let sp = locals
+            .checked_mul(bytes as usize)
+            .and_then(|locals| self.sp().checked_add(locals))
+            .filter(|&sp| sp <= MAX_STACK)
+            .ok_or(StackError::OutOfMemory)?;
+        self.frames.push(self.bp);
+        self.bp = self.sp() - args;
+        self.stack.resize(sp, 0);
+        Ok(())
+    }
+
+    /// Throws away the current frame, apart from the top `results` values,
+    /// which are left on top of the frame beneath.
+    pub fn leave(&mut self, results: usize, bytes: u8) -> Result<(), StackError> {
+        let bp = *self.frames.last().ok_or(StackError::NoFrame)?;
+        let results = results.saturating_mul(bytes as usize);
+        // Anything can pop values from beneath the frame, so it might not
+        // even have its base left.
+        match self.sp().checked_sub(self.bp) {
+            Some(len) if len >= results => {}
+            _ => {
+                return Err(StackError::Underflow {
+                    needed: self.bp.saturating_add(results),
+                    found: self.sp(),
+                })
+            }
+        }
+        let results = self.stack.split_off(self.sp() - results);
+        self.stack.truncate(self.bp);
+        self.stack.extend(results);
+        self.bp = bp;
+        self.frames.pop();
+        Ok(())
+    }
+
+    /// Pushes a copy of the `local`th value in the current frame.
+    pub fn get(&mut self, local: usize, bytes: u8) -> Result<(), StackError> {
+        let start = self.local(local, bytes, self.sp())?;
+        let value = self.stack[start..start + bytes as usize].to_vec();
+        self.stack.extend(value);
+        Ok(())
+    }
+
+    /// Pops a value into the `local`th value in the current frame.
+    pub fn set(&mut self, local: usize, bytes: u8) -> Result<(), StackError> {
+        self.check_depth(bytes as usize)?;
+        // The local has to be beneath the value being popped.
+        let start = self.local(local, bytes, self.sp() - bytes as usize)?;
+        let value = self.pop_n(bytes)?;
+        self.stack[start..start + bytes as usize].copy_from_slice(&value);
+        Ok(())
+    }
+
+    /// Where the `local`th value of the frame starts, if it ends by `end`.
+    fn local(&self, local: usize, bytes: u8, end: usize) -> Result<usize, StackError> {
+        let start = local
+            .checked_mul(bytes as usize)
+            .and_then(|offset| self.bp.checked_add(offset))
+            .filter(|&start| matches!(start.checked_add(bytes as usize), Some(e) if e <= end));
+        start.ok_or(StackError::BadLocal(local))
+    }
+}
+
+/// `n` as a big-endian number `bytes` wide, if it fits.
+fn usize_to_bytes(n: usize, bytes: u8) -> Option<Vec<u8>> {
+    let digits = n.to_be_bytes();
+    let significant = digits.iter().skip_while(|&&x| x == 0).count();
+    let padding = (bytes as usize).checked_sub(significant)?;
+    Some([&vec![0; padding][..], &digits[digits.len() - significant..]].concat())
+}
+
+/// `a += b`, wrapping, for big-endian numbers of the same width.
+fn add_in_place(a: &mut [u8], b: &[u8]) {
+    let mut carry = false;
+    for (x, &y) in a.iter_mut().zip(b).rev() {
+        let (sum, c1) = x.overflowing_add(y);
+        let (sum, c2) = sum.overflowing_add(carry as u8);
+        *x = sum;
+        carry = c1 || c2;
+    }
+}
+
+/// `a = a << 1 | bit`, for a big-endian number.
+fn shl1_in_place(a: &mut [u8], bit: bool) {
+    let mut carry = bit as u8;
+    for x in a.iter_mut().rev() {
+        let out = *x >> 7;
+        *x = *x << 1 | carry;
+        carry = out;
+    }
+}
+
+/// `a -= b`, wrapping, for big-endian numbers of the same width.
+fn sub_in_place(a: &mut [u8], b: &[u8]) {
+    let mut borrow = false;
+    for (x, &y) in a.iter_mut().zip(b).rev() {
+        let (diff, b1) = x.overflowing_sub(y);
+        let (diff, b2) = diff.overflowing_sub(borrow as u8);
+        *x = diff;
+        borrow = b1 || b2;
+    }
 }
 
 impl Deref for StackMachine {
@@ -272,12 +1000,22 @@ impl DerefMut for StackMachine {
     }
 }
 
-enum Instruction {
+/// An instruction for `Executor`. Operations work on values `op_bytes` wide,
+/// and jump targets are indexes into the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    /// Pushes its bytes, most significant first.
+    Push(Vec<u8>),
+    Dup,
+    Drop,
+    Swap,
+    Over,
     // Arithmetic
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
     // Comparison
     Eq,
     Lt,
@@ -288,31 +1026,282 @@ enum Instruction {
     Not,
     And,
     Or,
+    Xor,
+    Shl,
+    Shr,
+    // Memory
+    /// Pops a size, and pushes the address of that much new heap.
+    Alloc,
+    /// Pops an address, and pushes the value at it in the heap.
+    Load,
+    /// Pops an address, and then the value to store there.
+    Store,
+    /// Starts a frame holding the top `args` values and `locals` more.
+    Enter {
+        args: usize,
+        locals: usize,
+    },
+    /// Ends the current frame, keeping the top `results` values.
+    Leave(usize),
+    /// Pushes a copy of a local from the current frame.
+    Get(usize),
+    /// Pops a value into a local in the current frame.
+    Set(usize),
     // Control
-    Jmp,
-    Branch,
-    Call,
+    Jmp(usize),
+    /// Pops a flag, like the ones comparisons push, and jumps if it isn't
+    /// zero.
+    Branch(usize),
+    /// Jumps, pushing the index of the next instruction to the return stack.
+    Call(usize),
     Return,
+    /// Pops a value and prints it as an unsigned decimal number.
     Print,
+    /// Pops a length byte, then prints that many bytes beneath it as text.
+    /// String literals push themselves this way.
+    Type,
     Noop,
 }
 
+impl stack_lang::Instruction for Instruction {
+    fn is_control(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Jmp(_) | Instruction::Branch(_) | Instruction::Return
+        )
+    }
+
+    fn jump_targets(&self, _: usize) -> Vec<usize> {
+        match self {
+            &Instruction::Jmp(target) | &Instruction::Branch(target) => vec![target],
+            _ => Vec::new(),
+        }
+    }
+
+    fn falls_through(&self) -> bool {
+        !matches!(self, Instruction::Jmp(_) | Instruction::Return)
+    }
+}
+
+#[derive(Debug)]
+pub struct ExecError {
+    /// The index of the instruction that failed.
+    pub pc: usize,
+    pub kind: ExecErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ExecErrorKind {
+    Stack(StackError),
+    /// A Return without a Call.
+    ReturnStackUnderflow,
+    /// More than `MAX_CALLS` calls that haven't returned yet.
+    ReturnStackOverflow,
+    /// A jump past the end of the program.
+    BadTarget(usize),
+    Io(io::Error),
+}
+
+impl fmt::Display for ExecErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecErrorKind::Stack(e) => write!(f, "{}", e),
+            ExecErrorKind::ReturnStackUnderflow => write!(f, "return without a call"),
+            ExecErrorKind::ReturnStackOverflow => {
+                write!(f, "calls nested more than {} deep", MAX_CALLS)
+            }
+            ExecErrorKind::BadTarget(target) => {
+                write!(f, "jump to {}, which is outside the program", target)
+            }
+            ExecErrorKind::Io(e) => write!(f, "could not print: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at instruction {})", self.kind, self.pc)
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+impl From<StackError> for ExecErrorKind {
+    fn from(e: StackError) -> Self {
+        ExecErrorKind::Stack(e)
+    }
+}
+
+impl From<io::Error> for ExecErrorKind {
+    fn from(e: io::Error) -> Self {
+        ExecErrorKind::Io(e)
+    }
+}
+
+/// The deepest calls can nest, so that runaway recursion is an error rather
+/// than a return stack that grows until memory runs out.
+pub const MAX_CALLS: usize = 1 << 16;
+
+/// Runs a program of `Instruction`s against a `StackMachine`. Call and Return
+/// use a return stack of their own, so subroutines don't need to keep their
+/// return addresses out of the way of the values they work on.
+pub struct Executor<'sm> {
+    sm: &'sm mut StackMachine,
+    return_stack: Vec<usize>,
+    /// The index of the next instruction to run.
+    pc: usize,
+}
+
+impl<'sm> Executor<'sm> {
+    pub fn new(sm: &'sm mut StackMachine) -> Self {
+        Self {
+            sm,
+            return_stack: Vec::new(),
+            pc: 0,
+        }
+    }
+
+    /// Runs `program` from the start until it runs off the end, printing to
+    /// `out`.
+    pub fn run(&mut self, program: &[Instruction], out: &mut impl Write) -> Result<(), ExecError> {
+        self.pc = 0;
+        self.return_stack.clear();
+        while self.pc < program.len() {
+            let pc = self.pc;
+            self.step(program, out)
+                .map_err(|kind| ExecError { pc, kind })?;
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, program: &[Instruction], out: &mut impl Write) -> Result<(), ExecErrorKind> {
+        let sm = &mut self.sm;
+        let bytes = sm.op_bytes;
+        self.pc += 1;
+        match &program[self.pc - 1] {
+            Instruction::Push(value) => sm.extend_from_slice(value),
+            Instruction::Dup => sm.dup_n(bytes)?,
+            Instruction::Drop => sm.drop_n(bytes)?,
+            Instruction::Swap => sm.swap_n(bytes)?,
+            Instruction::Over => sm.over_n(bytes)?,
+            Instruction::Add => sm.add(bytes)?,
+            Instruction::Sub => sm.sub(bytes)?,
+            Instruction::Mul => sm.mul(bytes)?,
+            Instruction::Div => sm.div(bytes)?,
+            Instruction::Mod => sm.rem(bytes)?,
+            Instruction::Eq => sm.eq(bytes)?,
+            Instruction::Lt => sm.lt(bytes)?,
+            Instruction::Gt => sm.gt(bytes)?,
+            Instruction::Lte => sm.lte(bytes)?,
+            Instruction::Gte => sm.gte(bytes)?,
+            Instruction::Not => sm.not(bytes)?,
+            Instruction::And => sm.and(bytes)?,
+            Instruction::Or => sm.or(bytes)?,
+            Instruction::Xor => sm.xor(bytes)?,
+            Instruction::Shl => sm.shl(bytes)?,
+            Instruction::Shr => sm.shr(bytes)?,
+            Instruction::Alloc => sm.alloc(bytes)?,
+            Instruction::Load => sm.load(bytes)?,
+            Instruction::Store => sm.store(bytes)?,
+            &Instruction::Enter { args, locals } => sm.enter(args, locals, bytes)?,
+            &Instruction::Leave(results) => sm.leave(results, bytes)?,
+            &Instruction::Get(local) => sm.get(local, bytes)?,
+            &Instruction::Set(local) => sm.set(local, bytes)?,
+            &Instruction::Jmp(target) => self.jump(target, program)?,
+            &Instruction::Branch(target) => {
+                if sm.pop_n(bytes)?.iter().any(|&byte| byte != 0) {
+                    self.jump(target, program)?;
+                }
+            }
+            &Instruction::Call(target) => {
+                if self.return_stack.len() == MAX_CALLS {
+                    return Err(ExecErrorKind::ReturnStackOverflow);
+                }
+                self.return_stack.push(self.pc);
+                self.jump(target, program)?;
+            }
+            Instruction::Return => {
+                self.pc = self
+                    .return_stack
+                    .pop()
+                    .ok_or(ExecErrorKind::ReturnStackUnderflow)?;
+            }
+            Instruction::Print => {
+                let value = sm.pop_n(bytes)?;
+                writeln!(out, "{}", BigUint::from_bytes_be(&value))?;
+            }
+            Instruction::Type => {
+                let len = sm.pop_n(1)?[0];
+                let text = sm.pop_n(len)?;
+                write!(out, "{}", String::from_utf8_lossy(&text))?;
+            }
+            Instruction::Noop => {}
+        }
+        Ok(())
+    }
+
+    /// Jumping to the very end of the program is fine, and ends it.
+    fn jump(&mut self, target: usize, program: &[Instruction]) -> Result<(), ExecErrorKind> {
+        if target > program.len() {
+            return Err(ExecErrorKind::BadTarget(target));
+        }
+        self.pc = target;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::StackMachine;
+    use super::*;
+
+    /// Numbers either side of each byte boundary, where carries and borrows
+    /// happen.
+    const EDGES: [u16; 6] = [0, 1, 0xFF, 0x100, 0x8000, 0xFFFF];
+
+    /// Runs `op` on a fresh stack holding `operands`.
+    fn apply(op: Operation, bytes: u8, operands: &[&[u8]]) -> Result<Vec<u8>, StackError> {
+        let mut sm = StackMachine::new();
+        for operand in operands {
+            sm.extend_from_slice(operand);
+        }
+        op(&mut sm, bytes).map(|_| sm.stack)
+    }
+
+    /// Checks `op` against `expected` for every pair of bytes.
+    fn every_8bit(op: Operation, expected: impl Fn(u8, u8) -> Option<Vec<u8>>) {
+        for a in 0..=u8::MAX {
+            for b in 0..=u8::MAX {
+                let result = apply(op, 1, &[&[a], &[b]]).ok();
+                assert_eq!(result, expected(a, b), "failed on {} and {}", a, b);
+            }
+        }
+    }
+
+    /// Checks `op` against `expected` for every 16-bit number paired with each
+    /// of the `EDGES`, both ways round.
+    fn every_16bit(op: Operation, expected: impl Fn(u16, u16) -> Option<Vec<u8>>) {
+        for n in 0..=u16::MAX {
+            for &edge in &EDGES {
+                for &(a, b) in &[(n, edge), (edge, n)] {
+                    let result = apply(op, 2, &[&a.to_be_bytes(), &b.to_be_bytes()]).ok();
+                    assert_eq!(result, expected(a, b), "failed on {} and {}", a, b);
+                }
+            }
+        }
+    }
 
     #[test]
     fn adding_16bit_works() {
         let mut sm = StackMachine::new();
         // Overflow to 0.
         sm.extend_from_slice(&[255, 255, 0, 1]);
-        sm.add(2);
+        sm.add(2).unwrap();
         assert_eq!(&sm.stack, &[0, 0]);
         // Overflow to n u8.
         for n in 0..u8::MAX {
             sm.clear();
             sm.extend_from_slice(&[255, 255, 0, 1 + n]);
-            sm.add(2);
+            sm.add(2).unwrap();
             assert_eq!(&sm.stack, &[0, n], "failed on {}", n);
         }
         // Overflow to n, u16.
@@ -320,8 +1309,492 @@ mod tests {
             sm.clear();
             sm.extend_from_slice(&[255, 255]);
             sm.extend_from_slice(&(n + 1).to_be_bytes());
-            sm.add(2);
+            sm.add(2).unwrap();
             assert_eq!(&sm.stack, &n.to_be_bytes());
         }
+        every_8bit(StackMachine::add, |a, b| Some(vec![a.wrapping_add(b)]));
+        every_16bit(StackMachine::add, |a, b| {
+            Some(a.wrapping_add(b).to_be_bytes().to_vec())
+        });
+    }
+
+    #[test]
+    fn subtracting_borrows() {
+        every_8bit(StackMachine::sub, |a, b| Some(vec![a.wrapping_sub(b)]));
+        every_16bit(StackMachine::sub, |a, b| {
+            Some(a.wrapping_sub(b).to_be_bytes().to_vec())
+        });
+    }
+
+    #[test]
+    fn multiplying_works() {
+        every_8bit(StackMachine::mul, |a, b| Some(vec![a.wrapping_mul(b)]));
+        every_16bit(StackMachine::mul, |a, b| {
+            Some(a.wrapping_mul(b).to_be_bytes().to_vec())
+        });
+        // Wider than any primitive.
+        let a = u128::MAX / 3;
+        let b: u128 = 1 << 100 | 12345;
+        let result = apply(StackMachine::mul, 16, &[&a.to_be_bytes(), &b.to_be_bytes()]);
+        assert_eq!(result.unwrap(), a.wrapping_mul(b).to_be_bytes());
+    }
+
+    #[test]
+    fn dividing_works() {
+        every_8bit(StackMachine::div, |a, b| a.checked_div(b).map(|q| vec![q]));
+        every_8bit(StackMachine::rem, |a, b| a.checked_rem(b).map(|r| vec![r]));
+        every_16bit(StackMachine::div, |a, b| {
+            a.checked_div(b).map(|q| q.to_be_bytes().to_vec())
+        });
+        every_16bit(StackMachine::rem, |a, b| {
+            a.checked_rem(b).map(|r| r.to_be_bytes().to_vec())
+        });
+
+        // Dividing by zero leaves the operands where they were.
+        let mut sm = StackMachine::new();
+        sm.extend_from_slice(&[1, 2, 0, 0]);
+        assert_eq!(sm.div(2), Err(StackError::DivideByZero));
+        assert_eq!(&sm.stack, &[1, 2, 0, 0]);
+    }
+
+    #[test]
+    fn bitwise_operations_work() {
+        every_8bit(StackMachine::and, |a, b| Some(vec![a & b]));
+        every_8bit(StackMachine::or, |a, b| Some(vec![a | b]));
+        every_8bit(StackMachine::xor, |a, b| Some(vec![a ^ b]));
+        every_16bit(StackMachine::and, |a, b| {
+            Some((a & b).to_be_bytes().to_vec())
+        });
+        every_16bit(StackMachine::or, |a, b| {
+            Some((a | b).to_be_bytes().to_vec())
+        });
+        every_16bit(StackMachine::xor, |a, b| {
+            Some((a ^ b).to_be_bytes().to_vec())
+        });
+        for n in 0..=u16::MAX {
+            let result = apply(StackMachine::not, 2, &[&n.to_be_bytes()]);
+            assert_eq!(result.unwrap(), (!n).to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn shifting_works() {
+        for a in 0..=u8::MAX {
+            for shift in 0..=u8::MAX {
+                let expected = a.checked_shl(shift as u32).unwrap_or(0);
+                assert_eq!(
+                    apply(StackMachine::shl, 1, &[&[a], &[shift]]).unwrap(),
+                    [expected]
+                );
+                let expected = a.checked_shr(shift as u32).unwrap_or(0);
+                assert_eq!(
+                    apply(StackMachine::shr, 1, &[&[a], &[shift]]).unwrap(),
+                    [expected]
+                );
+            }
+        }
+        for a in 0..=u16::MAX {
+            for &shift in &[0, 1, 7, 8, 9, 15, 16, 17, 0x100, u16::MAX] {
+                let operands: &[&[u8]] = &[&a.to_be_bytes(), &shift.to_be_bytes()];
+                let expected = a.checked_shl(shift as u32).unwrap_or(0);
+                assert_eq!(
+                    apply(StackMachine::shl, 2, operands).unwrap(),
+                    expected.to_be_bytes()
+                );
+                let expected = a.checked_shr(shift as u32).unwrap_or(0);
+                assert_eq!(
+                    apply(StackMachine::shr, 2, operands).unwrap(),
+                    expected.to_be_bytes()
+                );
+            }
+        }
+    }
+
+    /// Checks that `op` pushes all ones when `test` holds, and 0 when it
+    /// doesn't.
+    fn check_comparison(op: Operation, test: fn(&u16, &u16) -> bool) {
+        let flag = |set, bytes| vec![if set { 0xFF } else { 0 }; bytes];
+        every_8bit(op, |a, b| Some(flag(test(&(a as u16), &(b as u16)), 1)));
+        every_16bit(op, |a, b| Some(flag(test(&a, &b), 2)));
+    }
+
+    #[test]
+    fn comparisons_are_unsigned() {
+        check_comparison(StackMachine::eq, PartialEq::eq);
+        check_comparison(StackMachine::lt, PartialOrd::lt);
+        check_comparison(StackMachine::gt, PartialOrd::gt);
+        check_comparison(StackMachine::lte, PartialOrd::le);
+        check_comparison(StackMachine::gte, PartialOrd::ge);
+    }
+
+    #[test]
+    fn operations_check_the_stack_depth() {
+        let mut sm = StackMachine::new();
+        sm.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(
+            sm.add(2),
+            Err(StackError::Underflow {
+                needed: 4,
+                found: 3
+            })
+        );
+        assert_eq!(
+            sm.shl(2),
+            Err(StackError::Underflow {
+                needed: 4,
+                found: 3
+            })
+        );
+        assert_eq!(&sm.stack, &[1, 2, 3]);
+        // The default width comes from op_bytes.
+        sm.sub(sm.op_bytes).unwrap();
+        assert_eq!(&sm.stack, &[1, 255]);
+    }
+
+    #[test]
+    fn shuffling_the_stack_works() {
+        let mut sm = StackMachine::new();
+        sm.extend_from_slice(&[1, 2, 3, 4]);
+        sm.over_n(2).unwrap();
+        assert_eq!(&sm.stack, &[1, 2, 3, 4, 1, 2]);
+        sm.swap_n(2).unwrap();
+        assert_eq!(&sm.stack, &[1, 2, 1, 2, 3, 4]);
+        sm.drop_n(4).unwrap();
+        sm.dup_n(2).unwrap();
+        assert_eq!(&sm.stack, &[1, 2, 1, 2]);
+        assert!(sm.over_n(4).is_err());
+    }
+
+    #[test]
+    fn the_heap_is_addressable() {
+        let mut sm = StackMachine::new();
+        sm.extend_from_slice(&[0, 3]);
+        sm.alloc(2).unwrap();
+        sm.extend_from_slice(&[0, 4]);
+        sm.alloc(2).unwrap();
+        assert_eq!(&sm.stack, &[0, 0, 0, 3]);
+        assert_eq!(sm.heap(), &[0; 7]);
+
+        // value address store
+        sm.extend_from_slice(&[0x12, 0x34, 0, 5]);
+        sm.store(2).unwrap();
+        sm.extend_from_slice(&[0, 4]);
+        sm.load(2).unwrap();
+        assert_eq!(&sm.stack, &[0, 0, 0, 3, 0, 0x12]);
+        assert_eq!(sm.heap(), &[0, 0, 0, 0, 0, 0x12, 0x34]);
+
+        assert_eq!(sm.load(2), Err(StackError::BadAddress(18)));
+        sm.extend_from_slice(&[0, 6]);
+        assert_eq!(sm.load(2), Err(StackError::BadAddress(6)));
+        // Failed operations leave the stack alone.
+        assert_eq!(&sm.stack, &[0, 0, 0, 3, 0, 0x12, 0, 6]);
+        sm.push(255);
+        assert_eq!(sm.alloc(1), Ok(()));
+        // The next address, 262, is too wide.
+        assert_eq!(sm.alloc(1), Err(StackError::OutOfMemory));
+    }
+
+    #[test]
+    fn frames_hold_locals() {
+        let mut sm = StackMachine::new();
+        sm.extend_from_slice(&[9, 1, 2]);
+        sm.enter(2, 1, 1).unwrap();
+        assert_eq!((sm.bp(), sm.sp()), (1, 4));
+        sm.get(1, 1).unwrap();
+        sm.set(2, 1).unwrap();
+        assert_eq!(&sm.stack, &[9, 1, 2, 2]);
+        assert_eq!(sm.get(3, 1), Err(StackError::BadLocal(3)));
+        assert_eq!(sm.set(3, 1), Err(StackError::BadLocal(3)));
+
+        // Frames nest, with locals counted from the base of each.
+        sm.extend_from_slice(&[0, 7]);
+        sm.enter(1, 0, 2).unwrap();
+        assert_eq!(sm.bp(), 4);
+        sm.get(0, 2).unwrap();
+        sm.add(2).unwrap();
+        sm.leave(1, 2).unwrap();
+        assert_eq!((sm.bp(), &sm.stack[..]), (1, &[9, 1, 2, 2, 0, 14][..]));
+
+        assert!(sm.leave(3, 2).is_err());
+        sm.leave(0, 1).unwrap();
+        assert_eq!((sm.bp(), &sm.stack[..]), (0, &[9][..]));
+        assert_eq!(sm.leave(0, 1), Err(StackError::NoFrame));
+    }
+
+    #[test]
+    fn frames_are_checked() {
+        let mut sm = StackMachine::new();
+        sm.extend_from_slice(&[1]);
+        sm.enter(0, 0, 1).unwrap();
+        sm.drop_n(1).unwrap();
+        assert_eq!(
+            sm.leave(0, 1),
+            Err(StackError::Underflow {
+                needed: 1,
+                found: 0
+            })
+        );
+        assert_eq!(sm.get(0, 1), Err(StackError::BadLocal(0)));
+
+        let mut sm = StackMachine::new();
+        assert_eq!(sm.enter(0, usize::MAX / 2, 2), Err(StackError::OutOfMemory));
+        assert_eq!(sm.enter(0, MAX_STACK + 1, 1), Err(StackError::OutOfMemory));
+        assert!(sm.enter(usize::MAX, 0, 2).is_err());
+        assert_eq!((sm.bp(), sm.sp()), (0, 0));
+        let e = interpret(1, "enter 0 99999999999").unwrap_err();
+        assert!(e.to_string().contains("out of memory"), "{}", e);
+    }
+
+    /// Runs `program` with values `bytes` wide, returning what it printed.
+    fn execute(bytes: u8, program: &[Instruction]) -> (StackMachine, String) {
+        let mut sm = StackMachine::new();
+        sm.op_bytes = bytes;
+        let mut out = Vec::new();
+        Executor::new(&mut sm).run(program, &mut out).unwrap();
+        (sm, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn executes_loops() {
+        use Instruction::*;
+        // Prints 3, 2, 1.
+        let program = [
+            Push(vec![3]),
+            Dup,
+            Print,
+            Push(vec![1]),
+            Sub,
+            Dup,
+            Push(vec![0]),
+            Gt,
+            Branch(1),
+            Drop,
+        ];
+        let (sm, out) = execute(1, &program);
+        assert_eq!(out, "3\n2\n1\n");
+        assert!(sm.is_empty());
+    }
+
+    #[test]
+    fn executes_subroutines() {
+        use Instruction::*;
+        let program = [
+            Push(vec![0, 5]),
+            Call(5),
+            Print,
+            Push(vec![0, 9]),
+            Jmp(18),
+            // ( n -- n! ), recursively.
+            Dup,
+            Push(vec![0, 1]),
+            Gt,
+            Branch(12),
+            Drop,
+            Push(vec![0, 1]),
+            Return,
+            Dup,
+            Push(vec![0, 1]),
+            Sub,
+            Call(5),
+            Mul,
+            Return,
+            Call(5),
+            Print,
+        ];
+        let (sm, out) = execute(2, &program);
+        // 9! doesn't fit in 2 bytes, and wraps around.
+        assert_eq!(out, format!("120\n{}\n", 362880 % 65536));
+        assert!(sm.is_empty());
+    }
+
+    #[test]
+    fn reports_where_execution_failed() {
+        use Instruction::*;
+        let run = |program: &[Instruction]| {
+            let mut sm = StackMachine::new();
+            let error = Executor::new(&mut sm)
+                .run(program, &mut Vec::new())
+                .unwrap_err();
+            (error.pc, error.to_string())
+        };
+        assert_eq!(
+            run(&[Noop, Push(vec![1]), Add]),
+            (
+                2,
+                "stack underflow: needed 2 bytes but only 1 are on the stack (at instruction 2)"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            run(&[Return]),
+            (0, "return without a call (at instruction 0)".to_string())
+        );
+        assert_eq!(
+            run(&[Call(0)]),
+            (
+                0,
+                format!(
+                    "calls nested more than {} deep (at instruction 0)",
+                    MAX_CALLS
+                )
+            )
+        );
+        assert_eq!(
+            run(&[Noop, Jmp(3)]),
+            (
+                1,
+                "jump to 3, which is outside the program (at instruction 1)".to_string()
+            )
+        );
+        let mut sm = StackMachine::new();
+        let error = Executor::new(&mut sm)
+            .run(&[Push(vec![1, 0]), Div], &mut Vec::new())
+            .unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ExecErrorKind::Stack(StackError::DivideByZero)
+        ));
+    }
+
+    /// Runs a multiline program with values `bytes` wide, returning what it
+    /// printed.
+    fn interpret(bytes: u8, program: &str) -> Result<String, LangError> {
+        let mut sm = StackMachine::new();
+        sm.op_bytes = bytes;
+        let mut out = Vec::new();
+        LangInterpreter::new(program, &mut sm).interpret_program(&mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn tokenizes_lines() {
+        assert_eq!(
+            tokenize(r#"  loop: "a \"b\"\n" print // "not a string""#).unwrap(),
+            vec![
+                Token::Label("loop"),
+                Token::Str("a \"b\"\n".to_string()),
+                Token::Word("print"),
+            ]
+        );
+        assert_eq!(tokenize("// nothing").unwrap(), vec![]);
+        assert!(matches!(
+            tokenize(r#"1 "oops"#),
+            Err(LangErrorKind::UnterminatedString)
+        ));
+    }
+
+    #[test]
+    fn interprets_programs() {
+        let program = r#"
+            // Prints the fibonacci numbers below 100.
+            "fibonacci:\n" type
+            0 1                 // a b
+        loop:
+            over print
+            swap over add       // b a+b
+            over 100 lt
+            branch loop
+            drop drop
+            "done\n" type
+        "#;
+        assert_eq!(
+            interpret(2, program).unwrap(),
+            "fibonacci:\n0\n1\n1\n2\n3\n5\n8\n13\n21\n34\n55\n89\ndone\n"
+        );
+
+        let program = "
+            0x10 call square print
+            jmp end
+        square: dup mul return
+        end:
+        ";
+        assert_eq!(interpret(4, program).unwrap(), "256\n");
+
+        let program = "1 3 shl print 0x8000 15 shr print 1 16 shl print";
+        assert_eq!(interpret(2, program).unwrap(), "8\n1\n0\n");
+
+        let program = "
+            // Fills an array with factorials, then prints them backwards.
+            12 alloc
+            enter 1 1           // local 0 is the array, and 1 the index
+        fill:
+            get 1 call fact
+            get 1 2 mul get 0 add store
+            get 1 1 add set 1
+            get 1 6 lt branch fill
+        print:
+            get 1 1 sub set 1
+            get 1 2 mul get 0 add load print
+            get 1 0 gt branch print
+            leave 0
+            jmp end
+        fact:
+            enter 1 0           // ( n -- n! )
+            get 0 1 lte branch one
+            get 0 1 sub call fact get 0 mul
+            leave 1 return
+        one:
+            1 leave 1 return
+        end:
+        ";
+        assert_eq!(interpret(2, program).unwrap(), "120\n24\n6\n2\n1\n1\n");
+    }
+
+    #[test]
+    fn interprets_single_lines() {
+        fn line(sm: &mut StackMachine, input: &str) -> Result<(), LangError> {
+            LangInterpreter::from_line(input, sm).interpret()
+        }
+        let mut sm = StackMachine::new();
+        line(&mut sm, "7").unwrap();
+        line(&mut sm, "0x10").unwrap();
+        line(&mut sm, "add").unwrap();
+        assert_eq!(&sm.stack, &[23]);
+        assert_eq!(
+            line(&mut sm, "frob").unwrap_err().to_string(),
+            "line 1: unknown word `frob`"
+        );
+        assert!(matches!(
+            line(&mut sm, "add").unwrap_err().kind,
+            LangErrorKind::Exec(ExecErrorKind::Stack(StackError::Underflow { .. }))
+        ));
+        assert_eq!(&sm.stack, &[23]);
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        let error = |bytes, program| interpret(bytes, program).unwrap_err().to_string();
+        assert_eq!(error(1, "1 2\nfrob"), "line 2: unknown word `frob`");
+        assert_eq!(error(1, "\n255 256"), "line 2: `256` is too big");
+        assert_eq!(error(1, "0xg"), "line 1: unknown word `0xg`");
+        assert_eq!(
+            error(1, "a:\nb: a:"),
+            "line 2: label `a` is already defined"
+        );
+        assert_eq!(
+            error(1, "jmp a\njmp nowhere\na:"),
+            "line 2: undefined label `nowhere`"
+        );
+        assert_eq!(error(1, "1 call"), "line 1: `call` needs a label");
+        assert_eq!(error(1, "enter 1\n"), "line 1: `enter` needs a count");
+        assert_eq!(error(1, "get x"), "line 1: `get` needs a count");
+        assert_eq!(
+            error(1, "1 load"),
+            "line 1: address 0x1 is outside of the heap"
+        );
+        assert_eq!(
+            error(1, "enter 0 0\nleave 0 leave 0"),
+            "line 2: leaving a frame that was never entered"
+        );
+        assert_eq!(error(1, "\"ok\"\n\"oops"), "line 2: unterminated string");
+        assert_eq!(
+            error(1, "// Runtime errors are reported too.\n1\n0 div"),
+            "line 3: division by zero"
+        );
+        let long = format!("\"{}\"", "a".repeat(256));
+        assert_eq!(
+            error(1, &long),
+            "line 1: strings can be 255 bytes at most, not 256"
+        );
     }
 }
@@ -0,0 +1,957 @@
+//! A two-pass assembler for LC-3 assembly.
+//!
+//! The first pass walks the source assigning an address to every statement so
+//! that labels can be resolved; the second pass encodes each statement now that
+//! every label (including those defined further down the file) is known.
+//!
+//! ```text
+//!         .ORIG x3000
+//!         LEA R0, HELLO   ; labels, comments and trap aliases all work
+//!         PUTS
+//!         HALT
+//! HELLO   .STRINGZ "Hello, World!"
+//!         .END
+//! ```
+
+use super::{
+    instructions::{Instruction, Register},
+    object::ObjectFile,
+};
+use std::{collections::HashMap, fmt};
+
+/// The output of a successful assembly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    /// One object per `.ORIG` block, in source order.
+    pub objects: Vec<ObjectFile>,
+    /// Every label and the address it refers to.
+    pub symbols: HashMap<String, u16>,
+}
+
+impl Assembly {
+    /// Every assembled word decoded back into an `Instruction`, in source order.
+    /// Data decodes too (as whatever instruction it happens to look like), just
+    /// as it would if the machine were to run into it.
+    pub fn instructions(&self) -> Vec<Instruction> {
+        self.objects
+            .iter()
+            .flat_map(|obj| obj.words.iter().map(|&word| Instruction::from(word)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column of the offending token.
+    pub column: usize,
+    pub kind: AsmErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownOpcode(String),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    InvalidLabel(String),
+    ExpectedRegister(String),
+    ExpectedNumber(String),
+    ExpectedString,
+    UnterminatedString,
+    WrongOperandCount {
+        expected: usize,
+        found: usize,
+    },
+    OutOfRange {
+        value: i32,
+        min: i32,
+        max: i32,
+    },
+    /// Code outside of an `.ORIG`/`.END` block.
+    MissingOrig,
+    /// A block that grows past the end of memory.
+    AddressOverflow,
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use AsmErrorKind::*;
+        match self {
+            UnknownOpcode(op) => write!(f, "unknown opcode or directive `{}`", op),
+            UndefinedLabel(label) => write!(f, "undefined label `{}`", label),
+            DuplicateLabel(label) => write!(f, "label `{}` is already defined", label),
+            InvalidLabel(label) => write!(f, "`{}` is not a valid label", label),
+            ExpectedRegister(found) => write!(f, "expected a register, found `{}`", found),
+            ExpectedNumber(found) => write!(f, "expected a number, found `{}`", found),
+            ExpectedString => write!(f, "expected a string literal"),
+            UnterminatedString => write!(f, "unterminated string literal"),
+            WrongOperandCount { expected, found } => {
+                write!(f, "expected {} operand(s), found {}", expected, found)
+            }
+            OutOfRange { value, min, max } => {
+                write!(f, "{} is out of range ({} to {})", value, min, max)
+            }
+            MissingOrig => write!(f, "statement is outside of an .ORIG block"),
+            AddressOverflow => write!(f, "program runs past the end of memory"),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+/// Assembles a whole source file, reporting every error found rather than just
+/// the first.
+pub fn assemble(source: &str) -> Result<Assembly, Vec<AsmError>> {
+    let mut errors = Vec::new();
+    let blocks = first_pass(source, &mut errors);
+    let symbols = collect_symbols(&blocks, &mut errors);
+
+    let objects = blocks
+        .iter()
+        .map(|block| {
+            let mut words = Vec::new();
+            for stmt in &block.statements {
+                if let Err(e) = encode(stmt, &symbols, &mut words) {
+                    errors.push(e);
+                }
+            }
+            ObjectFile::new(block.origin, words)
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(Assembly { objects, symbols })
+    } else {
+        errors.sort_by_key(|e| (e.line, e.column));
+        Err(errors)
+    }
+}
+
+/* Lexing */
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Word(String),
+    Str(String),
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, kind: AsmErrorKind) -> AsmError {
+        AsmError {
+            line: self.line,
+            column: self.column,
+            kind,
+        }
+    }
+
+    fn text(&self) -> &str {
+        match &self.kind {
+            TokenKind::Word(word) => word,
+            TokenKind::Str(s) => s,
+        }
+    }
+}
+
+/// Splits a line into tokens. Commas are treated as whitespace, and a `;` ends
+/// the line (unless it is inside a string).
+fn tokenize(line_no: usize, line: &str) -> Result<Vec<Token>, AsmError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some(&(i, ch)) = chars.peek() {
+        let column = line[..i].chars().count() + 1;
+        match ch {
+            ';' => break,
+            ',' => {
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                let mut terminated = false;
+                while let Some((_, ch)) = chars.next() {
+                    match ch {
+                        '"' => {
+                            terminated = true;
+                            break;
+                        }
+                        '\\' => match chars.next().map(|(_, ch)| ch) {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some('r') => s.push('\r'),
+                            Some('0') => s.push('\0'),
+                            Some('e') => s.push('\x1b'),
+                            Some(other) => s.push(other),
+                            None => break,
+                        },
+                        ch => s.push(ch),
+                    }
+                }
+                if !terminated {
+                    return Err(AsmError {
+                        line: line_no,
+                        column,
+                        kind: AsmErrorKind::UnterminatedString,
+                    });
+                }
+                tokens.push(Token {
+                    kind: TokenKind::Str(s),
+                    line: line_no,
+                    column,
+                });
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&(_, ch)) = chars.peek() {
+                    if ch.is_whitespace() || ch == ',' || ch == ';' || ch == '"' {
+                        break;
+                    }
+                    word.push(ch);
+                    chars.next();
+                }
+                tokens.push(Token {
+                    kind: TokenKind::Word(word),
+                    line: line_no,
+                    column,
+                });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/* Parsing */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Directive {
+    Orig,
+    Fill,
+    Blkw,
+    Stringz,
+    End,
+}
+
+/// Everything that may appear in the opcode position of a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    And,
+    Not,
+    Br(bool, bool, bool),
+    Jmp,
+    Ret,
+    Jsr,
+    Jsrr,
+    Ld,
+    Ldi,
+    Ldr,
+    Lea,
+    St,
+    Sti,
+    Str,
+    Trap,
+    Rti,
+    /// GETC, OUT, PUTS, IN, PUTSP and HALT are just TRAPs with a fixed vector.
+    TrapAlias(u8),
+    Directive(Directive),
+}
+
+impl Op {
+    fn parse(word: &str) -> Option<Self> {
+        use Op::*;
+        let upper = word.to_ascii_uppercase();
+        let op = match upper.as_str() {
+            "ADD" => Add,
+            "AND" => And,
+            "NOT" => Not,
+            "JMP" => Jmp,
+            "RET" => Ret,
+            "JSR" => Jsr,
+            "JSRR" => Jsrr,
+            "LD" => Ld,
+            "LDI" => Ldi,
+            "LDR" => Ldr,
+            "LEA" => Lea,
+            "ST" => St,
+            "STI" => Sti,
+            "STR" => Str,
+            "TRAP" => Trap,
+            "RTI" => Rti,
+            "GETC" => TrapAlias(0x20),
+            "OUT" => TrapAlias(0x21),
+            "PUTS" => TrapAlias(0x22),
+            "IN" => TrapAlias(0x23),
+            "PUTSP" => TrapAlias(0x24),
+            "HALT" => TrapAlias(0x25),
+            ".ORIG" => Directive(self::Directive::Orig),
+            ".FILL" => Directive(self::Directive::Fill),
+            ".BLKW" => Directive(self::Directive::Blkw),
+            ".STRINGZ" => Directive(self::Directive::Stringz),
+            ".END" => Directive(self::Directive::End),
+            br => return Self::parse_br(br),
+        };
+        Some(op)
+    }
+
+    /// BR must list its flags in n, z, p order; a bare BR means BRnzp.
+    fn parse_br(upper: &str) -> Option<Self> {
+        let mut flags = upper.strip_prefix("BR")?;
+        let mut take = |flag: &str| match flags.strip_prefix(flag) {
+            Some(rest) => {
+                flags = rest;
+                true
+            }
+            None => false,
+        };
+        let (n, z, p) = (take("N"), take("Z"), take("P"));
+        if !flags.is_empty() {
+            return None;
+        }
+        if n || z || p {
+            Some(Op::Br(n, z, p))
+        } else {
+            Some(Op::Br(true, true, true))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Statement {
+    op: Op,
+    /// The token naming the op, for error reporting.
+    op_token: Token,
+    operands: Vec<Token>,
+    address: u16,
+}
+
+#[derive(Debug, Clone)]
+struct Label {
+    name: String,
+    token: Token,
+    address: u16,
+}
+
+/// The contents of one `.ORIG` ... `.END` block.
+#[derive(Debug, Default)]
+struct Block {
+    origin: u16,
+    statements: Vec<Statement>,
+    labels: Vec<Label>,
+}
+
+fn is_valid_label(word: &str) -> bool {
+    let mut chars = word.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Assigns an address to every statement and label.
+fn first_pass(source: &str, errors: &mut Vec<AsmError>) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut current: Option<Block> = None;
+    // Wider than u16 so that running off the end of memory can be detected.
+    let mut address: u32 = 0;
+
+    for (i, line) in source.lines().enumerate() {
+        let mut tokens = match tokenize(i + 1, line) {
+            Ok(tokens) => tokens.into_iter(),
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+
+        let first = match tokens.next() {
+            Some(token) => token,
+            None => continue,
+        };
+
+        // Anything in the first position that isn't an op must be a label,
+        // unless what follows isn't an op either, in which case the first
+        // word was far more likely a typo'd opcode.
+        let (label, op_token) = match Op::parse(first.text()) {
+            Some(_) => (None, Some(first)),
+            None => match tokens.next() {
+                Some(second) if Op::parse(second.text()).is_none() => {
+                    let kind = AsmErrorKind::UnknownOpcode(first.text().to_string());
+                    errors.push(first.error(kind));
+                    continue;
+                }
+                second => (Some(first), second),
+            },
+        };
+
+        if let Some(label) = label {
+            let name = label.text().trim_end_matches(':').to_string();
+            if !matches!(label.kind, TokenKind::Word(_)) || !is_valid_label(&name) {
+                errors.push(label.error(AsmErrorKind::InvalidLabel(name)));
+                continue;
+            }
+            match &mut current {
+                Some(block) => block.labels.push(Label {
+                    name,
+                    token: label,
+                    address: address as u16,
+                }),
+                None => errors.push(label.error(AsmErrorKind::MissingOrig)),
+            }
+        }
+
+        let op_token = match op_token {
+            Some(token) => token,
+            None => continue,
+        };
+        // Checked above.
+        let op = Op::parse(op_token.text()).unwrap();
+        let operands: Vec<Token> = tokens.collect();
+
+        match op {
+            Op::Directive(Directive::Orig) => {
+                if let Some(block) = current.take() {
+                    blocks.push(block);
+                }
+                let origin = operand_count(&op_token, &operands, 1)
+                    .and_then(|_| number(&operands[0], 0, u16::MAX as i32));
+                match origin {
+                    Ok(origin) => {
+                        address = origin as u32;
+                        current = Some(Block {
+                            origin: origin as u16,
+                            ..Block::default()
+                        });
+                    }
+                    Err(e) => errors.push(e),
+                }
+            }
+            Op::Directive(Directive::End) => {
+                if let Some(block) = current.take() {
+                    blocks.push(block);
+                } else {
+                    errors.push(op_token.error(AsmErrorKind::MissingOrig));
+                }
+            }
+            _ => {
+                let block = match &mut current {
+                    Some(block) => block,
+                    None => {
+                        errors.push(op_token.error(AsmErrorKind::MissingOrig));
+                        continue;
+                    }
+                };
+                let size = match statement_size(op, &op_token, &operands) {
+                    Ok(size) => size,
+                    Err(e) => {
+                        errors.push(e);
+                        continue;
+                    }
+                };
+                if address + size > 1 << 16 {
+                    errors.push(op_token.error(AsmErrorKind::AddressOverflow));
+                    continue;
+                }
+                block.statements.push(Statement {
+                    op,
+                    op_token,
+                    operands,
+                    address: address as u16,
+                });
+                address += size;
+            }
+        }
+    }
+
+    // A missing .END is forgiven.
+    if let Some(block) = current.take() {
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// How many words a statement occupies in memory.
+fn statement_size(op: Op, op_token: &Token, operands: &[Token]) -> Result<u32, AsmError> {
+    Ok(match op {
+        Op::Directive(Directive::Blkw) => {
+            operand_count(op_token, operands, 1)?;
+            number(&operands[0], 0, u16::MAX as i32)? as u32
+        }
+        Op::Directive(Directive::Stringz) => {
+            operand_count(op_token, operands, 1)?;
+            match &operands[0].kind {
+                TokenKind::Str(s) => s.chars().count() as u32 + 1,
+                TokenKind::Word(_) => return Err(operands[0].error(AsmErrorKind::ExpectedString)),
+            }
+        }
+        _ => 1,
+    })
+}
+
+fn collect_symbols(blocks: &[Block], errors: &mut Vec<AsmError>) -> HashMap<String, u16> {
+    let mut symbols = HashMap::new();
+    for label in blocks.iter().flat_map(|block| &block.labels) {
+        if symbols.insert(label.name.clone(), label.address).is_some() {
+            let kind = AsmErrorKind::DuplicateLabel(label.name.clone());
+            errors.push(label.token.error(kind));
+        }
+    }
+    symbols
+}
+
+/* Operands */
+
+fn operand_count(op_token: &Token, operands: &[Token], expected: usize) -> Result<(), AsmError> {
+    if operands.len() == expected {
+        Ok(())
+    } else {
+        Err(op_token.error(AsmErrorKind::WrongOperandCount {
+            expected,
+            found: operands.len(),
+        }))
+    }
+}
+
+fn register(token: &Token) -> Result<Register, AsmError> {
+    let text = token.text();
+    let err = || token.error(AsmErrorKind::ExpectedRegister(text.to_string()));
+    if !matches!(token.kind, TokenKind::Word(_)) || text.len() != 2 {
+        return Err(err());
+    }
+    match (text.as_bytes()[0], text.as_bytes()[1]) {
+        (b'R' | b'r', r @ b'0'..=b'7') => Ok((r - b'0') as Register),
+        _ => Err(err()),
+    }
+}
+
+/// Parses a numeric literal: `#10`, `#-10`, `10`, `x3000`, `0x3000` or `b101`.
+pub(crate) fn parse_number(text: &str) -> Option<i32> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let (radix, digits) = if let Some(rest) = text.strip_prefix('#') {
+        (10, rest)
+    } else if let Some(rest) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (16, rest)
+    } else if let Some(rest) = text.strip_prefix('x').or_else(|| text.strip_prefix('X')) {
+        (16, rest)
+    } else if let Some(rest) = text.strip_prefix('b').or_else(|| text.strip_prefix('B')) {
+        (2, rest)
+    } else {
+        (10, text)
+    };
+    // Allow the sign to come after the prefix too, as in `#-1` or `x-1`.
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(rest) if !negative => (true, rest),
+        _ => (negative, digits),
+    };
+    if digits.is_empty() || digits.starts_with('+') {
+        return None;
+    }
+    let value = i32::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+fn number(token: &Token, min: i32, max: i32) -> Result<i32, AsmError> {
+    let value = match &token.kind {
+        TokenKind::Word(word) => parse_number(word),
+        TokenKind::Str(_) => None,
+    }
+    .ok_or_else(|| token.error(AsmErrorKind::ExpectedNumber(token.text().to_string())))?;
+    in_range(token, value, min, max)
+}
+
+fn in_range(token: &Token, value: i32, min: i32, max: i32) -> Result<i32, AsmError> {
+    if (min..=max).contains(&value) {
+        Ok(value)
+    } else {
+        Err(token.error(AsmErrorKind::OutOfRange { value, min, max }))
+    }
+}
+
+/// A signed immediate occupying `bits` bits.
+fn immediate(token: &Token, bits: u32) -> Result<u16, AsmError> {
+    let max = (1 << (bits - 1)) - 1;
+    number(token, -max - 1, max).map(|value| value as u16)
+}
+
+/// A PC-relative offset, given either as a label or as a literal offset.
+fn pc_offset(
+    token: &Token,
+    address: u16,
+    bits: u32,
+    symbols: &HashMap<String, u16>,
+) -> Result<u16, AsmError> {
+    let text = token.text();
+    if parse_number(text).is_some() {
+        return immediate(token, bits);
+    }
+    let target = *symbols
+        .get(text)
+        .ok_or_else(|| token.error(AsmErrorKind::UndefinedLabel(text.to_string())))?;
+    // Offsets are relative to the incremented PC.
+    let offset = target as i32 - (address as i32 + 1);
+    let max = (1 << (bits - 1)) - 1;
+    in_range(token, offset, -max - 1, max).map(|offset| offset as u16)
+}
+
+/* Encoding */
+
+fn encode(
+    stmt: &Statement,
+    symbols: &HashMap<String, u16>,
+    words: &mut Vec<u16>,
+) -> Result<(), AsmError> {
+    use Instruction::*;
+
+    let ops = &stmt.operands;
+    let count = |expected| operand_count(&stmt.op_token, ops, expected);
+    let offset = |token, bits| pc_offset(token, stmt.address, bits, symbols);
+
+    let instr = match stmt.op {
+        Op::Add | Op::And => {
+            count(3)?;
+            let (dr, sr1) = (register(&ops[0])?, register(&ops[1])?);
+            let is_add = stmt.op == Op::Add;
+            match register(&ops[2]) {
+                Ok(sr2) if is_add => AddReg(dr, sr1, sr2),
+                Ok(sr2) => AndReg(dr, sr1, sr2),
+                Err(_) if is_add => AddImm(dr, sr1, immediate(&ops[2], 5)?),
+                Err(_) => AndImm(dr, sr1, immediate(&ops[2], 5)?),
+            }
+        }
+        Op::Not => {
+            count(2)?;
+            Not(register(&ops[0])?, register(&ops[1])?)
+        }
+        Op::Br(n, z, p) => {
+            count(1)?;
+            Br(n, z, p, offset(&ops[0], 9)?)
+        }
+        Op::Jmp => {
+            count(1)?;
+            Jmp(register(&ops[0])?)
+        }
+        Op::Ret => {
+            count(0)?;
+            Jmp(7)
+        }
+        Op::Jsr => {
+            count(1)?;
+            Jsr(offset(&ops[0], 11)?)
+        }
+        Op::Jsrr => {
+            count(1)?;
+            Jsrr(register(&ops[0])?)
+        }
+        Op::Ld | Op::Ldi | Op::Lea | Op::St | Op::Sti => {
+            count(2)?;
+            let (r, offset9) = (register(&ops[0])?, offset(&ops[1], 9)?);
+            match stmt.op {
+                Op::Ld => Ld(r, offset9),
+                Op::Ldi => Ldi(r, offset9),
+                Op::Lea => Lea(r, offset9),
+                Op::St => St(r, offset9),
+                _ => Sti(r, offset9),
+            }
+        }
+        Op::Ldr | Op::Str => {
+            count(3)?;
+            let (r, base) = (register(&ops[0])?, register(&ops[1])?);
+            let offset6 = immediate(&ops[2], 6)?;
+            if stmt.op == Op::Ldr {
+                Ldr(r, base, offset6)
+            } else {
+                Str(r, base, offset6)
+            }
+        }
+        Op::Trap => {
+            count(1)?;
+            Trap(number(&ops[0], 0, u8::MAX as i32)? as u8)
+        }
+        Op::TrapAlias(vector) => {
+            count(0)?;
+            Trap(vector)
+        }
+        Op::Rti => {
+            count(0)?;
+            Rti()
+        }
+        Op::Directive(Directive::Fill) => {
+            count(1)?;
+            let token = &ops[0];
+            let value = match symbols.get(token.text()) {
+                Some(&address) => address,
+                None if parse_number(token.text()).is_none() && is_valid_label(token.text()) => {
+                    let kind = AsmErrorKind::UndefinedLabel(token.text().to_string());
+                    return Err(token.error(kind));
+                }
+                None => number(token, i16::MIN as i32, u16::MAX as i32)? as u16,
+            };
+            words.push(value);
+            return Ok(());
+        }
+        Op::Directive(Directive::Blkw) => {
+            // Validated during the first pass.
+            let len = parse_number(ops[0].text()).unwrap_or(0) as usize;
+            words.resize(words.len() + len, 0);
+            return Ok(());
+        }
+        Op::Directive(Directive::Stringz) => {
+            words.extend(ops[0].text().chars().map(|ch| ch as u16));
+            words.push(0);
+            return Ok(());
+        }
+        Op::Directive(Directive::Orig | Directive::End) => {
+            unreachable!("handled during the first pass")
+        }
+    };
+
+    words.push(instr.into());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::LC3;
+    use Instruction::*;
+
+    fn errors(source: &str) -> Vec<AsmError> {
+        assemble(source).expect_err("source should not assemble")
+    }
+
+    #[test]
+    fn assembles_every_opcode() {
+        let source = r#"
+            .ORIG x3000
+    START   ADD R1, R2, R3
+            ADD R1, R2, #-16
+            AND R1 R2 R3      ; commas are optional
+            AND R1, R2, #15
+            NOT R4, R5
+            BR START
+            BRnp START
+            BRz START
+            JMP R2
+            RET
+            JSR START
+            JSRR R3
+            LD R0, DATA
+            LDI R0, DATA
+            LDR R0, R6, #-1
+            LEA R0, DATA
+            ST R0, DATA
+            STI R0, DATA
+            STR R0, R6, #31
+            TRAP x25
+            RTI
+    DATA    .FILL xBEEF
+            .END
+        "#;
+        let asm = assemble(source).unwrap();
+        assert_eq!(
+            asm.instructions(),
+            vec![
+                AddReg(1, 2, 3),
+                AddImm(1, 2, 0b10000),
+                AndReg(1, 2, 3),
+                AndImm(1, 2, 15),
+                Not(4, 5),
+                Br(true, true, true, 0x1FA),
+                Br(true, false, true, 0x1F9),
+                Br(false, true, false, 0x1F8),
+                Jmp(2),
+                Jmp(7),
+                Jsr(0x7F5),
+                Jsrr(3),
+                Ld(0, 8),
+                Ldi(0, 7),
+                Ldr(0, 6, 0b11_1111),
+                Lea(0, 5),
+                St(0, 4),
+                Sti(0, 3),
+                Str(0, 6, 31),
+                Trap(0x25),
+                Rti(),
+                Instruction::from(0xBEEF),
+            ]
+        );
+        assert_eq!(asm.symbols["START"], 0x3000);
+        assert_eq!(asm.symbols["DATA"], 0x3015);
+    }
+
+    #[test]
+    fn assembles_trap_aliases() {
+        let asm = assemble(".ORIG x3000\nGETC\nOUT\nPUTS\nIN\nPUTSP\nHALT\n.END").unwrap();
+        assert_eq!(
+            asm.instructions(),
+            (0x20..=0x25).map(Trap).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn assembles_directives() {
+        let source = r#"
+            .ORIG x4000
+    PTR     .FILL MSG
+            .FILL #-1
+    BUF     .BLKW 3
+    MSG     .STRINGZ "hi\n"
+            .END
+        "#;
+        let asm = assemble(source).unwrap();
+        assert_eq!(
+            asm.objects,
+            vec![ObjectFile::new(
+                0x4000,
+                vec![
+                    0x4005,
+                    0xFFFF,
+                    0,
+                    0,
+                    0,
+                    'h' as u16,
+                    'i' as u16,
+                    '\n' as u16,
+                    0
+                ]
+            )]
+        );
+        assert_eq!(asm.symbols["BUF"], 0x4002);
+    }
+
+    #[test]
+    fn supports_many_blocks() {
+        let source = ".ORIG x3000\nLD R0, VAL\n.END\n.ORIG x3100\nVAL .FILL 7\n.END";
+        let asm = assemble(source).unwrap();
+        assert_eq!(asm.objects.len(), 2);
+        assert_eq!(asm.objects[1].origin, 0x3100);
+        assert_eq!(asm.instructions()[0], Ld(0, 0xFF));
+    }
+
+    #[test]
+    fn assembled_program_runs() {
+        let source = r#"
+            .ORIG x3000
+            AND R0, R0, #0
+            LD R1, COUNT
+    LOOP    ADD R0, R0, R1
+            ADD R1, R1, #-1
+            BRp LOOP
+            ST R0, RESULT
+            HALT
+    COUNT   .FILL #4
+    RESULT  .BLKW 1
+            .END
+        "#;
+        let asm = assemble(source).unwrap();
+        let mut lc3 = LC3::new();
+        lc3.load_objects(&asm.objects).unwrap();
+        for _ in 0..15 {
+            lc3.run_once();
+        }
+        assert_eq!(lc3.memory[asm.symbols["RESULT"] as usize], 4 + 3 + 2 + 1);
+    }
+
+    #[test]
+    fn reports_undefined_labels_with_position() {
+        let errs = errors(".ORIG x3000\n  LD R0, NOPE\n.END");
+        assert_eq!(
+            errs,
+            vec![AsmError {
+                line: 2,
+                column: 10,
+                kind: AsmErrorKind::UndefinedLabel("NOPE".to_string()),
+            }]
+        );
+        assert_eq!(errs[0].to_string(), "2:10: undefined label `NOPE`");
+    }
+
+    #[test]
+    fn reports_out_of_range_offsets() {
+        let source = ".ORIG x3000\nBR FAR\n.BLKW 256\nFAR HALT\n.END";
+        let errs = errors(source);
+        assert_eq!(errs.len(), 1);
+        assert_eq!((errs[0].line, errs[0].column), (2, 4));
+        assert_eq!(
+            errs[0].kind,
+            AsmErrorKind::OutOfRange {
+                value: 256,
+                min: -256,
+                max: 255
+            }
+        );
+
+        let errs = errors(".ORIG x3000\nADD R0, R0, #16\n.END");
+        assert!(matches!(
+            errs[0].kind,
+            AsmErrorKind::OutOfRange { value: 16, .. }
+        ));
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let source = r#".ORIG x3000
+            ADD R0, R8, #1
+            FOO R1
+            LD R0
+    A       HALT
+    A       HALT
+            .STRINGZ "oops
+            .END"#;
+        let kinds: Vec<_> = errors(source)
+            .into_iter()
+            .map(|e| (e.line, e.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (2, AsmErrorKind::ExpectedRegister("R8".to_string())),
+                (3, AsmErrorKind::UnknownOpcode("FOO".to_string())),
+                (
+                    4,
+                    AsmErrorKind::WrongOperandCount {
+                        expected: 2,
+                        found: 1
+                    }
+                ),
+                (6, AsmErrorKind::DuplicateLabel("A".to_string())),
+                (7, AsmErrorKind::UnterminatedString),
+            ]
+        );
+    }
+
+    #[test]
+    fn requires_orig() {
+        let errs = errors("ADD R0, R0, R0");
+        assert_eq!(errs[0].kind, AsmErrorKind::MissingOrig);
+    }
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(parse_number("#10"), Some(10));
+        assert_eq!(parse_number("#-10"), Some(-10));
+        assert_eq!(parse_number("10"), Some(10));
+        assert_eq!(parse_number("x3000"), Some(0x3000));
+        assert_eq!(parse_number("0XFFFF"), Some(0xFFFF));
+        assert_eq!(parse_number("x-1"), Some(-1));
+        assert_eq!(parse_number("b101"), Some(5));
+        assert_eq!(parse_number("#"), None);
+        assert_eq!(parse_number("LOOP"), None);
+    }
+}
@@ -0,0 +1,129 @@
+//! The LC-3's keyboard and display. Programs talk to them through the
+//! memory-mapped device registers (or through the TRAP routines, which do the
+//! same on their behalf), and we talk to the outside world through a `Console`
+//! so that tests can swap the terminal out for in-memory buffers.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Read, Write},
+    rc::Rc,
+    thread,
+    time::Duration,
+};
+use termion::raw::{IntoRawMode, RawTerminal};
+
+pub trait Console {
+    /// Returns the next key if one has been pressed, without blocking.
+    fn poll_key(&mut self) -> Option<u8>;
+    /// Blocks until a key is pressed. Returns None once input is exhausted.
+    fn read_key(&mut self) -> Option<u8>;
+    fn write(&mut self, byte: u8);
+    fn flush(&mut self) {}
+}
+
+/// A console backed by in-memory buffers.
+#[derive(Debug, Default)]
+pub struct BufferConsole {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl BufferConsole {
+    pub fn new(input: impl AsRef<[u8]>) -> Self {
+        Self {
+            input: input.as_ref().iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+impl Console for BufferConsole {
+    fn poll_key(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn read_key(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+}
+
+/// Lets a console be shared, so that whoever handed it to the machine can
+/// still get at it afterwards.
+impl<C: Console + ?Sized> Console for Rc<RefCell<C>> {
+    fn poll_key(&mut self) -> Option<u8> {
+        self.borrow_mut().poll_key()
+    }
+
+    fn read_key(&mut self) -> Option<u8> {
+        self.borrow_mut().read_key()
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.borrow_mut().write(byte)
+    }
+
+    fn flush(&mut self) {
+        self.borrow_mut().flush()
+    }
+}
+
+/// The real terminal, put into raw mode so that keys reach the program as
+/// soon as they are pressed rather than a line at a time.
+pub struct TermConsole {
+    stdin: termion::AsyncReader,
+    stdout: RawTerminal<io::Stdout>,
+}
+
+impl TermConsole {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            stdin: termion::async_stdin(),
+            stdout: io::stdout().into_raw_mode()?,
+        })
+    }
+}
+
+impl Console for TermConsole {
+    fn poll_key(&mut self) -> Option<u8> {
+        let mut buf = [0];
+        match self.stdin.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+
+    fn read_key(&mut self) -> Option<u8> {
+        self.flush();
+        loop {
+            match self.poll_key() {
+                // Raw mode means ctrl-c no longer kills us, so honour it here.
+                Some(3) => return None,
+                // Enter sends a carriage return in raw mode.
+                Some(b'\r') => return Some(b'\n'),
+                Some(key) => return Some(key),
+                None => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+    }
+
+    fn write(&mut self, byte: u8) {
+        // Raw mode doesn't translate newlines for us.
+        if byte == b'\n' {
+            let _ = self.stdout.write_all(b"\r");
+        }
+        let _ = self.stdout.write_all(&[byte]);
+    }
+
+    fn flush(&mut self) {
+        let _ = self.stdout.flush();
+    }
+}
@@ -0,0 +1,582 @@
+//! An interactive debugger for the LC-3, which is what `computour lc3` drops
+//! you into.
+//!
+//! Everything, including the debugger's own prompt, goes through the machine's
+//! `Console`; on a real terminal that console is in raw mode for the sake of
+//! the running program, so we do our own (very) basic line editing.
+
+use super::{
+    assembler::parse_number, console::Console, disassembler::format_word, load_program,
+    memory::MemoryHook, LC3,
+};
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+    mem,
+    rc::Rc,
+};
+
+const HELP: &str = "\
+commands:
+  load <file>          load an .asm or .obj program, resetting the machine
+  s, step [n]          execute n instructions (default 1)
+  c, continue          run until a breakpoint, watchpoint or halt (or for
+                       at most 10 million instructions)
+  b, break <loc>       set a breakpoint at an address or label
+  d, delete <loc>      remove a breakpoint
+  w, watch <loc>       stop whenever the memory cell at loc changes
+  unwatch <loc>        remove a watchpoint
+  info                 list breakpoints and watchpoints
+  r, regs              dump the registers and condition codes
+  l, list [n]          disassemble n instructions either side of the PC
+  x, mem <loc> [n]     dump n memory cells starting at loc
+  trace start          start recording every step the machine takes
+  trace stop [file]    stop recording, printing the trace or saving it
+  q, quit              exit";
+
+/// Why execution stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Halted,
+    Breakpoint(u16),
+    Watchpoint {
+        address: u16,
+        old: u16,
+        new: u16,
+    },
+    /// A `step` finished without anything else happening.
+    Stepped,
+    /// A `continue` ran `step_limit` instructions without stopping.
+    StepLimit,
+}
+
+/// How many instructions a `continue` runs before giving up, since with the
+/// terminal in raw mode there's no ctrl-c to get out of an infinite loop.
+const STEP_LIMIT: usize = 10_000_000;
+
+/// Watches for writes that change a watched address, from a memory hook.
+#[derive(Debug, Default)]
+struct Watcher {
+    watched: BTreeSet<u16>,
+    /// The first watchpoint to fire since the last time we looked.
+    hit: Option<Stop>,
+}
+
+struct WatchHook(Rc<RefCell<Watcher>>);
+
+impl MemoryHook for WatchHook {
+    fn on_write(&mut self, address: u16, old: u16, new: u16) -> bool {
+        let mut watcher = self.0.borrow_mut();
+        if old != new && watcher.hit.is_none() && watcher.watched.contains(&address) {
+            watcher.hit = Some(Stop::Watchpoint { address, old, new });
+        }
+        true
+    }
+}
+
+pub struct Debugger {
+    pub lc3: LC3,
+    breakpoints: BTreeSet<u16>,
+    watcher: Rc<RefCell<Watcher>>,
+    /// Labels of the loaded program, if it was assembled from source.
+    symbols: HashMap<String, u16>,
+    pub step_limit: usize,
+}
+
+impl Debugger {
+    pub fn new(mut lc3: LC3) -> Self {
+        let watcher = Rc::new(RefCell::new(Watcher::default()));
+        lc3.memory.add_hook(WatchHook(watcher.clone()));
+        Self {
+            lc3,
+            breakpoints: BTreeSet::new(),
+            watcher,
+            symbols: HashMap::new(),
+            step_limit: STEP_LIMIT,
+        }
+    }
+
+    /// Reads and runs commands until told to quit (or input runs out).
+    pub fn repl(&mut self) {
+        self.print("LC-3 debugger. Type `help` for a list of commands.\n");
+        loop {
+            self.print("(lc3) ");
+            let line = match self.read_line() {
+                Some(line) => line,
+                None => break,
+            };
+            match self.command(&line) {
+                Some(output) => self.print(&output),
+                None => break,
+            }
+        }
+    }
+
+    /// Runs a single command, returning what it printed, or None to quit.
+    pub fn command(&mut self, line: &str) -> Option<String> {
+        let mut args = line.split_whitespace();
+        let mut out = String::new();
+
+        let res = match args.next().unwrap_or("") {
+            "" => Ok(()),
+            "help" | "h" => {
+                out.push_str(HELP);
+                Ok(())
+            }
+            "q" | "quit" | "exit" => return None,
+            "load" => match args.next() {
+                Some(path) => self.load(path).map(|_| {
+                    let _ = write!(out, "loaded {}, pc = {:#06x}", path, self.lc3.ip);
+                }),
+                None => Err("usage: load <file>".to_string()),
+            },
+            "s" | "step" => self.parse_count(args.next(), 1).map(|n| {
+                let stop = self.step(n);
+                self.describe_stop(stop, &mut out);
+            }),
+            "c" | "continue" => {
+                let stop = self.continue_();
+                self.describe_stop(stop, &mut out);
+                Ok(())
+            }
+            "b" | "break" => self.location(args.next()).map(|address| {
+                self.breakpoints.insert(address);
+                let _ = write!(out, "breakpoint at {}", self.describe_address(address));
+            }),
+            "d" | "delete" => self.location(args.next()).and_then(|address| {
+                if self.breakpoints.remove(&address) {
+                    Ok(())
+                } else {
+                    Err(format!("no breakpoint at {:#06x}", address))
+                }
+            }),
+            "w" | "watch" => self.location(args.next()).map(|address| {
+                self.watcher.borrow_mut().watched.insert(address);
+                let _ = write!(out, "watching {}", self.describe_address(address));
+            }),
+            "unwatch" => self.location(args.next()).and_then(|address| {
+                if self.watcher.borrow_mut().watched.remove(&address) {
+                    Ok(())
+                } else {
+                    Err(format!("no watchpoint at {:#06x}", address))
+                }
+            }),
+            "info" => {
+                for &address in &self.breakpoints {
+                    let _ = writeln!(out, "breakpoint {}", self.describe_address(address));
+                }
+                for &address in &self.watcher.borrow().watched {
+                    let val = self.lc3.memory[address as usize];
+                    let desc = self.describe_address(address);
+                    let _ = writeln!(out, "watchpoint {} = {:#06x}", desc, val);
+                }
+                Ok(())
+            }
+            "r" | "regs" => {
+                self.dump_registers(&mut out);
+                Ok(())
+            }
+            "l" | "list" => self.parse_count(args.next(), 5).map(|n| {
+                // Either side of the PC only goes halfway round memory.
+                let n = n.min(self.lc3.memory.len() / 2 - 1);
+                let start = self.lc3.ip.wrapping_sub(n as u16);
+                self.disassemble(start, 2 * n + 1, &mut out);
+            }),
+            "x" | "mem" => self.location(args.next()).and_then(|address| {
+                let n = self.parse_count(args.next(), 1)?;
+                for i in 0..n.min(self.lc3.memory.len()) {
+                    let address = address.wrapping_add(i as u16);
+                    let val = self.lc3.memory[address as usize];
+                    let desc = self.describe_address(address);
+                    let _ = writeln!(out, "{}: {:#06x} ({})", desc, val, val as i16);
+                }
+                Ok(())
+            }),
+            "trace" => match (args.next(), args.next()) {
+                (Some("start"), None) => {
+                    self.lc3.start_trace();
+                    out.push_str("recording trace");
+                    Ok(())
+                }
+                (Some("stop"), path) => match (self.lc3.stop_trace(), path) {
+                    (None, _) => Err("not recording a trace".to_string()),
+                    (Some(trace), None) => {
+                        for step in &trace.steps {
+                            let _ = writeln!(out, "{}", step);
+                        }
+                        Ok(())
+                    }
+                    (Some(trace), Some(path)) => trace
+                        .write(path)
+                        .map(|_| {
+                            let _ = write!(out, "wrote {} steps to {}", trace.steps.len(), path);
+                        })
+                        .map_err(|e| format!("could not write {}: {}", path, e)),
+                },
+                _ => Err("usage: trace start | trace stop [file]".to_string()),
+            },
+            other => Err(format!("unknown command `{}`; try `help`", other)),
+        };
+
+        if let Err(e) = res {
+            out = e;
+        }
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+        Some(out)
+    }
+
+    /// Loads a program into a fresh machine (keeping the same console) and
+    /// forgets any breakpoints and watchpoints from the last one.
+    pub fn load(&mut self, path: &str) -> Result<(), String> {
+        let (objects, symbols) = load_program(path)?;
+        let mut lc3 = LC3::new();
+        lc3.load_objects(&objects).map_err(|e| e.to_string())?;
+        mem::swap(&mut lc3.console, &mut self.lc3.console);
+        *self = Self::new(lc3);
+        self.symbols = symbols;
+        Ok(())
+    }
+
+    /// Executes up to `n` instructions, stopping early if the machine halts or
+    /// a watchpoint fires. Breakpoints don't stop a step.
+    pub fn step(&mut self, n: usize) -> Stop {
+        for _ in 0..n {
+            if let Some(stop) = self.step_once() {
+                return stop;
+            }
+        }
+        Stop::Stepped
+    }
+
+    /// Runs until something interesting happens, or `step_limit` instructions
+    /// have run. Always executes at least one instruction, so that continuing
+    /// from a breakpoint makes progress.
+    pub fn continue_(&mut self) -> Stop {
+        for _ in 0..self.step_limit {
+            if let Some(stop) = self.step_once() {
+                return stop;
+            }
+            if self.breakpoints.contains(&self.lc3.ip) {
+                return Stop::Breakpoint(self.lc3.ip);
+            }
+        }
+        Stop::StepLimit
+    }
+
+    fn step_once(&mut self) -> Option<Stop> {
+        if self.lc3.halted {
+            return Some(Stop::Halted);
+        }
+        self.lc3.run_once();
+        self.lc3.console.flush();
+
+        if let Some(stop) = self.watcher.borrow_mut().hit.take() {
+            return Some(stop);
+        }
+        if self.lc3.halted {
+            return Some(Stop::Halted);
+        }
+        None
+    }
+
+    fn describe_stop(&self, stop: Stop, out: &mut String) {
+        match stop {
+            Stop::Halted => out.push_str("machine halted\n"),
+            Stop::Breakpoint(address) => {
+                let _ = writeln!(out, "breakpoint at {}", self.describe_address(address));
+            }
+            Stop::Watchpoint { address, old, new } => {
+                let desc = self.describe_address(address);
+                let _ = writeln!(out, "{} changed: {:#06x} -> {:#06x}", desc, old, new);
+            }
+            Stop::Stepped => {}
+            Stop::StepLimit => {
+                let _ = writeln!(out, "stopped after {} instructions", self.step_limit);
+            }
+        }
+        if stop != Stop::Halted {
+            self.disassemble(self.lc3.ip, 1, out);
+        }
+    }
+
+    fn dump_registers(&self, out: &mut String) {
+        for (i, r) in self.lc3.registers.iter().enumerate() {
+            let _ = write!(out, "R{} {:#06x} ({:>6})", i, r, *r as i16);
+            out.push_str(if i % 4 == 3 { "\n" } else { "  " });
+        }
+        let (n, z, p) = self.lc3.condition_codes();
+        let cc: String = [(n, 'n'), (z, 'z'), (p, 'p')]
+            .iter()
+            .map(|&(set, ch)| if set { ch } else { '-' })
+            .collect();
+        let _ = writeln!(
+            out,
+            "PC {:#06x}  PSR {:#06x}  CC {}",
+            self.lc3.ip, self.lc3.psr, cc
+        );
+    }
+
+    /// Writes `n` lines of disassembly starting at `start`, marking the PC with
+    /// `>` and breakpoints with `*`.
+    fn disassemble(&self, start: u16, n: usize, out: &mut String) {
+        for i in 0..n {
+            let address = start.wrapping_add(i as u16);
+            let word = self.lc3.memory[address as usize];
+            let pc = if address == self.lc3.ip { '>' } else { ' ' };
+            let bp = if self.breakpoints.contains(&address) {
+                '*'
+            } else {
+                ' '
+            };
+            let _ = write!(out, "{}{} {:#06x}: {:#06x}  ", pc, bp, address, word);
+            if let Some(label) = self.label_at(address) {
+                let _ = write!(out, "{}: ", label);
+            }
+            let label = |a| self.label_at(a).map(str::to_string);
+            let _ = writeln!(out, "{}", format_word(word, address, label));
+        }
+    }
+
+    fn label_at(&self, address: u16) -> Option<&str> {
+        self.symbols
+            .iter()
+            .filter(|&(_, &a)| a == address)
+            .map(|(label, _)| label.as_str())
+            .min()
+    }
+
+    fn describe_address(&self, address: u16) -> String {
+        match self.label_at(address) {
+            Some(label) => format!("{:#06x} ({})", address, label),
+            None => format!("{:#06x}", address),
+        }
+    }
+
+    /// Resolves a label or numeric address.
+    fn location(&self, arg: Option<&str>) -> Result<u16, String> {
+        let arg = arg.ok_or_else(|| "expected an address or label".to_string())?;
+        if let Some(&address) = self.symbols.get(arg) {
+            return Ok(address);
+        }
+        match parse_number(arg) {
+            Some(n) if (0..=u16::MAX as i32).contains(&n) => Ok(n as u16),
+            Some(n) => Err(format!("{} is not an address", n)),
+            None => Err(format!("unknown label `{}`", arg)),
+        }
+    }
+
+    fn parse_count(&self, arg: Option<&str>, default: usize) -> Result<usize, String> {
+        match arg {
+            None => Ok(default),
+            Some(arg) => arg
+                .parse()
+                .map_err(|_| format!("expected a count, found `{}`", arg)),
+        }
+    }
+
+    /// Writes to the console.
+    pub fn print(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.lc3.console.write(byte);
+        }
+        self.lc3.console.flush();
+    }
+
+    /// Reads a line through the console, echoing as we go since a raw mode
+    /// terminal won't do it for us.
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        loop {
+            match self.lc3.console.read_key()? {
+                b'\n' => {
+                    self.print("\n");
+                    return Some(line);
+                }
+                // Backspace or delete.
+                8 | 127 => {
+                    if line.pop().is_some() {
+                        self.print("\x08 \x08");
+                    }
+                }
+                key => {
+                    line.push(key as char);
+                    self.lc3.console.write(key);
+                    self.lc3.console.flush();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::{assembler::assemble, console::BufferConsole};
+    use std::{cell::RefCell, rc::Rc};
+
+    const COUNTDOWN: &str = r#"
+            .ORIG x3000
+            LD R1, COUNT
+    LOOP    ADD R1, R1, #-1
+            ST R1, COUNT
+            BRp LOOP
+            HALT
+    COUNT   .FILL #3
+            .END
+    "#;
+
+    fn debugger(source: &str) -> Debugger {
+        let asm = assemble(source).unwrap();
+        let mut lc3 = LC3::new();
+        lc3.load_objects(&asm.objects).unwrap();
+        let mut debugger = Debugger::new(lc3);
+        debugger.symbols = asm.symbols;
+        debugger
+    }
+
+    #[test]
+    fn steps() {
+        let mut dbg = debugger(COUNTDOWN);
+        assert_eq!(dbg.step(2), Stop::Stepped);
+        assert_eq!(dbg.lc3.ip, 0x3002);
+        assert_eq!(dbg.lc3.registers[1], 2);
+    }
+
+    #[test]
+    fn records_traces() {
+        let mut dbg = debugger(COUNTDOWN);
+        assert!(dbg.command("trace stop").unwrap().contains("not recording"));
+        dbg.command("trace start").unwrap();
+        dbg.step(3);
+        let output = dbg.command("trace stop").unwrap();
+        assert_eq!(output.lines().count(), 3);
+        assert!(output.contains("[x3005] x0003->x0002"), "{}", output);
+    }
+
+    #[test]
+    fn stops_at_breakpoints_by_label() {
+        let mut dbg = debugger(COUNTDOWN);
+        dbg.command("break LOOP").unwrap();
+        assert_eq!(dbg.continue_(), Stop::Breakpoint(0x3001));
+        assert_eq!(dbg.lc3.registers[1], 3);
+        assert_eq!(dbg.continue_(), Stop::Breakpoint(0x3001));
+        assert_eq!(dbg.lc3.registers[1], 2);
+        dbg.command("delete LOOP").unwrap();
+        assert_eq!(dbg.continue_(), Stop::Halted);
+        assert_eq!(dbg.lc3.registers[1], 0);
+    }
+
+    #[test]
+    fn gives_up_on_infinite_loops() {
+        let mut dbg = debugger(".ORIG x3000\nLOOP BRnzp LOOP\n.END");
+        dbg.step_limit = 1000;
+        assert_eq!(dbg.continue_(), Stop::StepLimit);
+        assert!(dbg.command("c").unwrap().contains("stopped after 1000"));
+    }
+
+    #[test]
+    fn lists_at_most_all_of_memory() {
+        let mut dbg = debugger(COUNTDOWN);
+        let listing = dbg.command("list 40000").unwrap();
+        assert_eq!(listing.lines().count(), 0xFFFF);
+        assert_eq!(dbg.command("x 0 70000").unwrap().lines().count(), 0x10000);
+    }
+
+    #[test]
+    fn stops_on_watched_writes() {
+        let mut dbg = debugger(COUNTDOWN);
+        dbg.command("watch COUNT").unwrap();
+        assert_eq!(
+            dbg.continue_(),
+            Stop::Watchpoint {
+                address: 0x3005,
+                old: 3,
+                new: 2
+            }
+        );
+        assert_eq!(dbg.lc3.ip, 0x3003);
+        let out = dbg.command("c").unwrap();
+        assert!(out.starts_with("0x3005 (COUNT) changed: 0x0002 -> 0x0001\n"));
+    }
+
+    #[test]
+    fn watches_device_registers() {
+        let mut dbg = debugger(
+            r#"
+                .ORIG x3000
+                LD R0, CHAR
+                STI R0, DDRP
+                HALT
+        CHAR    .FILL x41
+        DDRP    .FILL xFE06
+                .END
+            "#,
+        );
+        dbg.command("watch xFE06").unwrap();
+        assert_eq!(
+            dbg.continue_(),
+            Stop::Watchpoint {
+                address: 0xFE06,
+                old: 0,
+                new: 0x41
+            }
+        );
+    }
+
+    #[test]
+    fn dumps_registers() {
+        let mut dbg = debugger(COUNTDOWN);
+        dbg.step(1);
+        let out = dbg.command("regs").unwrap();
+        assert!(out.contains("R1 0x0003 (     3)"), "{}", out);
+        assert!(out.contains("PC 0x3001"), "{}", out);
+        assert!(out.contains("CC --p"), "{}", out);
+    }
+
+    #[test]
+    fn lists_around_the_pc() {
+        let mut dbg = debugger(COUNTDOWN);
+        dbg.command("b x3002").unwrap();
+        let out = dbg.command("list 1").unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(
+            lines[1].starts_with(">  0x3000: 0x2204  LD R1, COUNT"),
+            "{}",
+            out
+        );
+        assert!(lines[2].contains("LOOP: ADD R1, R1, #-1"), "{}", out);
+
+        dbg.step(2);
+        let out = dbg.command("l 0").unwrap();
+        assert!(out.starts_with(">* 0x3002"), "{}", out);
+    }
+
+    #[test]
+    fn reports_bad_commands() {
+        let mut dbg = debugger(COUNTDOWN);
+        assert_eq!(
+            dbg.command("break NOWHERE").unwrap(),
+            "unknown label `NOWHERE`\n"
+        );
+        assert_eq!(
+            dbg.command("frobnicate").unwrap(),
+            "unknown command `frobnicate`; try `help`\n"
+        );
+        assert_eq!(dbg.command("quit"), None);
+    }
+
+    #[test]
+    fn repl_reads_commands_from_the_console() {
+        let console = Rc::new(RefCell::new(BufferConsole::new("step\nregs\nquit\n")));
+        let mut dbg = debugger(COUNTDOWN);
+        dbg.lc3.console = Box::new(console.clone());
+        dbg.repl();
+        let output = console.borrow().output_string();
+        assert!(output.contains("(lc3) step\n"), "{}", output);
+        assert!(output.contains("R1 0x0003"), "{}", output);
+    }
+}
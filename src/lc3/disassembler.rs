@@ -0,0 +1,492 @@
+//! Turns LC-3 machine code back into assembly.
+//!
+//! Machine code doesn't say which words are instructions and which are data,
+//! so the disassembler has to guess:
+//!
+//! - Words that LD, ST, LDI, STI or LEA refer to are data.
+//! - Runs of printable characters ending in a zero are `.STRINGZ`s.
+//! - Runs of zeros are `.BLKW`s.
+//! - Anything that isn't a valid instruction (the reserved opcode, a BR that
+//!   never branches, a word with stray bits set) is a `.FILL`.
+//!
+//! Every PC-relative target inside the image gets a label: `SUB_xxxx` for
+//! subroutines, `L_xxxx` for other code, `STR_xxxx` for strings and `DATA_xxxx`
+//! for everything else. The guesses only affect how readable the output is;
+//! it always re-assembles to exactly the same image.
+
+use super::{
+    instructions::Instruction,
+    object::{LoadError, ObjectFile},
+    sign_extend,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    ops::Range,
+    path::Path,
+};
+
+/// Disassembles a set of objects into one `.ORIG` block each. Labels are
+/// shared between them, just as they are when assembling.
+pub fn disassemble(objects: &[ObjectFile]) -> String {
+    Disassembler::new(objects).run()
+}
+
+/// Disassembles the words of `memory` in `range`.
+pub fn disassemble_memory(memory: &[u16], range: Range<u16>) -> String {
+    let words = memory[range.start as usize..range.end as usize].to_vec();
+    disassemble(&[ObjectFile::new(range.start, words)])
+}
+
+pub fn disassemble_file(path: impl AsRef<Path>) -> Result<String, LoadError> {
+    Ok(disassemble(&[ObjectFile::read(path)?]))
+}
+
+/// Formats a single word at `address` as assembly, naming PC-relative targets
+/// with `label` where it knows them. Words that aren't instructions come out
+/// as `.FILL`s.
+pub fn format_word(word: u16, address: u16, label: impl Fn(u16) -> Option<String>) -> String {
+    match as_code(word) {
+        Some(instruction) => format_instruction(instruction, address, label),
+        None => format!(".FILL x{:04X}", word),
+    }
+}
+
+/// Decodes `word` if it's an instruction the assembler could have produced.
+fn as_code(word: u16) -> Option<Instruction> {
+    let instruction = Instruction::from(word);
+    match instruction {
+        Instruction::Reserved() | Instruction::Br(false, false, false, _) => None,
+        _ if u16::from(instruction) != word => None,
+        _ => Some(instruction),
+    }
+}
+
+/// Where a PC-relative instruction at `address` points, unless the offset wraps
+/// around the address space (which the assembler won't do for a label).
+fn target(address: u16, offset: u16, bits: u8) -> Option<u16> {
+    let target = address as i32 + 1 + sign_extend(offset, bits) as i16 as i32;
+    if (0..=u16::MAX as i32).contains(&target) {
+        Some(target as u16)
+    } else {
+        None
+    }
+}
+
+fn format_instruction(
+    instruction: Instruction,
+    address: u16,
+    label: impl Fn(u16) -> Option<String>,
+) -> String {
+    use Instruction::*;
+    let signed = |x: u16, bits: u8| sign_extend(x, bits) as i16;
+    let offset = |x: u16, bits: u8| {
+        target(address, x, bits)
+            .and_then(&label)
+            .unwrap_or_else(|| format!("#{}", signed(x, bits)))
+    };
+    match instruction {
+        AddReg(dr, sr1, sr2) => format!("ADD R{}, R{}, R{}", dr, sr1, sr2),
+        AddImm(dr, sr, imm) => format!("ADD R{}, R{}, #{}", dr, sr, signed(imm, 5)),
+        AndReg(dr, sr1, sr2) => format!("AND R{}, R{}, R{}", dr, sr1, sr2),
+        AndImm(dr, sr, imm) => format!("AND R{}, R{}, #{}", dr, sr, signed(imm, 5)),
+        Br(n, z, p, x) => {
+            let flags: String = [(n, 'n'), (z, 'z'), (p, 'p')]
+                .iter()
+                .filter(|&&(set, _)| set)
+                .map(|&(_, flag)| flag)
+                .collect();
+            format!("BR{} {}", flags, offset(x, 9))
+        }
+        Jmp(7) => "RET".to_string(),
+        Jmp(base) => format!("JMP R{}", base),
+        Jsr(x) => format!("JSR {}", offset(x, 11)),
+        Jsrr(base) => format!("JSRR R{}", base),
+        Ld(dr, x) => format!("LD R{}, {}", dr, offset(x, 9)),
+        Ldi(dr, x) => format!("LDI R{}, {}", dr, offset(x, 9)),
+        Ldr(dr, base, x) => format!("LDR R{}, R{}, #{}", dr, base, signed(x, 6)),
+        Lea(dr, x) => format!("LEA R{}, {}", dr, offset(x, 9)),
+        Not(dr, sr) => format!("NOT R{}, R{}", dr, sr),
+        Rti() => "RTI".to_string(),
+        St(sr, x) => format!("ST R{}, {}", sr, offset(x, 9)),
+        Sti(sr, x) => format!("STI R{}, {}", sr, offset(x, 9)),
+        Str(sr, base, x) => format!("STR R{}, R{}, #{}", sr, base, signed(x, 6)),
+        Trap(0x20) => "GETC".to_string(),
+        Trap(0x21) => "OUT".to_string(),
+        Trap(0x22) => "PUTS".to_string(),
+        Trap(0x23) => "IN".to_string(),
+        Trap(0x24) => "PUTSP".to_string(),
+        Trap(0x25) => "HALT".to_string(),
+        Trap(vector) => format!("TRAP x{:02X}", vector),
+        Reserved() => "RESERVED".to_string(),
+    }
+}
+
+/// Characters `.STRINGZ` can hold, along with how to write them.
+fn escape(word: u16) -> Option<String> {
+    let escaped = match word {
+        0x0A => "\\n".to_string(),
+        0x09 => "\\t".to_string(),
+        0x0D => "\\r".to_string(),
+        0x1B => "\\e".to_string(),
+        0x22 => "\\\"".to_string(),
+        0x5C => "\\\\".to_string(),
+        0x20..=0x7E => (word as u8 as char).to_string(),
+        _ => return None,
+    };
+    Some(escaped)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    String,
+    Code,
+    Subroutine,
+}
+
+struct Disassembler<'a> {
+    objects: &'a [ObjectFile],
+    words: HashMap<u16, u16>,
+    /// Words that are referred to as data.
+    data: HashSet<u16>,
+    /// Words that LEA points at, which are usually strings.
+    lea_targets: HashSet<u16>,
+    /// Words that LDI or STI refer to, which hold addresses.
+    pointers: HashSet<u16>,
+    labels: HashMap<u16, LabelKind>,
+}
+
+impl<'a> Disassembler<'a> {
+    fn new(objects: &'a [ObjectFile]) -> Self {
+        let words = objects
+            .iter()
+            .flat_map(|obj| obj.span().map(|a| a as u16).zip(obj.words.iter().copied()))
+            .collect();
+        let mut disassembler = Self {
+            objects,
+            words,
+            data: HashSet::new(),
+            lea_targets: HashSet::new(),
+            pointers: HashSet::new(),
+            labels: HashMap::new(),
+        };
+        disassembler.find_references();
+        disassembler
+    }
+
+    fn label(&mut self, address: u16, kind: LabelKind) {
+        if self.words.contains_key(&address) {
+            let label = self.labels.entry(address).or_insert(kind);
+            *label = kind.max(*label);
+        }
+    }
+
+    fn find_references(&mut self) {
+        use Instruction::*;
+
+        // Find the data first, so that constants that happen to look like
+        // instructions don't go on to produce labels of their own.
+        let code: Vec<_> = self
+            .words
+            .iter()
+            .filter_map(|(&address, &word)| Some((address, as_code(word)?)))
+            .collect();
+        for &(address, instruction) in &code {
+            if let Ld(_, x) | Ldi(_, x) | St(_, x) | Sti(_, x) | Lea(_, x) = instruction {
+                if let Some(target) = target(address, x, 9) {
+                    self.data.insert(target);
+                }
+            }
+            if let Lea(_, x) = instruction {
+                self.lea_targets.extend(target(address, x, 9));
+            }
+        }
+
+        for &(address, instruction) in &code {
+            if self.data.contains(&address) {
+                continue;
+            }
+            let (target, kind) = match instruction {
+                Br(_, _, _, x) => (target(address, x, 9), LabelKind::Code),
+                Jsr(x) => (target(address, x, 11), LabelKind::Subroutine),
+                Ld(_, x) | St(_, x) | Lea(_, x) => (target(address, x, 9), LabelKind::Data),
+                Ldi(_, x) | Sti(_, x) => {
+                    let target = target(address, x, 9);
+                    self.pointers.extend(target);
+                    (target, LabelKind::Data)
+                }
+                _ => continue,
+            };
+            if let Some(target) = target {
+                self.label(target, kind);
+            }
+        }
+
+        // Whatever an LDI or STI pointer points at gets a label too, so that
+        // the pointer can be written as `.FILL LABEL`.
+        for pointer in self.pointers.clone() {
+            if let Some(&value) = self.words.get(&pointer) {
+                self.data.insert(value);
+                self.label(value, LabelKind::Data);
+            }
+        }
+
+        // Now that every label is known, decide which labels mark strings.
+        let strings: Vec<_> = self
+            .labels
+            .keys()
+            .copied()
+            .filter(|&address| self.string_at(address).is_some())
+            .collect();
+        for address in strings {
+            self.label(address, LabelKind::String);
+        }
+    }
+
+    fn label_name(&self, address: u16) -> Option<String> {
+        let prefix = match self.labels.get(&address)? {
+            LabelKind::Data => "DATA",
+            LabelKind::String => "STR",
+            LabelKind::Code => "L",
+            LabelKind::Subroutine => "SUB",
+        };
+        Some(format!("{}_{:04X}", prefix, address))
+    }
+
+    /// The end of the object containing `address`.
+    fn object_end(&self, address: u16) -> usize {
+        self.objects
+            .iter()
+            .map(|obj| obj.span())
+            .find(|span| span.contains(&(address as usize)))
+            .map_or(0, |span| span.end)
+    }
+
+    /// The (escaped) contents of the string starting at `address`, and how many
+    /// words it takes up, if there is a string there. Strings need at least
+    /// two characters unless LEA points at them, and can't have labels in the
+    /// middle.
+    fn string_at(&self, address: u16) -> Option<(String, usize)> {
+        let mut s = String::new();
+        for (len, a) in (address as usize..self.object_end(address)).enumerate() {
+            let a = a as u16;
+            if a != address && self.labels.contains_key(&a) {
+                return None;
+            }
+            match self.words[&a] {
+                0 if len >= 2 || (len == 1 && self.lea_targets.contains(&address)) => {
+                    return Some((s, len + 1));
+                }
+                word => s.push_str(&escape(word)?),
+            }
+        }
+        None
+    }
+
+    fn run(&self) -> String {
+        let width = self
+            .labels
+            .keys()
+            .filter_map(|&address| self.label_name(address))
+            .map(|name| name.len() + 2)
+            .max()
+            .unwrap_or(0)
+            .max(8);
+
+        let mut out = String::new();
+        for obj in self.objects {
+            let _ = writeln!(out, "{:w$}.ORIG x{:04X}", "", obj.origin, w = width);
+            let end = obj.span().end;
+            let mut address = obj.origin as usize;
+            while address < end {
+                let (text, len) = self.statement(address as u16, end);
+                let label = self.label_name(address as u16).unwrap_or_default();
+                let _ = writeln!(out, "{:w$}{}", label, text, w = width);
+                address += len;
+            }
+            let _ = writeln!(out, "{:w$}.END", "", w = width);
+        }
+        out
+    }
+
+    /// The statement for the word(s) starting at `address`, and how many words
+    /// it covers.
+    fn statement(&self, address: u16, end: usize) -> (String, usize) {
+        if let Some((s, len)) = self.string_at(address) {
+            return (format!(".STRINGZ \"{}\"", s), len);
+        }
+
+        let word = self.words[&address];
+        if word == 0 {
+            let len = (address as usize..end)
+                .take_while(|&a| {
+                    let a = a as u16;
+                    self.words[&a] == 0 && (a == address || !self.labels.contains_key(&a))
+                })
+                .count();
+            return (format!(".BLKW {}", len), len);
+        }
+
+        match as_code(word) {
+            Some(instruction) if !self.data.contains(&address) => {
+                let text = format_instruction(instruction, address, |a| self.label_name(a));
+                (text, 1)
+            }
+            _ => {
+                let pointer = self.labels.get(&word).and_then(|_| self.label_name(word));
+                let text = match (pointer, escape(word)) {
+                    (Some(label), _) if self.pointers.contains(&address) => {
+                        format!(".FILL {}", label)
+                    }
+                    (_, Some(ch)) if word < 0x80 => format!(".FILL x{:04X} ; '{}'", word, ch),
+                    _ => format!(".FILL x{:04X}", word),
+                };
+                (text, 1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::assembler::assemble;
+
+    /// Disassembles `objects` and checks the result assembles back to them.
+    fn round_trip(objects: &[ObjectFile]) -> String {
+        let source = disassemble(objects);
+        let asm = assemble(&source).unwrap_or_else(|errors| {
+            panic!("{:?} in\n{}", errors, source);
+        });
+        assert_eq!(asm.objects, objects, "{}", source);
+        source
+    }
+
+    fn round_trip_source(source: &str) -> String {
+        round_trip(&assemble(source).unwrap().objects)
+    }
+
+    #[test]
+    fn recovers_labels() {
+        let source = round_trip_source(
+            r#"
+            .ORIG x3000
+            LD R1, COUNT
+    LOOP    ADD R1, R1, #-1
+            ST R1, COUNT
+            BRp LOOP
+            JSR DONE
+            HALT
+    DONE    RET
+    COUNT   .FILL #3
+            .END
+            "#,
+        );
+        let expected = "
+           .ORIG x3000
+           LD R1, DATA_3007
+L_3001     ADD R1, R1, #-1
+           ST R1, DATA_3007
+           BRp L_3001
+           JSR SUB_3006
+           HALT
+SUB_3006   RET
+DATA_3007  .FILL x0003
+           .END
+";
+        assert_eq!(source, &expected[1..]);
+    }
+
+    #[test]
+    fn recovers_strings_and_blocks() {
+        let source = round_trip_source(
+            r#"
+            .ORIG x3000
+            LEA R0, MSG
+            PUTS
+            LEA R0, ONE
+            PUTS
+            HALT
+    MSG     .STRINGZ "Hello, \"World\"!\n"
+    ONE     .STRINGZ "x"
+    BUF     .BLKW 4
+    TOP     .BLKW 2
+            .FILL #65
+            .FILL xD000
+            .END
+            "#,
+        );
+        assert!(
+            source.contains(r#"STR_3005  .STRINGZ "Hello, \"World\"!\n""#),
+            "{}",
+            source
+        );
+        assert!(source.contains(r#"STR_3016  .STRINGZ "x""#), "{}", source);
+        assert!(source.contains(".BLKW 6"), "{}", source);
+        assert!(source.contains(".FILL x0041 ; 'A'"), "{}", source);
+        assert!(source.contains(".FILL xD000"), "{}", source);
+    }
+
+    #[test]
+    fn recovers_pointers() {
+        let source = round_trip_source(
+            r#"
+            .ORIG x3000
+            LDI R0, PTR
+            HALT
+    PTR     .FILL VAL
+            .END
+
+            .ORIG x4000
+    VAL     .FILL #1234
+            .END
+            "#,
+        );
+        assert!(source.contains("LDI R0, DATA_3002"), "{}", source);
+        assert!(source.contains("DATA_3002  .FILL DATA_4000"), "{}", source);
+        assert!(source.contains("DATA_4000  .FILL x04D2"), "{}", source);
+    }
+
+    #[test]
+    fn targets_outside_the_image_stay_numeric() {
+        let source = round_trip(&[ObjectFile::new(0x3000, vec![0x0E05, 0x2100, 0x4801])]);
+        assert!(source.contains("BRnzp #5\n"), "{}", source);
+        assert!(source.contains("LD R0, #-256\n"), "{}", source);
+        assert!(source.contains("JSR #1\n"), "{}", source);
+    }
+
+    #[test]
+    fn every_word_round_trips() {
+        for chunk in 0..16u16 {
+            let words = (0..0x1000).map(|i| chunk << 12 | i).collect();
+            round_trip(&[ObjectFile::new(0x3000, words)]);
+        }
+    }
+
+    #[test]
+    fn disassembles_memory() {
+        let mut memory = vec![0; 0x3010];
+        memory[0x3000] = 0x1261; // ADD R1, R1, #1
+        memory[0x3001] = 0xF025; // HALT
+        let source = disassemble_memory(&memory, 0x3000..0x3004);
+        assert_eq!(
+            source,
+            "        .ORIG x3000\n        ADD R1, R1, #1\n        HALT\n        .BLKW 2\n        .END\n"
+        );
+    }
+
+    #[test]
+    fn formats_single_words() {
+        let label = |a| {
+            if a == 0x3001 {
+                Some("LOOP".to_string())
+            } else {
+                None
+            }
+        };
+        assert_eq!(format_word(0x0FFF, 0x3001, label), "BRnzp LOOP");
+        assert_eq!(format_word(0xC1C0, 0x3001, label), "RET");
+        assert_eq!(format_word(0x0041, 0x3001, label), ".FILL x0041");
+    }
+}
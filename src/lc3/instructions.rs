@@ -1,12 +1,16 @@
 //! Via https://github.com/erfur/lc3-vm-rust/blob/master/src/instruction.rs
 
+use super::{sign_extend, traps::HALT};
+use crate::lang::stack_lang;
+use std::convert::TryFrom;
+
 pub type Offset = u16;
 pub type Imm = u16;
 pub type TrapVector = u8;
 pub type Flag = bool;
 pub type Register = usize;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     AddReg(Register, Register, Register),
     AddImm(Register, Register, Imm),
@@ -28,3 +32,267 @@ pub enum Instruction {
     Trap(TrapVector),
     Reserved(),
 }
+
+/// Opcodes occupy the top 4 bits of every instruction word.
+mod op {
+    pub const BR: u16 = 0b0000;
+    pub const ADD: u16 = 0b0001;
+    pub const LD: u16 = 0b0010;
+    pub const ST: u16 = 0b0011;
+    pub const JSR: u16 = 0b0100;
+    pub const AND: u16 = 0b0101;
+    pub const LDR: u16 = 0b0110;
+    pub const STR: u16 = 0b0111;
+    pub const RTI: u16 = 0b1000;
+    pub const NOT: u16 = 0b1001;
+    pub const LDI: u16 = 0b1010;
+    pub const STI: u16 = 0b1011;
+    pub const JMP: u16 = 0b1100;
+    pub const RES: u16 = 0b1101;
+    pub const LEA: u16 = 0b1110;
+    pub const TRAP: u16 = 0b1111;
+}
+
+/// Extracts `len` bits of `word` starting at bit `lo`.
+#[inline]
+fn field(word: u16, lo: u8, len: u8) -> u16 {
+    (word >> lo) & ((1 << len) - 1)
+}
+
+#[inline]
+fn reg(word: u16, lo: u8) -> Register {
+    field(word, lo, 3) as Register
+}
+
+#[inline]
+fn flag(word: u16, bit: u8) -> Flag {
+    field(word, bit, 1) == 1
+}
+
+/// Decodes a 16-bit instruction word. Offsets and immediates are kept as their
+/// raw (not sign extended) bit fields, and bits the ISA leaves unused are
+/// ignored.
+impl From<u16> for Instruction {
+    fn from(word: u16) -> Self {
+        use Instruction::*;
+        match word >> 12 {
+            op::BR => Br(
+                flag(word, 11),
+                flag(word, 10),
+                flag(word, 9),
+                field(word, 0, 9),
+            ),
+            op::ADD if flag(word, 5) => AddImm(reg(word, 9), reg(word, 6), field(word, 0, 5)),
+            op::ADD => AddReg(reg(word, 9), reg(word, 6), reg(word, 0)),
+            op::LD => Ld(reg(word, 9), field(word, 0, 9)),
+            op::ST => St(reg(word, 9), field(word, 0, 9)),
+            op::JSR if flag(word, 11) => Jsr(field(word, 0, 11)),
+            op::JSR => Jsrr(reg(word, 6)),
+            op::AND if flag(word, 5) => AndImm(reg(word, 9), reg(word, 6), field(word, 0, 5)),
+            op::AND => AndReg(reg(word, 9), reg(word, 6), reg(word, 0)),
+            op::LDR => Ldr(reg(word, 9), reg(word, 6), field(word, 0, 6)),
+            op::STR => Str(reg(word, 9), reg(word, 6), field(word, 0, 6)),
+            op::RTI => Rti(),
+            op::NOT => Not(reg(word, 9), reg(word, 6)),
+            op::LDI => Ldi(reg(word, 9), field(word, 0, 9)),
+            op::STI => Sti(reg(word, 9), field(word, 0, 9)),
+            op::JMP => Jmp(reg(word, 6)),
+            op::RES => Reserved(),
+            op::LEA => Lea(reg(word, 9), field(word, 0, 9)),
+            op::TRAP => Trap(field(word, 0, 8) as TrapVector),
+            _ => unreachable!("opcodes are only 4 bits"),
+        }
+    }
+}
+
+/// Encodes an instruction into its 16-bit word. Operands wider than their
+/// field are truncated, so an offset of -1 can be written as `0xFFFF`.
+impl From<Instruction> for u16 {
+    fn from(instr: Instruction) -> Self {
+        use Instruction::*;
+
+        let r = |r: Register, lo: u8| ((r as u16) & 0b111) << lo;
+        let f = |flag: Flag, bit: u8| (flag as u16) << bit;
+        let bits = |x: u16, len: u8| x & ((1 << len) - 1);
+        let opcode = |op: u16| op << 12;
+
+        match instr {
+            AddReg(dr, sr1, sr2) => opcode(op::ADD) | r(dr, 9) | r(sr1, 6) | r(sr2, 0),
+            AddImm(dr, sr1, imm5) => {
+                opcode(op::ADD) | r(dr, 9) | r(sr1, 6) | 1 << 5 | bits(imm5, 5)
+            }
+            AndReg(dr, sr1, sr2) => opcode(op::AND) | r(dr, 9) | r(sr1, 6) | r(sr2, 0),
+            AndImm(dr, sr1, imm5) => {
+                opcode(op::AND) | r(dr, 9) | r(sr1, 6) | 1 << 5 | bits(imm5, 5)
+            }
+            Br(n, z, p, offset9) => {
+                opcode(op::BR) | f(n, 11) | f(z, 10) | f(p, 9) | bits(offset9, 9)
+            }
+            Jmp(base) => opcode(op::JMP) | r(base, 6),
+            Jsr(offset11) => opcode(op::JSR) | 1 << 11 | bits(offset11, 11),
+            Jsrr(base) => opcode(op::JSR) | r(base, 6),
+            Ld(dr, offset9) => opcode(op::LD) | r(dr, 9) | bits(offset9, 9),
+            Ldi(dr, offset9) => opcode(op::LDI) | r(dr, 9) | bits(offset9, 9),
+            Ldr(dr, base, offset6) => opcode(op::LDR) | r(dr, 9) | r(base, 6) | bits(offset6, 6),
+            Lea(dr, offset9) => opcode(op::LEA) | r(dr, 9) | bits(offset9, 9),
+            // The unused SR2 field of NOT is all ones.
+            Not(dr, sr) => opcode(op::NOT) | r(dr, 9) | r(sr, 6) | 0b11_1111,
+            Rti() => opcode(op::RTI),
+            St(sr, offset9) => opcode(op::ST) | r(sr, 9) | bits(offset9, 9),
+            Sti(sr, offset9) => opcode(op::STI) | r(sr, 9) | bits(offset9, 9),
+            Str(sr, base, offset6) => opcode(op::STR) | r(sr, 9) | r(base, 6) | bits(offset6, 6),
+            Trap(trapvect8) => opcode(op::TRAP) | trapvect8 as u16,
+            Reserved() => opcode(op::RES),
+        }
+    }
+}
+
+/// For control-flow graphs, where an instruction's index is its offset from
+/// the start of the program. Where JMP, and so RET, goes depends on a register,
+/// so they just end their block.
+impl stack_lang::Instruction for Instruction {
+    fn is_control(&self) -> bool {
+        use Instruction::*;
+        matches!(self, Br(..) | Jmp(_) | Rti() | Trap(HALT) | Reserved())
+    }
+
+    fn jump_targets(&self, index: usize) -> Vec<usize> {
+        match *self {
+            Instruction::Br(n, z, p, offset9) if n || z || p => {
+                let target = index as i64 + 1 + sign_extend(offset9, 9) as i16 as i64;
+                usize::try_from(target).into_iter().collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn falls_through(&self) -> bool {
+        use Instruction::*;
+        match self {
+            Br(n, z, p, _) => !(n & z & p),
+            Jmp(_) | Rti() | Trap(HALT) | Reserved() => false,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::stack_lang::Cfg;
+    use Instruction::*;
+
+    fn round_trips(instr: Instruction) {
+        let word = u16::from(instr);
+        assert_eq!(Instruction::from(word), instr, "{:#018b}", word);
+    }
+
+    #[test]
+    fn decodes_known_words() {
+        // Examples straight out of the ISA reference.
+        assert_eq!(Instruction::from(0x1042), AddReg(0, 1, 2));
+        assert_eq!(Instruction::from(0x1261), AddImm(1, 1, 1));
+        assert_eq!(Instruction::from(0x0FFE), Br(true, true, true, 0x1FE));
+        assert_eq!(Instruction::from(0xC1C0), Jmp(7));
+        assert_eq!(Instruction::from(0x4802), Jsr(2));
+        assert_eq!(Instruction::from(0x903F), Not(0, 0));
+        assert_eq!(Instruction::from(0xF025), Trap(0x25));
+        assert_eq!(Instruction::from(0x8000), Rti());
+        assert_eq!(Instruction::from(0xD000), Reserved());
+    }
+
+    #[test]
+    fn every_register_field_round_trips() {
+        for a in 0..8 {
+            round_trips(Jmp(a));
+            round_trips(Jsrr(a));
+            for b in 0..8 {
+                round_trips(Not(a, b));
+                for c in 0..8 {
+                    round_trips(AddReg(a, b, c));
+                    round_trips(AndReg(a, b, c));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_offset_field_round_trips() {
+        for r in 0..8 {
+            for imm5 in 0..1 << 5 {
+                for sr1 in 0..8 {
+                    round_trips(AddImm(r, sr1, imm5));
+                    round_trips(AndImm(r, sr1, imm5));
+                }
+            }
+            for offset6 in 0..1 << 6 {
+                for base in 0..8 {
+                    round_trips(Ldr(r, base, offset6));
+                    round_trips(Str(r, base, offset6));
+                }
+            }
+            for offset9 in 0..1 << 9 {
+                round_trips(Ld(r, offset9));
+                round_trips(Ldi(r, offset9));
+                round_trips(Lea(r, offset9));
+                round_trips(St(r, offset9));
+                round_trips(Sti(r, offset9));
+            }
+        }
+        for offset9 in 0..1 << 9 {
+            for nzp in 0..8 {
+                round_trips(Br(nzp & 4 != 0, nzp & 2 != 0, nzp & 1 != 0, offset9));
+            }
+        }
+        for offset11 in 0..1 << 11 {
+            round_trips(Jsr(offset11));
+        }
+        for trapvect8 in 0..=u8::MAX {
+            round_trips(Trap(trapvect8));
+        }
+        round_trips(Rti());
+        round_trips(Reserved());
+    }
+
+    #[test]
+    fn every_word_decodes_stably() {
+        // Not every word survives decoding exactly, since unused bits are
+        // dropped, but re-encoding a decoded word must always be a fixed point.
+        for word in 0..=u16::MAX {
+            let instr = Instruction::from(word);
+            let canonical = u16::from(instr);
+            assert_eq!(Instruction::from(canonical), instr);
+            assert_eq!(
+                canonical >> 12,
+                word >> 12,
+                "opcode changed for {:#06x}",
+                word
+            );
+        }
+    }
+
+    #[test]
+    fn encoding_truncates_wide_operands() {
+        assert_eq!(u16::from(Br(true, true, true, 0xFFFF)), 0x0FFF);
+        assert_eq!(u16::from(AddImm(0, 0, 0xFFFF)), 0x103F);
+    }
+
+    #[test]
+    fn builds_control_flow_graphs() {
+        // Counts R0 down to zero, calling a subroutine each time round.
+        let cfg = Cfg::new(&[
+            AndImm(0, 0, 0),
+            AddImm(0, 0, 5),
+            Jsr(3),                        // 2: loop
+            AddImm(0, 0, 0x1F),            // R0 -= 1
+            Br(false, false, true, 0x1FD), // 4: BRp loop
+            Trap(HALT),
+            Jmp(7), // 6: RET
+        ]);
+        let starts: Vec<_> = cfg.blocks.iter().map(|block| block.start).collect();
+        assert_eq!(starts, vec![0, 2, 5, 6]);
+        assert_eq!(cfg.successors(1), &[1, 2]);
+        assert_eq!(cfg.exits(), vec![2, 3]);
+        assert_eq!(cfg.loops()[0].header, 1);
+    }
+}
@@ -0,0 +1,283 @@
+//! Exceptions, interrupts and the switch between user and supervisor mode.
+//!
+//! Both work the same way as a TRAP into an operating system routine: the
+//! machine switches to the supervisor stack (if it wasn't already on it),
+//! pushes the PSR and PC there, enters supervisor mode and jumps to the handler
+//! listed in the interrupt vector table at x0100. The handler's RTI undoes all
+//! of that.
+
+use super::{KBSR, KBSR_IE, LC3, PSR_PRIORITY, PSR_USER};
+
+/// The interrupt vector table; the handler for vector `v` is at `IVT + v`.
+const IVT: u16 = 0x0100;
+
+/// The keyboard interrupts at this vector, and at this priority.
+const KEYBOARD_VECTOR: u16 = 0x80;
+const KEYBOARD_PRIORITY: u16 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    /// RTI executed in user mode.
+    PrivilegeViolation = 0x00,
+    /// The reserved opcode.
+    IllegalOpcode = 0x01,
+    /// User mode code touched system space or a device register.
+    AccessViolation = 0x02,
+}
+
+impl Exception {
+    fn description(self) -> &'static str {
+        match self {
+            Exception::PrivilegeViolation => "privilege mode violation",
+            Exception::IllegalOpcode => "illegal opcode",
+            Exception::AccessViolation => "access violation",
+        }
+    }
+}
+
+impl LC3 {
+    /// Transfers control to the handler for `exception`. Without an operating
+    /// system to handle it there's nothing sensible left to do, so the machine
+    /// reports what happened and halts.
+    pub(super) fn raise(&mut self, exception: Exception) {
+        let handler = self.mem_read(IVT + exception as u16);
+        if handler == 0 {
+            let pc = self.ip.wrapping_sub(1);
+            let message = format!("\n--- {} at {:#06x} ---\n", exception.description(), pc);
+            for byte in message.bytes() {
+                self.console.write(byte);
+            }
+            self.console.flush();
+            self.halted = true;
+            return;
+        }
+        self.enter_supervisor(None);
+        self.ip = handler;
+    }
+
+    /// Takes a keyboard interrupt if one is enabled, pending, and of higher
+    /// priority than whatever we're running now.
+    pub(super) fn poll_interrupts(&mut self) {
+        if self.memory[KBSR as usize] & KBSR_IE == 0 {
+            return;
+        }
+        let priority = (self.psr & PSR_PRIORITY) >> 8;
+        if priority >= KEYBOARD_PRIORITY {
+            return;
+        }
+        if self.key.is_none() {
+            self.key = self.console.poll_key();
+        }
+        let handler = self.mem_read(IVT + KEYBOARD_VECTOR);
+        if self.key.is_some() && handler != 0 {
+            self.enter_supervisor(Some(KEYBOARD_PRIORITY));
+            self.ip = handler;
+        }
+    }
+
+    /// Saves the PC and PSR on the supervisor stack and enters supervisor mode,
+    /// optionally raising the priority level.
+    pub(super) fn enter_supervisor(&mut self, priority: Option<u16>) {
+        let psr = self.psr;
+        if self.user_mode() {
+            self.saved_usp = self.registers[6];
+            self.registers[6] = self.saved_ssp;
+        }
+        self.push(psr);
+        self.push(self.ip);
+        self.psr &= !PSR_USER;
+        if let Some(priority) = priority {
+            self.psr = (self.psr & !PSR_PRIORITY) | (priority << 8);
+        }
+    }
+
+    pub(super) fn push(&mut self, val: u16) {
+        self.registers[6] = self.registers[6].wrapping_sub(1);
+        self.mem_write(self.registers[6], val);
+    }
+
+    pub(super) fn pop(&mut self) -> u16 {
+        let val = self.mem_read(self.registers[6]);
+        self.registers[6] = self.registers[6].wrapping_add(1);
+        val
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::{assembler::assemble, console::BufferConsole};
+    use std::{cell::RefCell, rc::Rc};
+
+    /// A tiny operating system: it sets up the supervisor stack, installs
+    /// whatever handlers the test defines, and drops into the user program at
+    /// x3000 with an RTI.
+    const OS: &str = r#"
+            .ORIG x0200
+    BOOT    LD R6, SSP
+            LD R0, UPSR
+            ADD R6, R6, #-1
+            STR R0, R6, #0
+            LD R0, UPC
+            ADD R6, R6, #-1
+            STR R0, R6, #0
+            RTI
+    SSP     .FILL x3000
+    UPSR    .FILL x8002
+    UPC     .FILL x3000
+            .END
+    "#;
+
+    fn run(program: &str, input: &str) -> (LC3, String) {
+        let console = Rc::new(RefCell::new(BufferConsole::new(input)));
+        let mut lc3 = LC3::new();
+        lc3.console = Box::new(console.clone());
+        let mut objects = assemble(OS).unwrap().objects;
+        objects.extend(assemble(program).unwrap().objects);
+        lc3.load_objects(&objects).unwrap();
+        lc3.run();
+        let output = console.borrow().output_string();
+        (lc3, output)
+    }
+
+    #[test]
+    fn boots_into_user_mode() {
+        let (lc3, _) = run(".ORIG x3000\nADD R1, R1, #1\nHALT\n.END", "");
+        assert!(lc3.user_mode());
+        assert_eq!(lc3.registers[1], 1);
+        assert_eq!(lc3.saved_ssp, 0x3000);
+    }
+
+    #[test]
+    fn trap_handlers_run_in_supervisor_mode() {
+        let program = r#"
+            .ORIG x3000
+            LD R6, USP
+            TRAP x30
+            HALT
+    USP     .FILL xFD00
+            .END
+
+            .ORIG x0030
+            .FILL SETFLAG
+            .END
+
+            .ORIG x1000
+    SETFLAG ST R0, SAVED ; system space, so would fault in user mode
+            AND R0, R0, #0
+            ADD R0, R0, #7
+            STI R0, FLAGPTR
+            LD R0, SAVED
+            RTI
+    SAVED   .BLKW 1
+    FLAGPTR .FILL x0500
+            .END
+        "#;
+        let (lc3, output) = run(program, "");
+        assert_eq!(lc3.memory[0x0500], 7);
+        // Back in user mode on the user stack.
+        assert!(lc3.user_mode());
+        assert_eq!(lc3.registers[6], 0xFD00);
+        assert_eq!(lc3.saved_ssp, 0x3000);
+        assert!(!output.contains("violation"), "{}", output);
+    }
+
+    #[test]
+    fn user_access_to_system_space_is_an_access_violation() {
+        let program = r#"
+            .ORIG x3000
+            LDI R0, SYSTEM
+            HALT
+    SYSTEM  .FILL x0000
+            .END
+        "#;
+        let (lc3, output) = run(program, "");
+        assert!(lc3.halted);
+        assert_eq!(output, "\n--- access violation at 0x3000 ---\n");
+
+        // Device registers are off limits too, and an installed handler gets
+        // to decide what happens.
+        let program = r#"
+            .ORIG x3000
+            LDI R0, KBSRPTR
+            HALT
+    KBSRPTR .FILL xFE00
+            .END
+
+            .ORIG x0102
+            .FILL ACV
+            .END
+
+            .ORIG x1000
+    ACV     AND R5, R5, #0
+            ADD R5, R5, #-1
+            HALT
+            .END
+        "#;
+        let (lc3, _) = run(program, "");
+        assert_eq!(lc3.registers[5], 0xFFFF);
+        // The faulting PC was saved on the supervisor stack.
+        assert_eq!(lc3.memory[0x2FFE], 0x3001);
+        assert_eq!(lc3.memory[0x2FFF], 0x8002);
+    }
+
+    #[test]
+    fn rti_in_user_mode_is_a_privilege_violation() {
+        let (_, output) = run(".ORIG x3000\nRTI\n.END", "");
+        assert_eq!(output, "\n--- privilege mode violation at 0x3000 ---\n");
+    }
+
+    #[test]
+    fn reserved_opcode_is_illegal() {
+        let (_, output) = run(".ORIG x3000\n.FILL xD000\n.END", "");
+        assert_eq!(output, "\n--- illegal opcode at 0x3000 ---\n");
+    }
+
+    #[test]
+    fn keyboard_interrupts() {
+        // The user program spins until the interrupt handler hands it a key.
+        let program = r#"
+            .ORIG x3000
+    LOOP    LD R0, KEY
+            BRz LOOP
+            HALT
+    KEY     .FILL 0
+            .END
+
+            .ORIG x0180
+            .FILL KBINT
+            .END
+
+            .ORIG x1000
+    KBINT   ST R0, SAVED
+            LDI R0, KBDR
+            STI R0, KEYPTR
+            LD R0, SAVED
+            RTI
+    SAVED   .BLKW 1
+    KBDR    .FILL xFE02
+    KEYPTR  .FILL x3003
+            .END
+        "#;
+        let console = Rc::new(RefCell::new(BufferConsole::new("k")));
+        let mut lc3 = LC3::new();
+        lc3.console = Box::new(console.clone());
+        let mut objects = assemble(OS).unwrap().objects;
+        objects.extend(assemble(program).unwrap().objects);
+        lc3.load_objects(&objects).unwrap();
+        // The OS doesn't enable keyboard interrupts or raise its own priority
+        // itself, so the test does both in its place.
+        lc3.memory[KBSR as usize] = KBSR_IE;
+        lc3.psr |= 4 << 8;
+
+        // Nothing happens during boot, at priority 4...
+        for _ in 0..8 {
+            lc3.run_once();
+        }
+        assert_eq!(lc3.ip, 0x3000);
+        // ...but the user program (at priority 0) is.
+        lc3.run();
+        assert_eq!(lc3.registers[0], 'k' as u16);
+        assert!(lc3.user_mode());
+    }
+}
@@ -0,0 +1,195 @@
+//! The LC-3's 64K words of memory, along with hooks that get to see (and
+//! optionally intercept) every access the machine makes. Hooks are how extra
+//! memory-mapped devices, watchpoints and access tracing plug in.
+//!
+//! Only accesses made through `read`, `read_device` and `write` run hooks.
+//! Indexing goes straight to the underlying cells, which is what debuggers and
+//! tests want when they inspect or patch memory without disturbing the machine.
+
+use std::ops::{Deref, DerefMut};
+
+/// One cell for every 16-bit address.
+pub const MEMORY_SIZE: usize = 1 << 16;
+
+pub trait MemoryHook {
+    /// Called on every read of `address`, which currently holds `stored`.
+    /// Returning a value serves the read from the hook instead, as a device
+    /// register would.
+    fn on_read(&mut self, address: u16, stored: u16) -> Option<u16> {
+        None
+    }
+
+    /// Called on every write of `new` to `address`, which currently holds
+    /// `old`. Returning false means the hook has consumed the write and memory
+    /// is left unchanged.
+    fn on_write(&mut self, address: u16, old: u16, new: u16) -> bool {
+        true
+    }
+}
+
+/// Identifies an installed hook, so that it can be removed later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookId(usize);
+
+pub struct Memory {
+    cells: Box<[u16]>,
+    hooks: Vec<(HookId, Box<dyn MemoryHook>)>,
+    next_hook_id: usize,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self {
+            cells: vec![0; MEMORY_SIZE].into_boxed_slice(),
+            hooks: Vec::new(),
+            next_hook_id: 0,
+        }
+    }
+
+    /// Hooks run in the order they were added. The first to claim a read
+    /// supplies its value, and the first to consume a write stops the rest from
+    /// seeing it.
+    pub fn add_hook(&mut self, hook: impl MemoryHook + 'static) -> HookId {
+        let id = HookId(self.next_hook_id);
+        self.next_hook_id += 1;
+        self.hooks.push((id, Box::new(hook)));
+        id
+    }
+
+    pub fn remove_hook(&mut self, id: HookId) {
+        self.hooks.retain(|(hook_id, _)| *hook_id != id);
+    }
+
+    pub fn hook_count(&self) -> usize {
+        self.hooks.len()
+    }
+
+    pub fn read(&mut self, address: u16) -> u16 {
+        self.read_device(address, self.cells[address as usize])
+    }
+
+    /// A read of a register the machine's own devices serve, which holds `val`
+    /// rather than whatever is stored. Hooks still see it, and can override it.
+    pub fn read_device(&mut self, address: u16, val: u16) -> u16 {
+        self.hooks
+            .iter_mut()
+            .find_map(|(_, hook)| hook.on_read(address, val))
+            .unwrap_or(val)
+    }
+
+    /// Returns false if a hook consumed the write.
+    pub fn write(&mut self, address: u16, val: u16) -> bool {
+        let old = self.cells[address as usize];
+        let allowed = self
+            .hooks
+            .iter_mut()
+            .all(|(_, hook)| hook.on_write(address, old, val));
+        if allowed {
+            self.cells[address as usize] = val;
+        }
+        allowed
+    }
+}
+
+impl Deref for Memory {
+    type Target = [u16];
+    fn deref(&self) -> &Self::Target {
+        &self.cells
+    }
+}
+
+impl DerefMut for Memory {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.cells
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Records every access.
+    #[derive(Default)]
+    struct Tracer(Rc<RefCell<Vec<(char, u16, u16)>>>);
+
+    impl MemoryHook for Tracer {
+        fn on_read(&mut self, address: u16, stored: u16) -> Option<u16> {
+            self.0.borrow_mut().push(('r', address, stored));
+            None
+        }
+
+        fn on_write(&mut self, address: u16, old: u16, new: u16) -> bool {
+            self.0.borrow_mut().push(('w', address, new));
+            true
+        }
+    }
+
+    /// A read-only counter device at a single address.
+    struct Counter(u16, u16);
+
+    impl MemoryHook for Counter {
+        fn on_read(&mut self, address: u16, _: u16) -> Option<u16> {
+            if address == self.0 {
+                self.1 += 1;
+                Some(self.1)
+            } else {
+                None
+            }
+        }
+
+        fn on_write(&mut self, address: u16, _: u16, _: u16) -> bool {
+            address != self.0
+        }
+    }
+
+    #[test]
+    fn every_address_is_addressable() {
+        let mut memory = Memory::new();
+        assert_eq!(memory.len(), 65_536);
+        memory.write(0xFFFF, 42);
+        assert_eq!(memory.read(0xFFFF), 42);
+        assert_eq!(memory[0xFFFF], 42);
+    }
+
+    #[test]
+    fn hooks_see_accesses() {
+        let mut memory = Memory::new();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let id = memory.add_hook(Tracer(log.clone()));
+        memory.write(0x3000, 7);
+        memory.read(0x3000);
+        // Indexing bypasses hooks.
+        memory[0x3001] = 8;
+        assert_eq!(memory[0x3001], 8);
+        assert_eq!(&*log.borrow(), &[('w', 0x3000, 7), ('r', 0x3000, 7)]);
+
+        memory.remove_hook(id);
+        memory.read(0x3000);
+        assert_eq!(log.borrow().len(), 2);
+    }
+
+    #[test]
+    fn hooks_can_act_as_devices() {
+        let mut memory = Memory::new();
+        memory.add_hook(Counter(0xFE10, 0));
+        assert_eq!(memory.read(0xFE10), 1);
+        assert_eq!(memory.read(0xFE10), 2);
+        memory.write(0xFE10, 100);
+        assert_eq!(memory[0xFE10], 0);
+        memory.write(0xFE11, 100);
+        assert_eq!(memory.read(0xFE11), 100);
+    }
+
+    #[test]
+    fn hooks_see_device_reads() {
+        let mut memory = Memory::new();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        memory.add_hook(Tracer(log.clone()));
+        assert_eq!(memory.read_device(0xFE04, 0x8000), 0x8000);
+        assert_eq!(&*log.borrow(), &[('r', 0xFE04, 0x8000)]);
+
+        memory.add_hook(Counter(0xFE04, 0));
+        assert_eq!(memory.read_device(0xFE04, 0x8000), 1);
+    }
+}
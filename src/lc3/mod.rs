@@ -1,24 +1,95 @@
 //! Credit to https://justinmeiners.github.io/lc3-vm/ for guidance/inspiration.
 
+mod assembler;
+mod console;
+mod debugger;
+mod disassembler;
 mod instructions;
+mod interrupts;
+mod memory;
+mod object;
+mod trace;
+mod traps;
 
+use console::{BufferConsole, Console, TermConsole};
+use debugger::Debugger;
 use instructions::Instruction;
-use std::mem;
+use interrupts::Exception;
+use memory::Memory;
+use object::ObjectFile;
+use trace::Recorder;
+use std::{collections::HashMap, fs, mem, path::Path};
 
-pub fn main() {
-    println!(
-        "{}",
-        2f64.powi(-2) + 2f64.powi(-3) + 2f64.powi(-5) + 2f64.powi(-6)
-    );
+/// Starts the debugger on the terminal, loading the program at `path` (either
+/// assembly source or an `.obj` image) if one is given.
+pub fn main(path: Option<String>) {
+    let mut lc3 = LC3::new();
+    lc3.console = match TermConsole::new() {
+        Ok(console) => Box::new(console),
+        Err(e) => return println!("could not put the terminal into raw mode: {}", e),
+    };
+    let mut debugger = Debugger::new(lc3);
+    if let Some(path) = path {
+        if let Some(output) = debugger.command(&format!("load {}", path)) {
+            debugger.print(&output);
+        }
+    }
+    debugger.repl();
+}
+
+/// Reads the objects making up a program, assembling it first if it isn't
+/// already an `.obj` file. Labels are only available in the latter case.
+fn load_program(path: impl AsRef<Path>) -> Result<(Vec<ObjectFile>, HashMap<String, u16>), String> {
+    let path = path.as_ref();
+    if path.extension() == Some("obj".as_ref()) {
+        return ObjectFile::read(path)
+            .map(|obj| (vec![obj], HashMap::new()))
+            .map_err(|e| e.to_string());
+    }
+    let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    match assembler::assemble(&source) {
+        Ok(assembly) => Ok((assembly.objects, assembly.symbols)),
+        Err(errors) => Err(errors
+            .iter()
+            .map(|e| format!("{}:{}", path.display(), e))
+            .collect::<Vec<_>>()
+            .join("\n")),
+    }
 }
 
-type Memory = [u16; u16::MAX as usize];
+/// Memory-mapped device registers. The keyboard and display status registers
+/// have their "ready" flag in bit 15, and the machine stops when bit 15 of the
+/// machine control register is cleared.
+const KBSR: u16 = 0xFE00;
+const KBDR: u16 = 0xFE02;
+const DSR: u16 = 0xFE04;
+const DDR: u16 = 0xFE06;
+const MCR: u16 = 0xFFFE;
+
+/// Bit 14 of the KBSR enables keyboard interrupts; it is the only part of the
+/// register programs may write.
+const KBSR_IE: u16 = 1 << 14;
+
+/// Addresses below this are system space, as are the device registers.
+const USER_SPACE: std::ops::Range<u16> = 0x3000..0xFE00;
+/// The supervisor stack grows down from just below user space.
+const SSP_START: u16 = 0x3000;
+
+/// Set when the machine is in user mode.
+const PSR_USER: u16 = 1 << 15;
+const PSR_PRIORITY: u16 = 0b111 << 8;
+
+/// Condition code bits, stored in the low three bits of the PSR.
+const FL_POS: u16 = 1 << 0;
+const FL_ZRO: u16 = 1 << 1;
+const FL_NEG: u16 = 1 << 2;
 
 struct LC3 {
     /// The LC-3 has 65,536 memory locations (the maximum that is addressable by
     /// a 16-bit unsigned integer 2^16), each of which stores a 16-bit value.
     /// This means it can store a total of only 128kb, which is a lot smaller
-    /// than you may be used to!
+    /// than you may be used to! Programs access it through `mem_read` and
+    /// `mem_write`, which also run any hooks installed on it.
     memory: Memory,
     /// A register is a slot for storing a single value on the CPU. Registers are
     /// like the "workbench" of the CPU. For the CPU to work with a piece of data,
@@ -29,20 +100,53 @@ struct LC3 {
     /// back in memory.
     registers: [u16; 8],
     /// ip is short for instruction pointer. The instruction pointer tracks the
-    /// location of the processor's next instruction.
-    ip: usize,
+    /// location of the processor's next instruction. By the time an instruction
+    /// executes this has already been incremented, so PC-relative offsets are
+    /// computed from the *following* instruction, just like on real hardware.
+    ip: u16,
+    /// Processor Status Register. Bit 15 is the privilege mode (0 for
+    /// supervisor, 1 for user), bits 10-8 the priority level the machine is
+    /// running at, and the low three bits the condition codes: bit 2 is
+    /// N(egative), bit 1 is Z(ero) and bit 0 is P(ositive). Exactly one of
+    /// those is set after any instruction that writes a register.
+    psr: u16,
+    /// R6 is the stack pointer, but user and supervisor code each get their own
+    /// stack. Whichever isn't in use is parked here.
+    saved_ssp: u16,
+    saved_usp: u16,
+    /// Set once there is nothing left to execute.
+    halted: bool,
+    /// Where the keyboard and display devices read and write.
+    console: Box<dyn Console>,
+    /// The keyboard data register: the last key pressed, if the program
+    /// hasn't read it yet.
+    key: Option<u8>,
+    /// Records every step while tracing is switched on.
+    trace: Option<Recorder>,
     /// Used as an alternate to running a program on memory, for easier testing.
     test_program_: Option<Vec<Instruction>>,
 }
 
 impl LC3 {
     pub fn new() -> Self {
-        Self {
-            memory: [0; u16::MAX as usize],
+        let mut this = Self {
+            memory: Memory::new(),
             registers: [0; 8],
             ip: 0,
+            // Like real hardware we power on in supervisor mode; it is up to an
+            // operating system to drop into user mode with an RTI.
+            psr: FL_ZRO,
+            saved_ssp: SSP_START,
+            saved_usp: 0,
+            halted: false,
+            console: Box::new(BufferConsole::default()),
+            key: None,
+            trace: None,
             test_program_: None,
-        }
+        };
+        // The clock starts out running.
+        this.memory[MCR as usize] = 1 << 15;
+        this
     }
 
     pub fn new_test(test_program: Vec<Instruction>) -> Self {
@@ -51,53 +155,243 @@ impl LC3 {
         this
     }
 
+    /// Runs until the machine halts.
+    pub fn run(&mut self) {
+        while !self.halted {
+            self.run_once();
+        }
+    }
+
     pub fn run_once(&mut self) {
-        let Self {
-            mut registers,
-            mut memory,
-            mut ip,
-            ..
-        } = self;
-
-        match self.next_instruction() {
+        if self.trace.is_some() {
+            self.record_step();
+        } else {
+            self.step();
+        }
+    }
+
+    /// Executes one instruction, returning it (if one could be fetched).
+    fn step(&mut self) -> Option<Instruction> {
+        self.poll_interrupts();
+
+        let (instruction, res) = match self.next_instruction() {
+            Ok(Some(instruction)) => (Some(instruction), self.execute(instruction)),
+            Ok(None) => {
+                self.halted = true;
+                return None;
+            }
+            Err(e) => (None, Err(e)),
+        };
+        if let Err(e) = res {
+            self.raise(e);
+        }
+        instruction
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<(), Exception> {
+        match instruction {
             Instruction::AddReg(dr, sr1, sr2) => {
-                registers[dr] = registers[sr1] + registers[sr2];
+                let val = self.registers[sr1].wrapping_add(self.registers[sr2]);
+                self.set_register(dr, val);
             }
             Instruction::AddImm(dr, sr1, imm) => {
-                registers[dr] = registers[sr1] + sign_extend(imm, 5);
+                let val = self.registers[sr1].wrapping_add(sign_extend(imm, 5));
+                self.set_register(dr, val);
             }
             Instruction::AndReg(dr, sr1, sr2) => {
-                registers[dr] = registers[sr1] & registers[sr2];
+                let val = self.registers[sr1] & self.registers[sr2];
+                self.set_register(dr, val);
             }
             Instruction::AndImm(dr, sr1, imm) => {
-                registers[dr] = registers[sr1] & sign_extend(imm, 5);
+                let val = self.registers[sr1] & sign_extend(imm, 5);
+                self.set_register(dr, val);
             }
             Instruction::Not(dr, sr1) => {
-                registers[dr] = !registers[sr1];
+                let val = !self.registers[sr1];
+                self.set_register(dr, val);
+            }
+            Instruction::Br(n, z, p, offset9) => {
+                let (cn, cz, cp) = self.condition_codes();
+                if (n && cn) || (z && cz) || (p && cp) {
+                    self.ip = self.pc_offset(offset9, 9);
+                }
             }
-            Instruction::Br(n, z, p, offset9) => {}
+            // RET is just JMP R7.
             Instruction::Jmp(base) => {
-                memory[registers[base] as usize];
+                self.ip = self.registers[base];
+            }
+            Instruction::Jsr(offset11) => {
+                self.registers[7] = self.ip;
+                self.ip = self.pc_offset(offset11, 11);
+            }
+            Instruction::Jsrr(base) => {
+                // Read the base first, since JSRR R7 is allowed.
+                let target = self.registers[base];
+                self.registers[7] = self.ip;
+                self.ip = target;
             }
-            Instruction::Jsr(offset11) => {}
-            Instruction::Jsrr(base) => {}
             Instruction::Ld(dr, offset9) => {
-                registers[dr] = memory[ip.wrapping_add(sign_extend(offset9, 9) as usize)]
+                let val = self.load(self.pc_offset(offset9, 9))?;
+                self.set_register(dr, val);
+            }
+            Instruction::Ldi(dr, offset9) => {
+                let address = self.load(self.pc_offset(offset9, 9))?;
+                let val = self.load(address)?;
+                self.set_register(dr, val);
+            }
+            Instruction::Ldr(dr, base, offset6) => {
+                let address = self.registers[base].wrapping_add(sign_extend(offset6, 6));
+                let val = self.load(address)?;
+                self.set_register(dr, val);
+            }
+            // As of the 3rd edition of Patt & Patel, LEA no longer sets the
+            // condition codes.
+            Instruction::Lea(dr, offset9) => {
+                self.registers[dr] = self.pc_offset(offset9, 9);
+            }
+            Instruction::St(sr, offset9) => {
+                self.store(self.pc_offset(offset9, 9), self.registers[sr])?;
+            }
+            Instruction::Sti(sr, offset9) => {
+                let address = self.load(self.pc_offset(offset9, 9))?;
+                self.store(address, self.registers[sr])?;
             }
-            _ => panic!("instruction not handled"),
+            Instruction::Str(sr, base, offset6) => {
+                let address = self.registers[base].wrapping_add(sign_extend(offset6, 6));
+                self.store(address, self.registers[sr])?;
+            }
+            // Traps run in supervisor mode, with the caller's PC and PSR saved on
+            // the supervisor stack for the service routine's RTI to restore.
+            Instruction::Trap(trapvect8) => {
+                let handler = self.mem_read(trapvect8 as u16);
+                if handler != 0 || !self.native_trap(trapvect8) {
+                    self.enter_supervisor(None);
+                    self.ip = handler;
+                }
+            }
+            // Return from an interrupt or trap handler: the PC and PSR were
+            // pushed on the stack (R6) by whoever invoked the handler.
+            Instruction::Rti() => {
+                if self.user_mode() {
+                    return Err(Exception::PrivilegeViolation);
+                }
+                self.ip = self.pop();
+                self.psr = self.pop();
+                if self.user_mode() {
+                    self.saved_ssp = self.registers[6];
+                    self.registers[6] = self.saved_usp;
+                }
+            }
+            Instruction::Reserved() => return Err(Exception::IllegalOpcode),
         }
+        Ok(())
     }
 
-    fn next_instruction(&mut self) -> Instruction {
-        match &mut self.test_program_ {
-            None => todo!(),
-            Some(program) => {
-                let res = program[self.ip];
-                self.ip += 1;
-                res
+    fn next_instruction(&mut self) -> Result<Option<Instruction>, Exception> {
+        let res = match &self.test_program_ {
+            None => {
+                let word = self.load(self.ip);
+                self.ip = self.ip.wrapping_add(1);
+                return word.map(|word| Some(Instruction::from(word)));
             }
+            Some(program) => program.get(self.ip as usize).copied(),
+        };
+        self.ip = self.ip.wrapping_add(1);
+        Ok(res)
+    }
+
+    fn user_mode(&self) -> bool {
+        self.psr & PSR_USER != 0
+    }
+
+    /// A memory read on behalf of a program, which may not touch system space
+    /// or the device registers from user mode.
+    fn load(&mut self, address: u16) -> Result<u16, Exception> {
+        self.check_access(address)?;
+        Ok(self.mem_read(address))
+    }
+
+    fn store(&mut self, address: u16, val: u16) -> Result<(), Exception> {
+        self.check_access(address)?;
+        self.mem_write(address, val);
+        Ok(())
+    }
+
+    fn check_access(&self, address: u16) -> Result<(), Exception> {
+        if self.user_mode() && !USER_SPACE.contains(&address) {
+            Err(Exception::AccessViolation)
+        } else {
+            Ok(())
         }
     }
+
+    /// Device registers are served by the console, but memory hooks still get
+    /// to see (and override) those reads, as with any other address.
+    fn mem_read(&mut self, address: u16) -> u16 {
+        let val = match address {
+            KBSR => {
+                if self.key.is_none() {
+                    self.key = self.console.poll_key();
+                }
+                let ready = (self.key.is_some() as u16) << 15;
+                ready | (self.memory[KBSR as usize] & KBSR_IE)
+            }
+            KBDR => self.key.take().map_or(0, |key| key as u16),
+            // Output is never buffered, so the display is always ready.
+            DSR => 1 << 15,
+            _ => return self.memory.read(address),
+        };
+        self.memory.read_device(address, val)
+    }
+
+    /// Writes go through memory, hooks and all, before the devices act on
+    /// them. A hook that consumes a device register write keeps it from the
+    /// device too.
+    fn mem_write(&mut self, address: u16, val: u16) {
+        let val = if address == KBSR { val & KBSR_IE } else { val };
+        if !self.memory.write(address, val) {
+            return;
+        }
+        match address {
+            DDR => {
+                self.console.write(val as u8);
+                self.console.flush();
+            }
+            MCR if val >> 15 == 0 => self.halted = true,
+            _ => {}
+        }
+    }
+
+    /// Computes `ip + SEXT(offset)` where `offset` is a `bits` wide field.
+    fn pc_offset(&self, offset: u16, bits: u8) -> u16 {
+        self.ip.wrapping_add(sign_extend(offset, bits))
+    }
+
+    /// Writes a register and updates the condition codes from the written value.
+    fn set_register(&mut self, r: usize, val: u16) {
+        self.registers[r] = val;
+        self.set_cc(val);
+    }
+
+    fn set_cc(&mut self, val: u16) {
+        let flag = if val == 0 {
+            FL_ZRO
+        } else if val >> 15 == 1 {
+            FL_NEG
+        } else {
+            FL_POS
+        };
+        self.psr = (self.psr & !0b111) | flag;
+    }
+
+    /// Returns the (n, z, p) condition codes.
+    pub fn condition_codes(&self) -> (bool, bool, bool) {
+        (
+            self.psr & FL_NEG != 0,
+            self.psr & FL_ZRO != 0,
+            self.psr & FL_POS != 0,
+        )
+    }
 }
 
 #[inline]
@@ -124,7 +418,6 @@ mod tests {
     use Instruction::*;
 
     #[test]
-    #[ignore = "this test fails but this module isn't important to me right now"]
     fn add_works() {
         /// add r6 r2 r6
         let test_program = vec![AddReg(1, 0, 1), AddImm(0, 1, 13)];
@@ -138,4 +431,205 @@ mod tests {
         assert_eq!(lc3.registers[0], 33);
         assert_eq!(lc3.registers[1], 20);
     }
+
+    #[test]
+    fn add_sets_condition_codes() {
+        let mut lc3 = LC3::new_test(vec![
+            AddImm(0, 0, 0b11111),
+            AddImm(0, 0, 1),
+            AddImm(0, 0, 1),
+        ]);
+        lc3.run_once();
+        assert_eq!(lc3.registers[0], 0xFFFF);
+        assert_eq!(lc3.condition_codes(), (true, false, false));
+        lc3.run_once();
+        assert_eq!(lc3.condition_codes(), (false, true, false));
+        lc3.run_once();
+        assert_eq!(lc3.condition_codes(), (false, false, true));
+    }
+
+    #[test]
+    fn and_works() {
+        let mut lc3 = LC3::new_test(vec![
+            AndReg(2, 0, 1),
+            AndImm(3, 0, 0b10000),
+            AndImm(4, 0, 0),
+        ]);
+        lc3.registers[0] = 0b1010_1010_1010_1010;
+        lc3.registers[1] = 0b0000_0000_1111_1111;
+        lc3.run_once();
+        assert_eq!(lc3.registers[2], 0b1010_1010);
+        // The immediate is sign extended, so 0b10000 masks off only the low 4 bits.
+        lc3.run_once();
+        assert_eq!(lc3.registers[3], 0b1010_1010_1010_0000);
+        assert_eq!(lc3.condition_codes(), (true, false, false));
+        lc3.run_once();
+        assert_eq!(lc3.registers[4], 0);
+        assert_eq!(lc3.condition_codes(), (false, true, false));
+    }
+
+    #[test]
+    fn not_works() {
+        let mut lc3 = LC3::new_test(vec![Not(1, 0)]);
+        lc3.registers[0] = 0x00FF;
+        lc3.run_once();
+        assert_eq!(lc3.registers[1], 0xFF00);
+        assert_eq!(lc3.condition_codes(), (true, false, false));
+    }
+
+    #[test]
+    fn br_works() {
+        // Skip forwards over one instruction, then branch backwards to the
+        // start only if the result was zero (which it won't be).
+        let mut lc3 = LC3::new_test(vec![
+            AddImm(0, 0, 1),
+            Br(false, false, true, 1),
+            AddImm(1, 1, 1),
+            Br(false, true, false, 0b1_1111_1100),
+        ]);
+        lc3.run_once();
+        lc3.run_once();
+        assert_eq!(lc3.ip, 3);
+        lc3.run_once();
+        assert_eq!(lc3.ip, 4);
+        assert_eq!(lc3.registers[1], 0);
+        // An unconditional branch backwards.
+        let mut lc3 = LC3::new_test(vec![Br(true, true, true, 0b1_1111_1111)]);
+        lc3.run_once();
+        assert_eq!(lc3.ip, 0);
+    }
+
+    #[test]
+    fn jmp_and_ret_work() {
+        let mut lc3 = LC3::new_test(vec![Jmp(2)]);
+        lc3.registers[2] = 0x3000;
+        lc3.run_once();
+        assert_eq!(lc3.ip, 0x3000);
+    }
+
+    #[test]
+    fn jsr_works() {
+        let mut lc3 = LC3::new_test(vec![Jsr(0b111_1111_1111)]);
+        lc3.run_once();
+        assert_eq!(lc3.registers[7], 1);
+        assert_eq!(lc3.ip, 0);
+    }
+
+    #[test]
+    fn jsrr_works() {
+        let mut lc3 = LC3::new_test(vec![Jsrr(7)]);
+        lc3.registers[7] = 0x4000;
+        lc3.run_once();
+        assert_eq!(lc3.registers[7], 1);
+        assert_eq!(lc3.ip, 0x4000);
+    }
+
+    #[test]
+    fn loads_work() {
+        let mut lc3 = LC3::new_test(vec![
+            Ld(0, 9),
+            Ldi(1, 7),
+            Ldr(2, 3, 0b11_1111),
+            Lea(4, 0b1_1111_1100),
+        ]);
+        lc3.memory[10] = 0x8000;
+        lc3.memory[9] = 0x1234;
+        lc3.memory[0x1234] = 42;
+        lc3.registers[3] = 0x2001;
+        lc3.memory[0x2000] = 7;
+        lc3.run_once();
+        assert_eq!(lc3.registers[0], 0x8000);
+        assert_eq!(lc3.condition_codes(), (true, false, false));
+        lc3.run_once();
+        assert_eq!(lc3.registers[1], 42);
+        assert_eq!(lc3.condition_codes(), (false, false, true));
+        lc3.run_once();
+        assert_eq!(lc3.registers[2], 7);
+        lc3.run_once();
+        assert_eq!(lc3.registers[4], 0);
+        // LEA leaves the condition codes alone.
+        assert_eq!(lc3.condition_codes(), (false, false, true));
+    }
+
+    #[test]
+    fn stores_work() {
+        let mut lc3 = LC3::new_test(vec![St(0, 9), Sti(0, 9), Str(0, 1, 0b11_1110)]);
+        lc3.registers[0] = 0xBEEF;
+        lc3.registers[1] = 0x3002;
+        lc3.memory[11] = 0x5000;
+        lc3.run_once();
+        assert_eq!(lc3.memory[10], 0xBEEF);
+        lc3.run_once();
+        assert_eq!(lc3.memory[0x5000], 0xBEEF);
+        lc3.run_once();
+        assert_eq!(lc3.memory[0x3000], 0xBEEF);
+    }
+
+    #[test]
+    fn trap_and_rti_work() {
+        let mut lc3 = LC3::new_test(vec![Trap(0x25)]);
+        lc3.memory[0x25] = 0x0400;
+        lc3.registers[6] = 0x3000;
+        lc3.set_cc(0xFFFF);
+        lc3.run_once();
+        assert_eq!(lc3.ip, 0x0400);
+        assert_eq!(lc3.registers[6], 0x2FFE);
+        assert_eq!(lc3.memory[0x2FFE], 1);
+        assert_eq!(lc3.memory[0x2FFF], FL_NEG);
+
+        let mut lc3 = LC3::new_test(vec![Rti()]);
+        lc3.registers[6] = 0x2FFE;
+        lc3.memory[0x2FFE] = 0x3000;
+        lc3.memory[0x2FFF] = FL_NEG;
+        lc3.run_once();
+        assert_eq!(lc3.ip, 0x3000);
+        assert_eq!(lc3.condition_codes(), (true, false, false));
+        assert_eq!(lc3.registers[6], 0x3000);
+    }
+
+    #[test]
+    fn runs_to_completion() {
+        // Multiply 3 by 4 through repeated addition.
+        let mut lc3 = LC3::new_test(vec![
+            AndImm(0, 0, 0),
+            AddImm(1, 0, 3),
+            AddImm(2, 0, 4),
+            AddReg(0, 0, 1),
+            AddImm(2, 2, 0b11111),
+            Br(false, false, true, 0b1_1111_1101),
+        ]);
+        lc3.run();
+        assert_eq!(lc3.registers[0], 12);
+        assert_eq!(lc3.condition_codes(), (false, true, false));
+    }
+
+    #[test]
+    fn runs_from_memory() {
+        let mut lc3 = LC3::new();
+        lc3.ip = 0x3000;
+        lc3.memory[0x3000] = AddImm(0, 0, 5).into();
+        lc3.memory[0x3001] = AddReg(1, 0, 0).into();
+        lc3.run_once();
+        lc3.run_once();
+        assert_eq!(lc3.registers[1], 10);
+        assert_eq!(lc3.ip, 0x3002);
+    }
+
+    #[test]
+    fn updates_persist() {
+        let mut lc3 = LC3::new_test(vec![
+            AddImm(0, 0, 0b11111),
+            AddImm(1, 1, 1),
+            Str(1, 0, 0),
+            AddImm(1, 1, 1),
+            Str(1, 0, 0),
+        ]);
+        lc3.run();
+        assert_eq!(lc3.registers[0], 0xFFFF);
+        assert_eq!(lc3.registers[1], 2);
+        // The very last address exists and keeps what was written to it.
+        assert_eq!(lc3.memory.len(), 1 << 16);
+        assert_eq!(lc3.memory[0xFFFF], 2);
+        assert_eq!(lc3.mem_read(0xFFFF), 2);
+    }
 }
@@ -0,0 +1,238 @@
+//! Loading of `.obj` images as produced by the standard LC-3 toolchain
+//! (`lc3as`, `laser`, lc3tools...). The format is about as simple as it gets:
+//! a sequence of big-endian 16-bit words, the first of which is the origin
+//! (the address the rest should be placed at).
+
+use super::LC3;
+use std::{fmt, fs, io, ops::Range, path::Path};
+
+/// A single object file: a contiguous run of words destined for `origin`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectFile {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    /// Every object file needs at least its origin word.
+    MissingOrigin,
+    /// Words are 2 bytes, so an odd number of bytes means a truncated file.
+    OddLength(usize),
+    /// The payload runs past the end of memory.
+    TooLong {
+        origin: u16,
+        len: usize,
+    },
+    /// Two object files want the same memory.
+    Overlap {
+        first: Range<usize>,
+        second: Range<usize>,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "could not read object file: {}", e),
+            LoadError::MissingOrigin => write!(f, "object file has no origin word"),
+            LoadError::OddLength(len) => {
+                write!(f, "object file is {} bytes, which is not whole words", len)
+            }
+            LoadError::TooLong { origin, len } => write!(
+                f,
+                "{} words at origin {:#06x} run past the end of memory",
+                len, origin
+            ),
+            LoadError::Overlap { first, second } => write!(
+                f,
+                "object at {:#06x}..{:#06x} overlaps object at {:#06x}..{:#06x}",
+                second.start, second.end, first.start, first.end
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl ObjectFile {
+    pub fn new(origin: u16, words: Vec<u16>) -> Self {
+        Self { origin, words }
+    }
+
+    /// Parses the raw bytes of an object file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+        if bytes.len() & 1 == 1 {
+            return Err(LoadError::OddLength(bytes.len()));
+        }
+        let mut words = bytes
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+        let origin = words.next().ok_or(LoadError::MissingOrigin)?;
+        Ok(Self::new(origin, words.collect()))
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// The inverse of `from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity((self.words.len() + 1) * 2);
+        bytes.extend_from_slice(&self.origin.to_be_bytes());
+        for word in &self.words {
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    /// The memory addresses this object occupies.
+    pub fn span(&self) -> Range<usize> {
+        let start = self.origin as usize;
+        start..start + self.words.len()
+    }
+}
+
+impl LC3 {
+    /// Copies the object into memory at its origin and points the PC at it.
+    pub fn load_object(&mut self, obj: &ObjectFile) -> Result<(), LoadError> {
+        self.check_fits(obj)?;
+        self.memory[obj.span()].copy_from_slice(&obj.words);
+        self.ip = obj.origin;
+        // Real programs run from memory, not from a test program.
+        self.test_program_ = None;
+        Ok(())
+    }
+
+    fn check_fits(&self, obj: &ObjectFile) -> Result<(), LoadError> {
+        if obj.span().end > self.memory.len() {
+            return Err(LoadError::TooLong {
+                origin: obj.origin,
+                len: obj.words.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Loads several objects into one machine image, refusing to let any two
+    /// of them share an address. The PC is left at the origin of the first,
+    /// which by convention is the program (the rest being its data or OS).
+    /// Nothing is loaded unless all of them can be.
+    pub fn load_objects(&mut self, objs: &[ObjectFile]) -> Result<(), LoadError> {
+        for (i, obj) in objs.iter().enumerate() {
+            self.check_fits(obj)?;
+            for earlier in &objs[..i] {
+                let (first, second) = (earlier.span(), obj.span());
+                if first.start < second.end && second.start < first.end {
+                    return Err(LoadError::Overlap { first, second });
+                }
+            }
+        }
+        for obj in objs.iter().rev() {
+            self.load_object(obj)?;
+        }
+        Ok(())
+    }
+
+    pub fn load_obj_file(&mut self, path: impl AsRef<Path>) -> Result<(), LoadError> {
+        self.load_object(&ObjectFile::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::instructions::Instruction::*;
+
+    #[test]
+    fn parses_origin_and_payload() {
+        let obj = ObjectFile::from_bytes(&[0x30, 0x00, 0x12, 0x61, 0xF0, 0x25]).unwrap();
+        assert_eq!(obj.origin, 0x3000);
+        assert_eq!(obj.words, vec![0x1261, 0xF025]);
+        assert_eq!(
+            obj.to_bytes(),
+            vec![0x30, 0x00, 0x12, 0x61, 0xF0, 0x25],
+            "bytes should round trip"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(matches!(
+            ObjectFile::from_bytes(&[]),
+            Err(LoadError::MissingOrigin)
+        ));
+        assert!(matches!(
+            ObjectFile::from_bytes(&[0x30, 0x00, 0x12]),
+            Err(LoadError::OddLength(3))
+        ));
+    }
+
+    #[test]
+    fn loads_into_memory_and_sets_pc() {
+        let mut lc3 = LC3::new();
+        let obj = ObjectFile::new(0x3000, vec![AddImm(0, 0, 7).into(), AddReg(1, 0, 0).into()]);
+        lc3.load_object(&obj).unwrap();
+        assert_eq!(lc3.ip, 0x3000);
+        assert_eq!(lc3.memory[0x3001], u16::from(AddReg(1, 0, 0)));
+        lc3.run_once();
+        lc3.run_once();
+        assert_eq!(lc3.registers[1], 14);
+    }
+
+    #[test]
+    fn rejects_objects_past_end_of_memory() {
+        let mut lc3 = LC3::new();
+        let obj = ObjectFile::new(0xFFF0, vec![0; 0x20]);
+        assert!(matches!(
+            lc3.load_object(&obj),
+            Err(LoadError::TooLong { origin: 0xFFF0, .. })
+        ));
+    }
+
+    #[test]
+    fn loads_many_objects() {
+        let mut lc3 = LC3::new();
+        let program = ObjectFile::new(0x3000, vec![Ld(0, 0x0FF).into()]);
+        let data = ObjectFile::new(0x3100, vec![1234]);
+        lc3.load_objects(&[program, data]).unwrap();
+        assert_eq!(lc3.ip, 0x3000);
+        lc3.run_once();
+        assert_eq!(lc3.registers[0], 1234);
+    }
+
+    #[test]
+    fn detects_overlap() {
+        let mut lc3 = LC3::new();
+        let a = ObjectFile::new(0x3000, vec![0; 4]);
+        let b = ObjectFile::new(0x3003, vec![0; 4]);
+        let c = ObjectFile::new(0x3004, vec![0; 4]);
+        assert!(matches!(
+            lc3.load_objects(&[a.clone(), b]),
+            Err(LoadError::Overlap { .. })
+        ));
+        assert!(lc3.load_objects(&[a, c]).is_ok());
+    }
+
+    #[test]
+    fn loads_all_objects_or_none() {
+        let mut lc3 = LC3::new();
+        let program = ObjectFile::new(0x3000, vec![1, 2, 3]);
+        let too_long = ObjectFile::new(0xFFF0, vec![4; 0x20]);
+        assert!(matches!(
+            lc3.load_objects(&[program, too_long]),
+            Err(LoadError::TooLong { origin: 0xFFF0, .. })
+        ));
+        assert_eq!(lc3.memory[0x3000..0x3003], [0, 0, 0]);
+        assert_eq!(lc3.memory[0xFFF0], 0);
+    }
+}
@@ -0,0 +1,586 @@
+//! Execution traces: a record of exactly what a program did, step by step.
+//!
+//! Each `Step` stores the old *and* new value of everything it changed, so a
+//! `Replayer` can rebuild the machine at any point of the trace from the
+//! initial snapshot, going backwards just as easily as forwards.
+//!
+//! Traces are saved in a compact binary format (all numbers big-endian):
+//!
+//! ```text
+//! "LC3T" version:u8
+//! pc:u16 psr:u16 registers:[u16; 8]
+//! runs:u32 { start:u16 len:u16 words:[u16; len] }   non-zero memory
+//! steps:u32 { step }
+//!
+//! step = pc:u16 next_pc:u16 has_instr:u8 [word:u16]
+//!        has_psr:u8 [old:u16 new:u16]
+//!        regs:u8 { r:u8 old:u16 new:u16 }
+//!        writes:u16 { address:u16 old:u16 new:u16 }
+//! ```
+
+use super::{
+    instructions::Instruction,
+    memory::{HookId, MemoryHook, MEMORY_SIZE},
+    LC3,
+};
+use std::{cell::RefCell, fmt, fs, io, path::Path, rc::Rc};
+
+const MAGIC: &[u8; 4] = b"LC3T";
+const VERSION: u8 = 1;
+
+/// The architecturally visible state of the machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineState {
+    pub pc: u16,
+    pub psr: u16,
+    pub registers: [u16; 8],
+    pub memory: Vec<u16>,
+}
+
+/// Everything a single instruction changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// Where the instruction was fetched from.
+    pub pc: u16,
+    /// Where the machine went next.
+    pub next_pc: u16,
+    /// None if the fetch itself faulted.
+    pub instruction: Option<Instruction>,
+    /// The old and new PSR, if it changed. This covers the condition codes as
+    /// well as privilege and priority changes.
+    pub psr: Option<(u16, u16)>,
+    /// (register, old, new) for each register that changed.
+    pub registers: Vec<(u8, u16, u16)>,
+    /// (address, old, new) for each memory write, in the order they happened.
+    pub writes: Vec<(u16, u16, u16)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    pub initial: MachineState,
+    pub steps: Vec<Step>,
+}
+
+/// What the machine keeps while tracing is switched on.
+pub(super) struct Recorder {
+    trace: Trace,
+    writes: Rc<RefCell<Vec<(u16, u16, u16)>>>,
+    hook: HookId,
+}
+
+/// Logs memory writes on the recorder's behalf.
+struct WriteLog(Rc<RefCell<Vec<(u16, u16, u16)>>>);
+
+impl MemoryHook for WriteLog {
+    fn on_write(&mut self, address: u16, old: u16, new: u16) -> bool {
+        self.0.borrow_mut().push((address, old, new));
+        true
+    }
+}
+
+impl LC3 {
+    /// Starts recording a trace from the machine's current state.
+    pub fn start_trace(&mut self) {
+        self.stop_trace();
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let hook = self.memory.add_hook(WriteLog(writes.clone()));
+        self.trace = Some(Recorder {
+            trace: Trace {
+                initial: self.state(),
+                steps: Vec::new(),
+            },
+            writes,
+            hook,
+        });
+    }
+
+    /// Stops recording, returning the trace so far.
+    pub fn stop_trace(&mut self) -> Option<Trace> {
+        let recorder = self.trace.take()?;
+        self.memory.remove_hook(recorder.hook);
+        Some(recorder.trace)
+    }
+
+    pub fn state(&self) -> MachineState {
+        MachineState {
+            pc: self.ip,
+            psr: self.psr,
+            registers: self.registers,
+            memory: self.memory.to_vec(),
+        }
+    }
+
+    /// `step`, but noting down everything that changed.
+    pub(super) fn record_step(&mut self) {
+        let (pc, psr, registers) = (self.ip, self.psr, self.registers);
+        let instruction = self.step();
+
+        let mut step = Step {
+            pc,
+            next_pc: self.ip,
+            instruction,
+            psr: if psr != self.psr {
+                Some((psr, self.psr))
+            } else {
+                None
+            },
+            registers: (0..8)
+                .filter(|&r| registers[r] != self.registers[r])
+                .map(|r| (r as u8, registers[r], self.registers[r]))
+                .collect(),
+            writes: Vec::new(),
+        };
+        let recorder = match &mut self.trace {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        step.writes = recorder.writes.borrow_mut().drain(..).collect();
+        recorder.trace.steps.push(step);
+    }
+}
+
+impl fmt::Display for Step {
+    /// One line per step, e.g. `x3001 AddImm(1, 1, 31)  R1 x0001->x0000  CC p->z`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "x{:04X} ", self.pc)?;
+        match self.instruction {
+            Some(instruction) => write!(f, "{:?}", instruction)?,
+            None => write!(f, "<fault>")?,
+        }
+        for &(r, old, new) in &self.registers {
+            write!(f, "  R{} x{:04X}->x{:04X}", r, old, new)?;
+        }
+        for &(address, old, new) in &self.writes {
+            write!(f, "  [x{:04X}] x{:04X}->x{:04X}", address, old, new)?;
+        }
+        if let Some((old, new)) = self.psr {
+            if old & 0b111 != new & 0b111 {
+                write!(f, "  CC {}->{}", cc(old), cc(new))?;
+            }
+            if old & !0b111 != new & !0b111 {
+                write!(f, "  PSR x{:04X}->x{:04X}", old, new)?;
+            }
+        }
+        if self.next_pc != self.pc.wrapping_add(1) {
+            write!(f, "  -> x{:04X}", self.next_pc)?;
+        }
+        Ok(())
+    }
+}
+
+fn cc(psr: u16) -> &'static str {
+    match psr & 0b111 {
+        0b100 => "n",
+        0b010 => "z",
+        0b001 => "p",
+        _ => "?",
+    }
+}
+
+/* Binary format */
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Io(e) => write!(f, "could not read trace: {}", e),
+            TraceError::BadMagic => write!(f, "not an LC-3 trace file"),
+            TraceError::UnsupportedVersion(v) => write!(f, "unsupported trace version {}", v),
+            TraceError::Truncated => write!(f, "trace file is truncated"),
+        }
+    }
+}
+
+impl From<io::Error> for TraceError {
+    fn from(e: io::Error) -> Self {
+        TraceError::Io(e)
+    }
+}
+
+/// Reads big-endian numbers off the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], TraceError> {
+        if self.0.len() < n {
+            return Err(TraceError::Truncated);
+        }
+        let (front, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(front)
+    }
+
+    fn u8(&mut self) -> Result<u8, TraceError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, TraceError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, TraceError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, x: u16) {
+    out.extend_from_slice(&x.to_be_bytes());
+}
+
+impl Trace {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+
+        let initial = &self.initial;
+        put_u16(&mut out, initial.pc);
+        put_u16(&mut out, initial.psr);
+        for &r in &initial.registers {
+            put_u16(&mut out, r);
+        }
+
+        // Memory is mostly zeroes, so only store the runs that aren't.
+        let mut runs = Vec::new();
+        let mut address = 0;
+        while address < initial.memory.len() {
+            if initial.memory[address] == 0 {
+                address += 1;
+                continue;
+            }
+            let start = address;
+            while address < initial.memory.len()
+                && initial.memory[address] != 0
+                && address - start < u16::MAX as usize
+            {
+                address += 1;
+            }
+            runs.push(start..address);
+        }
+        out.extend_from_slice(&(runs.len() as u32).to_be_bytes());
+        for run in runs {
+            put_u16(&mut out, run.start as u16);
+            put_u16(&mut out, run.len() as u16);
+            for &word in &initial.memory[run] {
+                put_u16(&mut out, word);
+            }
+        }
+
+        out.extend_from_slice(&(self.steps.len() as u32).to_be_bytes());
+        for step in &self.steps {
+            put_u16(&mut out, step.pc);
+            put_u16(&mut out, step.next_pc);
+            match step.instruction {
+                Some(instruction) => {
+                    out.push(1);
+                    put_u16(&mut out, instruction.into());
+                }
+                None => out.push(0),
+            }
+            match step.psr {
+                Some((old, new)) => {
+                    out.push(1);
+                    put_u16(&mut out, old);
+                    put_u16(&mut out, new);
+                }
+                None => out.push(0),
+            }
+            out.push(step.registers.len() as u8);
+            for &(r, old, new) in &step.registers {
+                out.push(r);
+                put_u16(&mut out, old);
+                put_u16(&mut out, new);
+            }
+            put_u16(&mut out, step.writes.len() as u16);
+            for &(address, old, new) in &step.writes {
+                put_u16(&mut out, address);
+                put_u16(&mut out, old);
+                put_u16(&mut out, new);
+            }
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TraceError> {
+        let mut r = Reader(bytes);
+        if r.take(4).map_err(|_| TraceError::BadMagic)? != MAGIC {
+            return Err(TraceError::BadMagic);
+        }
+        match r.u8()? {
+            VERSION => {}
+            v => return Err(TraceError::UnsupportedVersion(v)),
+        }
+
+        let pc = r.u16()?;
+        let psr = r.u16()?;
+        let mut registers = [0; 8];
+        for reg in registers.iter_mut() {
+            *reg = r.u16()?;
+        }
+        let mut memory = vec![0; MEMORY_SIZE];
+        for _ in 0..r.u32()? {
+            let start = r.u16()? as usize;
+            let len = r.u16()? as usize;
+            let cells = memory
+                .get_mut(start..start + len)
+                .ok_or(TraceError::Truncated)?;
+            for cell in cells {
+                *cell = r.u16()?;
+            }
+        }
+        let initial = MachineState {
+            pc,
+            psr,
+            registers,
+            memory,
+        };
+
+        let mut steps = Vec::new();
+        for _ in 0..r.u32()? {
+            let pc = r.u16()?;
+            let next_pc = r.u16()?;
+            let instruction = match r.u8()? {
+                0 => None,
+                _ => Some(Instruction::from(r.u16()?)),
+            };
+            let psr = match r.u8()? {
+                0 => None,
+                _ => Some((r.u16()?, r.u16()?)),
+            };
+            let registers = (0..r.u8()?)
+                .map(|_| Ok(((r.u8()? & 0b111), r.u16()?, r.u16()?)))
+                .collect::<Result<_, TraceError>>()?;
+            let writes = (0..r.u16()?)
+                .map(|_| Ok((r.u16()?, r.u16()?, r.u16()?)))
+                .collect::<Result<_, TraceError>>()?;
+            steps.push(Step {
+                pc,
+                next_pc,
+                instruction,
+                psr,
+                registers,
+                writes,
+            });
+        }
+
+        Ok(Self { initial, steps })
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, TraceError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+/* Replay */
+
+/// Moves a copy of the machine backwards and forwards through a trace.
+pub struct Replayer<'t> {
+    trace: &'t Trace,
+    state: MachineState,
+    /// How many steps have been applied to the initial state.
+    position: usize,
+}
+
+impl<'t> Replayer<'t> {
+    pub fn new(trace: &'t Trace) -> Self {
+        Self {
+            trace,
+            state: trace.initial.clone(),
+            position: 0,
+        }
+    }
+
+    pub fn state(&self) -> &MachineState {
+        &self.state
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Applies the next step, returning it, or None at the end of the trace.
+    pub fn step_forward(&mut self) -> Option<&'t Step> {
+        let step = self.trace.steps.get(self.position)?;
+        for &(r, _, new) in &step.registers {
+            self.state.registers[r as usize] = new;
+        }
+        for &(address, _, new) in &step.writes {
+            self.state.memory[address as usize] = new;
+        }
+        if let Some((_, new)) = step.psr {
+            self.state.psr = new;
+        }
+        self.state.pc = step.next_pc;
+        self.position += 1;
+        Some(step)
+    }
+
+    /// Undoes the last step, returning it, or None at the start of the trace.
+    pub fn step_backward(&mut self) -> Option<&'t Step> {
+        self.position = self.position.checked_sub(1)?;
+        let step = &self.trace.steps[self.position];
+        for &(r, old, _) in &step.registers {
+            self.state.registers[r as usize] = old;
+        }
+        // Undo writes newest first, in case one address was written twice.
+        for &(address, old, _) in step.writes.iter().rev() {
+            self.state.memory[address as usize] = old;
+        }
+        if let Some((old, _)) = step.psr {
+            self.state.psr = old;
+        }
+        self.state.pc = step.pc;
+        Some(step)
+    }
+
+    /// Moves to the state after `position` steps (clamped to the trace).
+    pub fn seek(&mut self, position: usize) {
+        let position = position.min(self.trace.steps.len());
+        while self.position < position {
+            self.step_forward();
+        }
+        while self.position > position {
+            self.step_backward();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::{assembler::assemble, instructions::Instruction::*};
+
+    const COUNTDOWN: &str = r#"
+            .ORIG x3000
+            LD R1, COUNT
+    LOOP    ADD R1, R1, #-1
+            ST R1, COUNT
+            BRp LOOP
+            HALT
+    COUNT   .FILL #3
+            .END
+    "#;
+
+    fn traced(source: &str) -> (LC3, Trace) {
+        let mut lc3 = LC3::new();
+        lc3.load_objects(&assemble(source).unwrap().objects)
+            .unwrap();
+        lc3.start_trace();
+        lc3.run();
+        let trace = lc3.stop_trace().unwrap();
+        (lc3, trace)
+    }
+
+    #[test]
+    fn records_each_step() {
+        let (_, trace) = traced(COUNTDOWN);
+        // LD, then three times round the loop, then HALT.
+        assert_eq!(trace.steps.len(), 1 + 3 * 3 + 1);
+        assert_eq!(
+            trace.steps[0],
+            Step {
+                pc: 0x3000,
+                next_pc: 0x3001,
+                instruction: Some(Ld(1, 4)),
+                psr: Some((0b010, 0b001)),
+                registers: vec![(1, 0, 3)],
+                writes: vec![],
+            }
+        );
+        assert_eq!(trace.steps[2].writes, vec![(0x3005, 3, 2)]);
+        assert_eq!(
+            trace.steps[2].to_string(),
+            "x3002 St(1, 2)  [x3005] x0003->x0002"
+        );
+        assert_eq!(
+            trace.steps[3].to_string(),
+            "x3003 Br(false, false, true, 509)  -> x3001"
+        );
+    }
+
+    #[test]
+    fn tracing_is_opt_in() {
+        let mut lc3 = LC3::new_test(vec![AddImm(0, 0, 1)]);
+        assert!(lc3.trace.is_none());
+        assert_eq!(lc3.memory.hook_count(), 0);
+        lc3.run();
+        assert!(lc3.stop_trace().is_none());
+    }
+
+    #[test]
+    fn records_device_register_writes() {
+        let (lc3, trace) = traced(
+            r#"
+                .ORIG x3000
+                LD R0, IE
+                STI R0, KBSRP
+                AND R0, R0, #0
+                STI R0, MCRP
+        IE      .FILL x4000
+        KBSRP   .FILL xFE00
+        MCRP    .FILL xFFFE
+                .END
+            "#,
+        );
+        assert!(lc3.halted);
+        assert_eq!(trace.steps[1].writes, vec![(0xFE00, 0, 0x4000)]);
+        assert_eq!(trace.steps[3].writes, vec![(0xFFFE, 0x8000, 0)]);
+        let mut replayer = Replayer::new(&trace);
+        replayer.seek(trace.steps.len());
+        assert_eq!(replayer.state().memory, lc3.state().memory);
+    }
+
+    #[test]
+    fn replays_forwards_and_backwards() {
+        let (lc3, trace) = traced(COUNTDOWN);
+        let mut replayer = Replayer::new(&trace);
+
+        replayer.seek(trace.steps.len());
+        assert_eq!(replayer.state().registers, lc3.registers);
+        assert_eq!(replayer.state().memory[0x3005], 0);
+
+        // Step back over the last store of COUNT.
+        replayer.seek(3);
+        assert_eq!(replayer.state().memory[0x3005], 2);
+        assert_eq!(replayer.state().pc, 0x3003);
+        let undone = replayer.step_backward().unwrap();
+        assert_eq!(undone.pc, 0x3002);
+        assert_eq!(replayer.state().memory[0x3005], 3);
+        assert_eq!(replayer.state().registers[1], 2);
+
+        replayer.seek(0);
+        assert_eq!(replayer.state(), &trace.initial);
+        assert!(replayer.step_backward().is_none());
+    }
+
+    #[test]
+    fn binary_format_round_trips() {
+        let (_, trace) = traced(COUNTDOWN);
+        let bytes = trace.to_bytes();
+        assert_eq!(Trace::from_bytes(&bytes).unwrap(), trace);
+        // The initial memory is stored sparsely.
+        assert!(bytes.len() < 1024, "{} bytes", bytes.len());
+
+        assert!(matches!(
+            Trace::from_bytes(b"nope"),
+            Err(TraceError::BadMagic)
+        ));
+        assert!(matches!(
+            Trace::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(TraceError::Truncated)
+        ));
+    }
+}
@@ -0,0 +1,203 @@
+//! Built-in versions of the LC-3 OS's TRAP service routines, so that programs
+//! can do I/O without an operating system image being loaded. A routine
+//! installed in the trap vector table always takes precedence over these.
+
+use super::{instructions::TrapVector, LC3};
+
+pub const GETC: TrapVector = 0x20;
+pub const OUT: TrapVector = 0x21;
+pub const PUTS: TrapVector = 0x22;
+pub const IN: TrapVector = 0x23;
+pub const PUTSP: TrapVector = 0x24;
+pub const HALT: TrapVector = 0x25;
+
+impl LC3 {
+    /// Performs the service routine for `vector`, returning false if there
+    /// isn't a built-in one.
+    pub(super) fn native_trap(&mut self, vector: TrapVector) -> bool {
+        match vector {
+            // Read a single character into R0, without echoing it.
+            GETC => self.read_char(),
+            // Write the character in R0.
+            OUT => self.console.write(self.registers[0] as u8),
+            // Write the null-terminated string starting at R0, one character
+            // per word.
+            PUTS => {
+                let mut address = self.registers[0];
+                loop {
+                    let word = self.mem_read(address);
+                    if word == 0 {
+                        break;
+                    }
+                    self.console.write(word as u8);
+                    address = address.wrapping_add(1);
+                }
+            }
+            // Prompt for a character, echo it and store it in R0.
+            IN => {
+                self.write_str("Enter a character: ");
+                self.read_char();
+                if !self.halted {
+                    self.console.write(self.registers[0] as u8);
+                }
+            }
+            // Like PUTS, but with two characters packed into each word, the
+            // first in the low byte.
+            PUTSP => {
+                let mut address = self.registers[0];
+                loop {
+                    let word = self.mem_read(address);
+                    if word == 0 {
+                        break;
+                    }
+                    let [high, low] = word.to_be_bytes();
+                    self.console.write(low);
+                    if high != 0 {
+                        self.console.write(high);
+                    }
+                    address = address.wrapping_add(1);
+                }
+            }
+            HALT => {
+                self.write_str("\n--- halting the LC-3 ---\n");
+                self.halted = true;
+            }
+            _ => return false,
+        }
+        self.console.flush();
+        true
+    }
+
+    fn read_char(&mut self) {
+        match self.console.read_key() {
+            Some(key) => self.registers[0] = key as u16,
+            // Nothing can ever arrive, so there is no point carrying on.
+            None => self.halted = true,
+        }
+    }
+
+    fn write_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.console.write(byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc3::{assembler::assemble, console::BufferConsole};
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Assembles and runs `source`, feeding it `input` and returning its output.
+    fn run(source: &str, input: &str) -> (LC3, String) {
+        let console = Rc::new(RefCell::new(BufferConsole::new(input)));
+        let mut lc3 = LC3::new();
+        lc3.console = Box::new(console.clone());
+        lc3.load_objects(&assemble(source).unwrap().objects)
+            .unwrap();
+        lc3.run();
+        let output = console.borrow().output_string();
+        (lc3, output)
+    }
+
+    #[test]
+    fn puts_and_halt() {
+        let source = r#"
+            .ORIG x3000
+            LEA R0, HELLO
+            PUTS
+            HALT
+    HELLO   .STRINGZ "Hello, World!"
+            .END
+        "#;
+        let (_, output) = run(source, "");
+        assert_eq!(output, "Hello, World!\n--- halting the LC-3 ---\n");
+    }
+
+    #[test]
+    fn getc_and_out_echo_input() {
+        let source = r#"
+            .ORIG x3000
+    LOOP    GETC
+            OUT
+            BR LOOP
+            .END
+        "#;
+        // Running out of input halts the machine.
+        let (_, output) = run(source, "echo");
+        assert_eq!(output, "echo");
+    }
+
+    #[test]
+    fn in_prompts_and_echoes() {
+        let source = ".ORIG x3000\nIN\nHALT\n.END";
+        let (lc3, output) = run(source, "q");
+        assert_eq!(lc3.registers[0], 'q' as u16);
+        assert!(output.starts_with("Enter a character: q"));
+    }
+
+    #[test]
+    fn putsp_unpacks_two_characters_per_word() {
+        let source = r#"
+            .ORIG x3000
+            LEA R0, PACKED
+            PUTSP
+            HALT
+    PACKED  .FILL x6261 ; "ab"
+            .FILL x0063 ; "c"
+            .FILL 0
+            .END
+        "#;
+        let (_, output) = run(source, "");
+        assert!(output.starts_with("abc\n"));
+    }
+
+    #[test]
+    fn installed_handlers_take_precedence() {
+        let source = r#"
+            .ORIG x3000
+            LD R6, STACK
+            OUT
+            HALT
+    STACK   .FILL x3000
+            .END
+            .ORIG x0021
+            .FILL HANDLER
+            .END
+            .ORIG x4000
+    HANDLER ADD R1, R1, #1
+            RTI
+            .END
+        "#;
+        let (lc3, output) = run(source, "");
+        assert_eq!(lc3.registers[1], 1);
+        assert_eq!(output, "\n--- halting the LC-3 ---\n");
+    }
+
+    #[test]
+    fn device_registers_drive_io() {
+        // Poll the keyboard until a key is ready, then poll the display until it
+        // is ready and write the key back out; finally stop the clock.
+        let source = r#"
+            .ORIG x3000
+    KWAIT   LDI R1, KBSR
+            BRzp KWAIT
+            LDI R0, KBDR
+    DWAIT   LDI R1, DSR
+            BRzp DWAIT
+            STI R0, DDR
+            AND R0, R0, #0
+            STI R0, MCR
+    KBSR    .FILL xFE00
+    KBDR    .FILL xFE02
+    DSR     .FILL xFE04
+    DDR     .FILL xFE06
+    MCR     .FILL xFFFE
+            .END
+        "#;
+        let (lc3, output) = run(source, "z");
+        assert_eq!(output, "z");
+        assert!(lc3.halted);
+    }
+}
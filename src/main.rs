@@ -22,6 +22,7 @@ mod lc3;
 mod lispy;
 mod monkey;
 mod nomicon;
+mod old;
 mod rune;
 
 use std::env::args;
@@ -37,9 +38,17 @@ enum Run {
     LC3,
     Game,
     Lang,
+    /// Runs a stack language file: `stack <file> [bytes]`.
+    Stack,
+    /// Starts a Forth that reads from stdin: `forth [bytes]`.
+    Forth,
+    /// Runs a Bril program from its JSON: `bril <file> [args...]`.
+    Bril,
     Lispy,
     Rune,
     Monkey,
+    #[strum(serialize = "old", serialize = "vm")]
+    Vm,
     Default,
 }
 use Run::*;
@@ -56,7 +65,7 @@ fn main() {
             return nomicon::main();
         }
         LC3 => {
-            return lc3::main();
+            return lc3::main(args.next());
         }
         Game => {
             return game::main();
@@ -64,6 +73,35 @@ fn main() {
         Lang => {
             return lang::main();
         }
+        Stack => {
+            const USAGE: &str = "usage: stack <file> [bytes], with bytes from 1 to 255";
+            let path = args.next();
+            return match (path, op_bytes(args.next())) {
+                (Some(path), Some(bytes)) => lang::run_file(&path, bytes),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    std::process::exit(2);
+                }
+            };
+        }
+        Forth => {
+            const USAGE: &str = "usage: forth [bytes], with bytes from 1 to 255";
+            return match op_bytes(args.next()) {
+                Some(bytes) => lang::forth::run(bytes),
+                None => {
+                    eprintln!("{}", USAGE);
+                    std::process::exit(2);
+                }
+            };
+        }
+        Bril => {
+            let path = args.next().unwrap_or_else(|| {
+                eprintln!("usage: bril <file> [args...]");
+                std::process::exit(2);
+            });
+            let args: Vec<_> = args.collect();
+            return lang::bril::run_file(&path, &args);
+        }
         Lispy => {
             return lispy::main();
         }
@@ -75,6 +113,9 @@ fn main() {
         Monkey => {
             return monkey::main();
         }
+        Vm => {
+            return old::vm::main();
+        }
         Default => {
             println!("Running default main");
         }
@@ -122,3 +163,10 @@ fn main() {
         });
     }
 }
+
+/// Reads how many bytes wide stack machine values are, which defaults to 1.
+fn op_bytes(arg: Option<String>) -> Option<u8> {
+    arg.map_or(Some(1), |bytes| {
+        bytes.parse().ok().filter(|&bytes| bytes != 0)
+    })
+}
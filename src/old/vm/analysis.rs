@@ -0,0 +1,517 @@
+//! Static analysis of VM programs: liveness, reaching definitions and
+//! constant propagation, on top of `lang::dataflow`.
+//!
+//! Jumps go to the address in a register, so to build a control-flow graph
+//! their targets are worked out from the code before them: the assembler's
+//! `load $r @label` followed by `jmp $r` jumps to `label`. Jumps whose targets
+//! can't be worked out like that are treated as leaving the program, except
+//! that liveness takes them to need every register.
+//!
+//! Calls are worked out the same way. A call ends its block, with edges both
+//! to the code it calls and on to the instruction after it, where a RET comes
+//! back to. RETs aren't matched up with their calls, so they leave the program
+//! like unknown jumps do, and a call is taken to read and write every
+//! register: the code it calls starts out knowing nothing about them.
+
+use super::{assembler::INSTRUCTION_SIZE, instruction::Opcode};
+use crate::lang::{
+    dataflow::{Analysis, Direction},
+    stack_lang::{self, Cfg},
+};
+use std::{collections::BTreeSet, fmt, iter::FromIterator};
+
+const REGISTERS: u8 = 32;
+
+/// A set of registers.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers(u32);
+
+impl Registers {
+    pub const ALL: Self = Self(u32::MAX);
+
+    /// Registers that don't exist are ignored.
+    pub fn insert(&mut self, register: u8) {
+        self.0 |= 1u32.checked_shl(register as u32).unwrap_or(0);
+    }
+
+    pub fn contains(&self, register: u8) -> bool {
+        register < REGISTERS && self.0 & 1 << register != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = u8> {
+        (0..REGISTERS).filter(move |&r| self.contains(r))
+    }
+}
+
+impl FromIterator<u8> for Registers {
+    fn from_iter<I: IntoIterator<Item = u8>>(iter: I) -> Self {
+        let mut registers = Self::default();
+        for register in iter {
+            registers.insert(register);
+        }
+        registers
+    }
+}
+
+impl fmt::Debug for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// A decoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Op {
+    pub opcode: Opcode,
+    pub operands: [u8; 3],
+    /// Where a jump or call goes, as an instruction index, if it's known.
+    pub target: Option<usize>,
+}
+
+impl Op {
+    /// The registers the instruction reads. A CALL or RET hands control to
+    /// code that could read any of them.
+    pub fn uses(&self) -> Registers {
+        use Opcode::*;
+        let [a, b, _] = self.operands;
+        match self.opcode {
+            ADD | SUB | MUL | DIV | EQ | NEQ | GT | LT | GTE | LTE | STOREM | SEND => {
+                Registers::from_iter(vec![a, b])
+            }
+            JMP | JEQ | JNEQ | ALOC | INC | DEC | PUSH => Registers::from_iter(vec![a]),
+            LOADM => Registers::from_iter(vec![b]),
+            CALL | RET => Registers::ALL,
+            HLT | LOAD | JMPF | JMPB | POP | RECV | IGL => Registers::default(),
+        }
+    }
+
+    /// The registers the instruction writes. A CALL could write any of them.
+    pub fn defs(&self) -> Registers {
+        use Opcode::*;
+        let [a, _, c] = self.operands;
+        match self.opcode {
+            ADD | SUB | MUL | DIV => Registers::from_iter(vec![c]),
+            LOAD | INC | DEC | POP | LOADM | RECV => Registers::from_iter(vec![a]),
+            CALL => Registers::ALL,
+            _ => Registers::default(),
+        }
+    }
+
+    /// The immediate operand of a LOAD.
+    fn immediate(&self) -> u16 {
+        u16::from_be_bytes([self.operands[1], self.operands[2]])
+    }
+
+    /// Whether this is a jump to a register, whose target has to be worked
+    /// out.
+    fn is_register_jump(&self) -> bool {
+        matches!(self.opcode, Opcode::JMP | Opcode::JEQ | Opcode::JNEQ)
+    }
+}
+
+impl stack_lang::Instruction for Op {
+    fn is_control(&self) -> bool {
+        use Opcode::*;
+        matches!(
+            self.opcode,
+            JMP | JMPF | JMPB | JEQ | JNEQ | CALL | RET | HLT | IGL
+        )
+    }
+
+    fn jump_targets(&self, _: usize) -> Vec<usize> {
+        self.target.into_iter().collect()
+    }
+
+    fn falls_through(&self) -> bool {
+        use Opcode::*;
+        match self.opcode {
+            JEQ | JNEQ | CALL => true,
+            _ => !stack_lang::Instruction::is_control(self),
+        }
+    }
+}
+
+/// Decodes a program, working out where its jumps and calls go. Any partial
+/// instruction at the end is left off.
+pub fn decode(program: &[u8]) -> Vec<Op> {
+    let mut ops: Vec<_> = program
+        .chunks_exact(INSTRUCTION_SIZE)
+        .map(|bytes| Op {
+            opcode: Opcode::from(bytes[0]),
+            operands: [bytes[1], bytes[2], bytes[3]],
+            target: None,
+        })
+        .collect();
+    for i in 0..ops.len() {
+        let op = ops[i];
+        // Relative jumps are from just after their operand.
+        let from = i * INSTRUCTION_SIZE + 2;
+        let distance = op.operands[0] as usize;
+        let address = match op.opcode {
+            Opcode::JMPF => Some(from + distance),
+            Opcode::JMPB => from.checked_sub(distance),
+            Opcode::JMP | Opcode::JEQ | Opcode::JNEQ | Opcode::CALL => {
+                loaded_address(&ops[..i], op.operands[0])
+            }
+            _ => None,
+        };
+        // Jumps into the middle of an instruction aren't followed.
+        ops[i].target = address
+            .map(|address| (address, address / INSTRUCTION_SIZE))
+            .filter(|&(address, target)| target * INSTRUCTION_SIZE == address)
+            .map(|(_, target)| target)
+            .filter(|&target| target < ops.len());
+    }
+    ops
+}
+
+/// The address LOADed into `register` in the straight-line code at the end
+/// of `ops`, if that's the last thing to have written it.
+fn loaded_address(ops: &[Op], register: u8) -> Option<usize> {
+    for op in ops.iter().rev() {
+        if stack_lang::Instruction::is_control(op) {
+            return None;
+        }
+        if op.defs().contains(register) {
+            return match op.opcode {
+                Opcode::LOAD => Some(op.immediate() as usize),
+                _ => None,
+            };
+        }
+    }
+    None
+}
+
+pub fn cfg(program: &[u8]) -> Cfg<Op> {
+    Cfg::new(&decode(program))
+}
+
+/// Which registers might be read before they're next written.
+pub struct Liveness;
+
+impl Analysis<Op> for Liveness {
+    type Fact = Registers;
+
+    const DIRECTION: Direction = Direction::Backward;
+
+    /// Nothing reads the registers once the program halts.
+    fn boundary(&self) -> Registers {
+        Registers::default()
+    }
+
+    fn bottom(&self) -> Registers {
+        Registers::default()
+    }
+
+    fn join(&self, into: &mut Registers, other: &Registers) {
+        *into = into.union(*other);
+    }
+
+    fn transfer(&self, op: &Op, _: usize, live: &mut Registers) {
+        if op.is_register_jump() && op.target.is_none() {
+            // It could be going anywhere.
+            *live = Registers::ALL;
+        }
+        *live = live.difference(op.defs()).union(op.uses());
+    }
+}
+
+/// Which writes to registers might not have been overwritten yet, as
+/// `(register, index of the instruction)` pairs.
+pub struct ReachingDefinitions;
+
+impl Analysis<Op> for ReachingDefinitions {
+    type Fact = BTreeSet<(u8, usize)>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn bottom(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        into.extend(other);
+    }
+
+    fn transfer(&self, op: &Op, index: usize, definitions: &mut Self::Fact) {
+        let defs = op.defs();
+        if !defs.is_empty() {
+            definitions.retain(|&(register, _)| !defs.contains(register));
+            definitions.extend(defs.iter().map(|register| (register, index)));
+        }
+    }
+}
+
+/// What's known about the value of a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    /// No path has got here yet.
+    Undefined,
+    Constant(i32),
+    /// Different paths disagree, or it isn't known at all.
+    Unknown,
+}
+
+impl Value {
+    fn join(self, other: Self) -> Self {
+        match (self, other) {
+            (Value::Undefined, x) | (x, Value::Undefined) => x,
+            (a, b) if a == b => a,
+            _ => Value::Unknown,
+        }
+    }
+}
+
+/// Which registers hold the same value however the program got there.
+pub struct ConstantPropagation;
+
+impl Analysis<Op> for ConstantPropagation {
+    type Fact = [Value; REGISTERS as usize];
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    /// VMs start with every register zeroed.
+    fn boundary(&self) -> Self::Fact {
+        [Value::Constant(0); REGISTERS as usize]
+    }
+
+    fn bottom(&self) -> Self::Fact {
+        [Value::Undefined; REGISTERS as usize]
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        for (a, &b) in into.iter_mut().zip(other) {
+            *a = a.join(b);
+        }
+    }
+
+    fn transfer(&self, op: &Op, _: usize, values: &mut Self::Fact) {
+        use Opcode::*;
+        let [a, b, c] = op.operands;
+        let get = |r: u8| values.get(r as usize).copied().unwrap_or(Value::Unknown);
+        let (value, register) = match op.opcode {
+            LOAD => (Value::Constant(op.immediate() as i32), a),
+            ADD | SUB | MUL | DIV => {
+                let value = match (get(a), get(b)) {
+                    (Value::Constant(x), Value::Constant(y)) => match op.opcode {
+                        ADD => Value::Constant(x.wrapping_add(y)),
+                        SUB => Value::Constant(x.wrapping_sub(y)),
+                        MUL => Value::Constant(x.wrapping_mul(y)),
+                        // Dividing by zero faults, so nothing comes after.
+                        _ if y == 0 => Value::Undefined,
+                        _ => Value::Constant(x.wrapping_div(y)),
+                    },
+                    (Value::Undefined, _) | (_, Value::Undefined) => Value::Undefined,
+                    _ => Value::Unknown,
+                };
+                (value, c)
+            }
+            INC | DEC => {
+                let value = match get(a) {
+                    Value::Constant(x) if op.opcode == INC => Value::Constant(x.wrapping_add(1)),
+                    Value::Constant(x) => Value::Constant(x.wrapping_sub(1)),
+                    value => value,
+                };
+                (value, a)
+            }
+            POP | LOADM | RECV => (Value::Unknown, a),
+            CALL => {
+                *values = [Value::Unknown; REGISTERS as usize];
+                return;
+            }
+            _ => return,
+        };
+        if let Some(slot) = values.get_mut(register as usize) {
+            *slot = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lang::dataflow::solve, old::vm::assembler::assemble};
+
+    /// Sums 10 down to 1 into $1.
+    const SUM: &str = "
+            load $0 #10
+            load $1 #0
+            load $2 #1
+        loop:
+            add $1 $0 $1
+            sub $0 $2 $0
+            load $3 #0
+            neq $0 $3
+            load $4 @loop
+            jeq $4
+            hlt
+    ";
+
+    fn registers(registers: &[u8]) -> Registers {
+        registers.iter().copied().collect()
+    }
+
+    /// The fact before each instruction of `source`.
+    fn analyse<A: Analysis<Op>>(source: &str, analysis: A) -> Vec<A::Fact> {
+        let cfg = cfg(&assemble(source).unwrap());
+        solve(&cfg, &analysis).before_each(&cfg, &analysis)
+    }
+
+    #[test]
+    fn works_out_jump_targets() {
+        let ops = decode(&assemble(SUM).unwrap());
+        assert_eq!(ops[8].opcode, Opcode::JEQ);
+        assert_eq!(ops[8].target, Some(3));
+
+        let ops = decode(&assemble("jmpf @end\nload $0 #1\nend: jmpb @end\nhlt").unwrap());
+        assert_eq!((ops[0].target, ops[2].target), (Some(2), Some(2)));
+        // Only a LOAD right before the jump says where it goes.
+        let ops = decode(&assemble("load $0 #4\ninc $0\njmp $0\nload $1 #0\ncall $1").unwrap());
+        assert_eq!((ops[2].target, ops[4].target), (None, Some(0)));
+
+        let cfg = cfg(&assemble(SUM).unwrap());
+        let starts: Vec<_> = cfg.blocks.iter().map(|block| block.start).collect();
+        assert_eq!(starts, vec![0, 3, 9]);
+        assert_eq!(cfg.successors(1), &[1, 2]);
+        assert_eq!(cfg.loops()[0].header, 1);
+    }
+
+    #[test]
+    fn finds_live_registers() {
+        let live = analyse(SUM, Liveness);
+        assert_eq!(live[0], registers(&[]));
+        assert_eq!(live[2], registers(&[0, 1]));
+        assert_eq!(live[3], registers(&[0, 1, 2]));
+        assert_eq!(live[6], registers(&[0, 1, 2, 3]));
+        assert_eq!(live[8], registers(&[0, 1, 2, 4]));
+        // The sum's never read, so isn't live once the loop's over.
+        assert_eq!(live[9], registers(&[]));
+
+        // A jump that could go anywhere needs everything.
+        let live = analyse("load $0 #4\ninc $0\njmp $0", Liveness);
+        assert_eq!(live[2], Registers::ALL);
+        assert_eq!(live[0], Registers::ALL.difference(registers(&[0])));
+    }
+
+    #[test]
+    fn finds_reaching_definitions() {
+        let reaching = analyse(SUM, ReachingDefinitions);
+        let of = |i: usize, register| -> Vec<_> {
+            reaching[i]
+                .iter()
+                .filter(|&&(r, _)| r == register)
+                .map(|&(_, index)| index)
+                .collect()
+        };
+        // The loop sees the definitions from before it, and from the last
+        // time round.
+        assert_eq!(of(3, 0), vec![0, 4]);
+        assert_eq!(of(3, 1), vec![1, 3]);
+        assert_eq!(of(3, 2), vec![2]);
+        assert_eq!(of(3, 4), vec![7]);
+        assert_eq!(of(9, 1), vec![3]);
+        assert!(reaching[0].is_empty());
+    }
+
+    #[test]
+    fn propagates_constants() {
+        let values = analyse(SUM, ConstantPropagation);
+        assert_eq!(values[0][0], Value::Constant(0));
+        assert_eq!(values[3][0], Value::Unknown);
+        assert_eq!(values[3][2], Value::Constant(1));
+        // Zero to begin with, and zero every time round.
+        assert_eq!(values[3][3], Value::Constant(0));
+        // Zero to begin with, but then the loop's address.
+        assert_eq!(values[3][4], Value::Unknown);
+        assert_eq!(values[9][4], Value::Constant(12));
+
+        let values = analyse(
+            "
+                load $0 #6
+                load $1 #7
+                mul $0 $1 $2
+                dec $2
+                load $3 #0
+                div $2 $3 $4
+                hlt
+            ",
+            ConstantPropagation,
+        );
+        assert_eq!(values[4][2], Value::Constant(41));
+        // Nothing comes after dividing by zero.
+        assert_eq!(values[6][4], Value::Undefined);
+
+        // Paths that agree keep the constant.
+        let values = analyse(
+            "
+                load $0 #1
+                load $9 @else
+                eq $0 $0
+                jeq $9
+                load $1 #5
+                load $2 #1
+                load $9 @end
+                jmp $9
+            else:
+                load $1 #5
+                load $2 #2
+            end:
+                recv $3
+                hlt
+            ",
+            ConstantPropagation,
+        );
+        assert_eq!(values[10][1], Value::Constant(5));
+        assert_eq!(values[10][2], Value::Unknown);
+        assert_eq!(values[11][3], Value::Unknown);
+    }
+
+    /// Calls a subroutine that sets $2.
+    const CALL: &str = "
+            load $1 @f
+            call $1
+            hlt
+        f:
+            load $2 #5
+            ret
+    ";
+
+    #[test]
+    fn follows_calls() {
+        let cfg = cfg(&assemble(CALL).unwrap());
+        let starts: Vec<_> = cfg.blocks.iter().map(|block| block.start).collect();
+        assert_eq!(starts, vec![0, 2, 3]);
+        assert_eq!(cfg.successors(0), &[2, 1]);
+
+        // The subroutine knows nothing about the registers it was called
+        // with, but does know what it sets.
+        let values = analyse(CALL, ConstantPropagation);
+        assert_eq!(values[3][2], Value::Unknown);
+        assert_eq!(values[4][2], Value::Constant(5));
+        assert_eq!(values[2][1], Value::Unknown);
+
+        let reaching = analyse(CALL, ReachingDefinitions);
+        assert!(reaching[3].contains(&(1, 1)));
+        assert!(reaching[4].contains(&(2, 3)));
+        assert!(!reaching[4].contains(&(2, 1)));
+
+        let live = analyse(CALL, Liveness);
+        assert_eq!(live[3], Registers::ALL.difference(registers(&[2])));
+    }
+}
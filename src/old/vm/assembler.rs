@@ -0,0 +1,357 @@
+//! A textual assembler for the VM's bytecode.
+//!
+//! Every instruction assembles to four bytes: the opcode followed by its
+//! operands, padded with zeroes.
+//!
+//! ```text
+//! ; Count $0 up to 10.
+//!         load $0 #0
+//!         load $1 #1
+//!         load $2 #10
+//!         load $3 @done    ; labels are addresses, loaded like any number
+//! loop:   add $0 $1 $0
+//!         eq $0 $2
+//!         jeq $3           ; jmp and jeq jump to the address in a register
+//!         jmpb @loop       ; jmpf and jmpb can take a label directly
+//! done:   hlt
+//! ```
+//!
+//! Registers are written `$0` to `$31`, immediates `#500`, and label
+//! references `@name`.
+
+use super::instruction::Opcode;
+use std::{collections::HashMap, convert::TryFrom, fmt};
+
+/// The size of every assembled instruction.
+pub const INSTRUCTION_SIZE: usize = 4;
+
+const REGISTERS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownOpcode(String),
+    BadRegister(String),
+    BadImmediate(String),
+    /// An immediate or jump distance that doesn't fit in its operand.
+    OutOfRange(String),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    WrongOperandCount {
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use AsmErrorKind::*;
+        match self {
+            UnknownOpcode(op) => write!(f, "unknown opcode `{}`", op),
+            BadRegister(reg) => write!(f, "`{}` is not a register ($0 to $31)", reg),
+            BadImmediate(imm) => write!(f, "`{}` is not an immediate (like #10)", imm),
+            OutOfRange(operand) => write!(f, "`{}` is out of range", operand),
+            UndefinedLabel(label) => write!(f, "undefined label `{}`", label),
+            DuplicateLabel(label) => write!(f, "label `{}` is already defined", label),
+            WrongOperandCount { expected, found } => {
+                write!(f, "expected {} operands, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+/// What an operand is and how many bytes it takes up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Operand {
+    Register,
+    /// A two-byte immediate or label address.
+    Immediate,
+    /// A one-byte jump distance, measured from just after the operand.
+    Forward,
+    Backward,
+}
+
+pub(super) fn operands(opcode: Opcode) -> &'static [Operand] {
+    use Opcode::*;
+    use Operand::*;
+    match opcode {
+        HLT | RET | IGL => &[],
+        LOAD => &[Register, Immediate],
+        ADD | SUB | MUL | DIV => &[Register, Register, Register],
+        JMP | JEQ | JNEQ | ALOC | INC | DEC | PUSH | POP | CALL | RECV => &[Register],
+        JMPF => &[Forward],
+        JMPB => &[Backward],
+        EQ | NEQ | GT | LT | GTE | LTE | LOADM | STOREM | SEND => &[Register, Register],
+    }
+}
+
+struct Statement<'a> {
+    line: usize,
+    address: usize,
+    opcode: Opcode,
+    operands: Vec<&'a str>,
+}
+
+/// Assembles `source` into a program for the VM, reporting every error found.
+pub fn assemble(source: &str) -> Result<Vec<u8>, Vec<AsmError>> {
+    assemble_at(source, 0)
+}
+
+/// Assembles `source` to be placed `origin` bytes into a program, which is
+/// where its labels will point.
+pub fn assemble_at(source: &str, origin: usize) -> Result<Vec<u8>, Vec<AsmError>> {
+    let mut errors = Vec::new();
+    let mut labels = HashMap::new();
+    let mut statements = Vec::new();
+
+    // The first pass finds every label, so that jumps can refer forwards.
+    for (i, line) in source.lines().enumerate() {
+        let line_no = i + 1;
+        let code = line.split(';').next().unwrap_or_default();
+        let mut words: Vec<_> = code.split_whitespace().collect();
+        if let Some(label) = words.first().and_then(|word| word.strip_suffix(':')) {
+            let address = origin + statements.len() * INSTRUCTION_SIZE;
+            if labels.insert(label, address).is_some() {
+                errors.push(AsmError {
+                    line: line_no,
+                    kind: AsmErrorKind::DuplicateLabel(label.to_string()),
+                });
+            }
+            words.remove(0);
+        }
+        let (op, operands) = match words.split_first() {
+            Some((op, operands)) => (op, operands),
+            None => continue,
+        };
+        match op.to_lowercase().parse::<Opcode>() {
+            Ok(opcode) => statements.push(Statement {
+                line: line_no,
+                address: origin + statements.len() * INSTRUCTION_SIZE,
+                opcode,
+                operands: operands.to_vec(),
+            }),
+            Err(_) => errors.push(AsmError {
+                line: line_no,
+                kind: AsmErrorKind::UnknownOpcode(op.to_string()),
+            }),
+        }
+    }
+
+    let mut program = Vec::with_capacity(statements.len() * INSTRUCTION_SIZE);
+    for stmt in &statements {
+        match encode(stmt, &labels) {
+            Ok(bytes) => program.extend_from_slice(&bytes),
+            Err(kind) => errors.push(AsmError {
+                line: stmt.line,
+                kind,
+            }),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        errors.sort_by_key(|e| e.line);
+        Err(errors)
+    }
+}
+
+fn encode(
+    stmt: &Statement<'_>,
+    labels: &HashMap<&str, usize>,
+) -> Result<[u8; INSTRUCTION_SIZE], AsmErrorKind> {
+    let expected = operands(stmt.opcode);
+    if stmt.operands.len() != expected.len() {
+        return Err(AsmErrorKind::WrongOperandCount {
+            expected: expected.len(),
+            found: stmt.operands.len(),
+        });
+    }
+
+    let mut bytes = [0; INSTRUCTION_SIZE];
+    bytes[0] = stmt.opcode as u8;
+    let mut i = 1;
+    for (&kind, &text) in expected.iter().zip(&stmt.operands) {
+        match kind {
+            Operand::Register => {
+                bytes[i] = register(text)?;
+                i += 1;
+            }
+            Operand::Immediate => {
+                let value = immediate(text, labels)?;
+                let value =
+                    u16::try_from(value).map_err(|_| AsmErrorKind::OutOfRange(text.to_string()))?;
+                bytes[i..i + 2].copy_from_slice(&value.to_be_bytes());
+                i += 2;
+            }
+            Operand::Forward | Operand::Backward => {
+                // Jumps are relative to the pc after reading the operand.
+                let from = (stmt.address + i + 1) as i64;
+                let distance = match text.strip_prefix('@') {
+                    Some(_) if kind == Operand::Forward => immediate(text, labels)? - from,
+                    Some(_) => from - immediate(text, labels)?,
+                    None => immediate(text, labels)?,
+                };
+                bytes[i] = u8::try_from(distance)
+                    .map_err(|_| AsmErrorKind::OutOfRange(text.to_string()))?;
+                i += 1;
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+fn register(text: &str) -> Result<u8, AsmErrorKind> {
+    text.strip_prefix('$')
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|&n| n < REGISTERS)
+        .map(|n| n as u8)
+        .ok_or_else(|| AsmErrorKind::BadRegister(text.to_string()))
+}
+
+/// A `#number` or `@label`.
+fn immediate(text: &str, labels: &HashMap<&str, usize>) -> Result<i64, AsmErrorKind> {
+    if let Some(label) = text.strip_prefix('@') {
+        return labels
+            .get(label)
+            .map(|&address| address as i64)
+            .ok_or_else(|| AsmErrorKind::UndefinedLabel(label.to_string()));
+    }
+    let digits = text
+        .strip_prefix('#')
+        .ok_or_else(|| AsmErrorKind::BadImmediate(text.to_string()))?;
+    digits.parse::<i64>().map_err(|e| match e.kind() {
+        std::num::IntErrorKind::PosOverflow | std::num::IntErrorKind::NegOverflow => {
+            AsmErrorKind::OutOfRange(text.to_string())
+        }
+        _ => AsmErrorKind::BadImmediate(text.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::old::vm::VM;
+    use Opcode::*;
+
+    fn errors(source: &str) -> Vec<(usize, AsmErrorKind)> {
+        assemble(source)
+            .expect_err("source should not assemble")
+            .into_iter()
+            .map(|e| (e.line, e.kind))
+            .collect()
+    }
+
+    #[test]
+    fn assembles_instructions() {
+        let program = assemble("load $10 #500\nadd $0 $1 $2\n\n  HLT ; done").unwrap();
+        let expected = [
+            [LOAD as u8, 10, 1, 244],
+            [ADD as u8, 0, 1, 2],
+            [HLT as u8, 0, 0, 0],
+        ];
+        assert_eq!(program, expected.concat());
+    }
+
+    #[test]
+    fn resolves_labels() {
+        let source = "
+            jmpf @end
+        start:
+            load $0 @start
+            jmp $0
+        end: jmpb @start
+        ";
+        let program = assemble(source).unwrap();
+        let expected = [
+            [JMPF as u8, 10, 0, 0],
+            [LOAD as u8, 0, 0, 4],
+            [JMP as u8, 0, 0, 0],
+            [JMPB as u8, 10, 0, 0],
+        ];
+        assert_eq!(program, expected.concat());
+    }
+
+    #[test]
+    fn assembled_programs_run() {
+        let source = "
+            load $0 #0
+            load $1 #1
+            load $2 #10
+            load $3 @done
+        loop:
+            add $0 $1 $0
+            eq $0 $2
+            jeq $3
+            jmpb @loop
+        done:
+            hlt
+        ";
+        let mut vm = VM::new();
+        vm.program = assemble(source).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 10);
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        let source = "
+            load $32 #1
+            load $0 #65536
+            add $0 $1
+            frob $0
+            jmpf @nowhere
+        a: hlt
+        a: hlt
+            load $0 500
+        ";
+        assert_eq!(
+            errors(source),
+            vec![
+                (2, AsmErrorKind::BadRegister("$32".to_string())),
+                (3, AsmErrorKind::OutOfRange("#65536".to_string())),
+                (
+                    4,
+                    AsmErrorKind::WrongOperandCount {
+                        expected: 3,
+                        found: 2
+                    }
+                ),
+                (5, AsmErrorKind::UnknownOpcode("frob".to_string())),
+                (6, AsmErrorKind::UndefinedLabel("nowhere".to_string())),
+                (8, AsmErrorKind::DuplicateLabel("a".to_string())),
+                (9, AsmErrorKind::BadImmediate("500".to_string())),
+            ]
+        );
+        assert_eq!(
+            assemble("load $x #1").unwrap_err()[0].to_string(),
+            "line 1: `$x` is not a register ($0 to $31)"
+        );
+    }
+
+    #[test]
+    fn rejects_jumps_that_are_too_far() {
+        let mut source = "jmpf @end\n".to_string();
+        source.push_str(&"hlt\n".repeat(64));
+        source.push_str("end: hlt");
+        assert_eq!(
+            errors(&source),
+            vec![(1, AsmErrorKind::OutOfRange("@end".to_string()))]
+        );
+        assert_eq!(
+            errors("l: jmpf @l"),
+            vec![(1, AsmErrorKind::OutOfRange("@l".to_string()))]
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! The on-disk format for VM programs. All numbers are big-endian:
+//!
+//! ```text
+//! magic     b"RVM\x1A"
+//! version   u8
+//! entry     u32     offset into the code of the first instruction
+//! rodata    u32     length of the read-only data
+//! code      u32     length of the code
+//! <rodata bytes> <code bytes>
+//! ```
+//!
+//! Files are checked thoroughly when loaded, so that a VM never starts
+//! running a program with a malformed instruction in it.
+
+use super::{
+    assembler::{operands, Operand, INSTRUCTION_SIZE},
+    instruction::Opcode,
+    VM,
+};
+use std::{convert::TryInto, fmt, fs, io, path::Path};
+
+pub const MAGIC: &[u8; 4] = b"RVM\x1A";
+pub const VERSION: u8 = 1;
+
+const HEADER_SIZE: usize = 4 + 1 + 4 + 4 + 4;
+const REGISTERS: u8 = 32;
+
+/// A program along with the data it needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytecode {
+    pub entry: u32,
+    /// Loaded at the bottom of the heap, where STOREM can't touch it.
+    pub rodata: Vec<u8>,
+    pub code: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The file ends before the header says it should.
+    Truncated,
+    /// The file carries on after the header says it should have ended.
+    TrailingBytes(usize),
+    /// The code isn't made of whole instructions.
+    MisalignedCode(usize),
+    /// The entry point isn't the start of an instruction.
+    BadEntry(u32),
+    IllegalOpcode {
+        opcode: u8,
+        offset: usize,
+    },
+    BadRegister {
+        register: u8,
+        offset: usize,
+    },
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use FormatError::*;
+        match self {
+            Io(e) => write!(f, "could not read bytecode file: {}", e),
+            BadMagic => write!(f, "not a bytecode file"),
+            UnsupportedVersion(v) => write!(f, "unsupported bytecode version {}", v),
+            Truncated => write!(f, "bytecode file is truncated"),
+            TrailingBytes(n) => write!(f, "bytecode file has {} unexpected trailing bytes", n),
+            MisalignedCode(len) => write!(
+                f,
+                "code is {} bytes, which is not whole {}-byte instructions",
+                len, INSTRUCTION_SIZE
+            ),
+            BadEntry(entry) => write!(f, "entry point {} is not an instruction", entry),
+            IllegalOpcode { opcode, offset } => {
+                write!(f, "illegal opcode {} at offset {}", opcode, offset)
+            }
+            BadRegister { register, offset } => {
+                write!(f, "no register ${} (at offset {})", register, offset)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for FormatError {
+    fn from(e: io::Error) -> Self {
+        FormatError::Io(e)
+    }
+}
+
+impl Bytecode {
+    /// A program with no read-only data that starts at its first instruction.
+    pub fn new(code: Vec<u8>) -> Self {
+        Self {
+            entry: 0,
+            rodata: Vec::new(),
+            code,
+        }
+    }
+
+    /// Parses and validates the raw bytes of a bytecode file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FormatError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(FormatError::Truncated);
+        }
+        if bytes[4] != VERSION {
+            return Err(FormatError::UnsupportedVersion(bytes[4]));
+        }
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+        let entry = u32_at(5);
+        let rodata_len = u32_at(9) as usize;
+        let code_len = u32_at(13) as usize;
+
+        let body = &bytes[HEADER_SIZE..];
+        let len = rodata_len
+            .checked_add(code_len)
+            .ok_or(FormatError::Truncated)?;
+        if body.len() < len {
+            return Err(FormatError::Truncated);
+        }
+        if body.len() > len {
+            return Err(FormatError::TrailingBytes(body.len() - len));
+        }
+        let bytecode = Self {
+            entry,
+            rodata: body[..rodata_len].to_vec(),
+            code: body[rodata_len..].to_vec(),
+        };
+        bytecode.validate()?;
+        Ok(bytecode)
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, FormatError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// The inverse of `from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.rodata.len() + self.code.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.entry.to_be_bytes());
+        bytes.extend_from_slice(&(self.rodata.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.code.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.rodata);
+        bytes.extend_from_slice(&self.code);
+        bytes
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    /// Checks that the code is made of whole, well-formed instructions, and
+    /// that the entry point is one of them.
+    pub fn validate(&self) -> Result<(), FormatError> {
+        let instructions = self.code.chunks_exact(INSTRUCTION_SIZE);
+        if !instructions.remainder().is_empty() {
+            return Err(FormatError::MisalignedCode(self.code.len()));
+        }
+        let mut starts = (0..self.code.len()).step_by(INSTRUCTION_SIZE);
+        if !starts.any(|start| start == self.entry as usize) {
+            return Err(FormatError::BadEntry(self.entry));
+        }
+        for (i, instruction) in instructions.enumerate() {
+            let offset = i * INSTRUCTION_SIZE;
+            let opcode = instruction[0];
+            if Opcode::from(opcode) == Opcode::IGL {
+                return Err(FormatError::IllegalOpcode { opcode, offset });
+            }
+            let mut j = 1;
+            for operand in operands(Opcode::from(opcode)) {
+                match operand {
+                    Operand::Register if instruction[j] >= REGISTERS => {
+                        let register = instruction[j];
+                        return Err(FormatError::BadRegister { register, offset });
+                    }
+                    Operand::Immediate => j += 2,
+                    _ => j += 1,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VM {
+    /// Replaces whatever the VM was running with `bytecode`, ready to run from
+    /// its entry point.
+    pub fn load(&mut self, bytecode: &Bytecode) -> Result<(), FormatError> {
+        bytecode.validate()?;
+        *self = VM::new();
+        self.program = bytecode.code.clone();
+        self.pc = bytecode.entry as usize;
+        self.heap = bytecode.rodata.clone();
+        self.readonly = bytecode.rodata.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::old::vm::{assembler::assemble, VmError};
+
+    fn sample() -> Bytecode {
+        // Skips the first instruction, then reads the second word of rodata.
+        let code = assemble("hlt\nload $0 #4\nloadm $1 $0\nhlt").unwrap();
+        Bytecode {
+            entry: 4,
+            rodata: vec![0, 0, 0, 1, 0, 0, 0, 2],
+            code,
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let bytecode = sample();
+        let bytes = bytecode.to_bytes();
+        assert_eq!(&bytes[..5], b"RVM\x1A\x01");
+        assert_eq!(bytes.len(), HEADER_SIZE + 8 + 16);
+        assert_eq!(Bytecode::from_bytes(&bytes).unwrap(), bytecode);
+    }
+
+    #[test]
+    fn reads_and_writes_files() {
+        let name = format!("old_vm_bytecode_test_{}.rvm", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        sample().write(&path).unwrap();
+        assert_eq!(Bytecode::read(&path).unwrap(), sample());
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(Bytecode::read(&path), Err(FormatError::Io(_))));
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let bytes = sample().to_bytes();
+        let parse = |bytes: &[u8]| Bytecode::from_bytes(bytes).unwrap_err();
+
+        assert!(matches!(parse(b"ELF"), FormatError::BadMagic));
+        assert!(matches!(parse(&bytes[..10]), FormatError::Truncated));
+        assert!(matches!(
+            parse(&bytes[..bytes.len() - 1]),
+            FormatError::Truncated
+        ));
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(matches!(parse(&longer), FormatError::TrailingBytes(1)));
+        let mut newer = bytes;
+        newer[4] = 2;
+        assert!(matches!(parse(&newer), FormatError::UnsupportedVersion(2)));
+    }
+
+    #[test]
+    fn validates_code() {
+        let check = |entry, code: Vec<u8>| {
+            let bytecode = Bytecode {
+                entry,
+                rodata: vec![],
+                code,
+            };
+            Bytecode::from_bytes(&bytecode.to_bytes()).unwrap_err()
+        };
+        let hlt = vec![Opcode::HLT as u8, 0, 0, 0];
+
+        assert!(matches!(
+            check(0, vec![0; 6]),
+            FormatError::MisalignedCode(6)
+        ));
+        assert!(matches!(check(2, hlt.clone()), FormatError::BadEntry(2)));
+        assert!(matches!(check(4, hlt.clone()), FormatError::BadEntry(4)));
+        assert!(matches!(check(0, vec![]), FormatError::BadEntry(0)));
+        assert!(matches!(
+            check(0, [hlt.clone(), vec![200, 0, 0, 0]].concat()),
+            FormatError::IllegalOpcode {
+                opcode: 200,
+                offset: 4
+            }
+        ));
+        // Immediates and padding can be anything.
+        let load = vec![Opcode::LOAD as u8, 1, 255, 255];
+        assert!(Bytecode::new(load).validate().is_ok());
+        let inc = vec![Opcode::INC as u8, 1, 255, 255];
+        assert!(Bytecode::new(inc).validate().is_ok());
+        let err = check(0, vec![Opcode::ADD as u8, 1, 2, 32]);
+        assert_eq!(err.to_string(), "no register $32 (at offset 0)");
+    }
+
+    #[test]
+    fn loads_into_the_vm() {
+        let mut vm = VM::new();
+        vm.registers[5] = 1;
+        vm.load(&sample()).unwrap();
+        assert_eq!(vm.registers[5], 0);
+        assert_eq!(vm.pc, 4);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], 2);
+
+        // Read-only data stays that way.
+        vm.program = assemble("load $0 #4\nstorem $0 $0\nhlt").unwrap();
+        vm.pc = 0;
+        assert_eq!(vm.run(), Err(VmError::ReadOnly { address: 4, pc: 4 }));
+        vm.program = assemble("load $0 #8\naloc $0\nstorem $0 $0\nhlt").unwrap();
+        vm.pc = 0;
+        vm.run().unwrap();
+        assert_eq!(&vm.heap[8..12], &[0, 0, 0, 8]);
+    }
+}
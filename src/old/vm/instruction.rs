@@ -1,4 +1,8 @@
-#[derive(Debug, PartialEq, Clone, Copy)]
+use strum_macros::EnumString;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, EnumString)]
+#[strum(serialize_all = "lowercase")]
 pub enum Opcode {
     HLT,
     LOAD,
@@ -16,6 +20,38 @@ pub enum Opcode {
     /// Jump if Equal: takes one register as an argument, and jumps to the value
     /// at that register if the equality flag is true.
     JEQ,
+    /// The other comparisons set the equality flag just like EQ, comparing the
+    /// first register to the second as signed numbers.
+    NEQ,
+    GT,
+    LT,
+    GTE,
+    LTE,
+    /// Jump if Not Equal: JEQ, but for when the equality flag is false.
+    JNEQ,
+    /// Grows the heap by the number of bytes in the given register.
+    ALOC,
+    INC,
+    DEC,
+    PUSH,
+    POP,
+    /// Pushes the address of the next instruction and jumps to the value at the
+    /// given register; RET pops it back off again.
+    CALL,
+    RET,
+    /// Loads the (big-endian) 4-byte word at the heap address in the second
+    /// register into the first. LOAD itself loads an immediate.
+    LOADM,
+    /// Stores the first register as a 4-byte word at the heap address in the
+    /// second.
+    STOREM,
+    /// Sends the value in the second register to the VM whose id is in the
+    /// first. Only does anything when run by a `Scheduler`.
+    SEND,
+    /// Takes the oldest message sent to this VM and puts it in the given
+    /// register, waiting for one if there isn't any yet.
+    RECV,
+    #[strum(disabled)]
     IGL,
 }
 
@@ -34,6 +70,23 @@ impl From<u8> for Opcode {
             8 => JMPB,
             9 => EQ,
             10 => JEQ,
+            11 => NEQ,
+            12 => GT,
+            13 => LT,
+            14 => GTE,
+            15 => LTE,
+            16 => JNEQ,
+            17 => ALOC,
+            18 => INC,
+            19 => DEC,
+            20 => PUSH,
+            21 => POP,
+            22 => CALL,
+            23 => RET,
+            24 => LOADM,
+            25 => STOREM,
+            26 => SEND,
+            27 => RECV,
             _ => IGL,
         }
     }
@@ -1,10 +1,102 @@
+pub mod analysis;
+pub mod assembler;
+pub mod bytecode;
 mod instruction;
+pub mod repl;
+pub mod scheduler;
 
 use instruction::{Instruction, Opcode};
+use std::{collections::VecDeque, convert::TryFrom, fmt, io};
 
-pub fn main() {}
+pub fn main() {
+    repl::start(io::stdin(), io::stdout());
+}
+
+/// The most bytes the heap can grow to.
+pub const MAX_HEAP: usize = 1 << 24;
+
+/// Everything that can go wrong while running a program. Each error records
+/// the pc of the instruction that caused it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    IllegalOpcode {
+        opcode: u8,
+        pc: usize,
+    },
+    DivideByZero {
+        pc: usize,
+    },
+    /// The program ran off its end, or jumped outside of itself.
+    PcOutOfBounds {
+        pc: usize,
+    },
+    BadRegister {
+        register: u8,
+        pc: usize,
+    },
+    /// An ALOC of a negative number of bytes, or that would grow the heap
+    /// past `MAX_HEAP`.
+    BadAllocation {
+        bytes: i32,
+        pc: usize,
+    },
+    /// A LOADM or STOREM outside of the heap.
+    HeapOutOfBounds {
+        address: usize,
+        pc: usize,
+    },
+    /// A POP or RET with nothing on the stack.
+    StackUnderflow {
+        pc: usize,
+    },
+    /// A STOREM to the program's read-only data.
+    ReadOnly {
+        address: usize,
+        pc: usize,
+    },
+    /// A RECV with no message to receive. The pc is left on the RECV, so that
+    /// it can be tried again once a message arrives.
+    WouldBlock {
+        pc: usize,
+    },
+    /// A SEND to a VM that the scheduler doesn't have.
+    NoSuchVm {
+        id: i32,
+        pc: usize,
+    },
+}
 
-#[derive(Debug)]
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use VmError::*;
+        match self {
+            IllegalOpcode { opcode, pc } => write!(f, "illegal opcode {} at pc {}", opcode, pc),
+            DivideByZero { pc } => write!(f, "division by zero at pc {}", pc),
+            PcOutOfBounds { pc } => write!(f, "pc {} is outside of the program", pc),
+            BadRegister { register, pc } => write!(f, "no register ${} (at pc {})", register, pc),
+            BadAllocation { bytes, pc } => {
+                write!(f, "can't allocate {} bytes (at pc {})", bytes, pc)
+            }
+            HeapOutOfBounds { address, pc } => {
+                write!(
+                    f,
+                    "heap address {} is out of bounds (at pc {})",
+                    address, pc
+                )
+            }
+            StackUnderflow { pc } => write!(f, "stack underflow at pc {}", pc),
+            ReadOnly { address, pc } => {
+                write!(f, "heap address {} is read-only (at pc {})", address, pc)
+            }
+            WouldBlock { pc } => write!(f, "no message to receive at pc {}", pc),
+            NoSuchVm { id, pc } => write!(f, "no VM with id {} (at pc {})", id, pc),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+#[derive(Debug, Clone)]
 pub struct VM {
     /// Array that simulates having hardware registers.
     registers: [i32; 32],
@@ -16,9 +108,19 @@ pub struct VM {
     remainder: u32,
     /// Contains the result of the last equality comparison operation
     equal_flag: bool,
+    /// Byte-addressed memory, grown with ALOC.
+    heap: Vec<u8>,
+    /// Shared by PUSH/POP and the return addresses of CALL/RET.
+    stack: Vec<i32>,
+    /// How many bytes at the bottom of the heap are read-only data.
+    readonly: usize,
+    /// Messages waiting to be RECVed.
+    inbox: VecDeque<i32>,
+    /// Messages SENT, as (recipient, value) pairs, waiting for the scheduler
+    /// to deliver them.
+    outbox: Vec<(i32, i32)>,
 }
 
-/// Panics on programs that do not halt.
 impl VM {
     pub fn new() -> Self {
         Self {
@@ -27,113 +129,265 @@ impl VM {
             pc: 0,
             remainder: 0,
             equal_flag: false,
+            heap: vec![],
+            stack: vec![],
+            readonly: 0,
+            inbox: VecDeque::new(),
+            outbox: vec![],
         }
     }
 
-    pub fn run(&mut self) {
-        /// Continue running our VM until an instruction tells us to stop.
-        while self.execute_instruction() {}
+    /// Runs until the program halts, or faults.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        // Continue running our VM until an instruction tells us to stop.
+        while self.step()? {}
+        Ok(())
     }
 
     /// Executes a single instruction, for more controlled execution of the VM.
-    fn execute_instruction(&mut self) -> bool {
-        match self.decode_opcode() {
+    /// Returns false once the program has halted.
+    pub fn step(&mut self) -> Result<bool, VmError> {
+        // Running off the end is reported where it happened; everything else
+        // at the faulting instruction.
+        self.execute_instruction(self.pc)
+    }
+
+    fn execute_instruction(&mut self, pc: usize) -> Result<bool, VmError> {
+        match self.decode_opcode()? {
             Opcode::HLT => {
-                println!("HLT encountered");
-                return false;
+                return Ok(false);
             }
             Opcode::IGL => {
-                println!("Unrecognized opcode found! Terminating!");
-                return false;
+                let opcode = self.program[self.pc - 1];
+                return Err(VmError::IllegalOpcode { opcode, pc });
             }
             Opcode::LOAD => {
-                let register = self.next_byte() as usize;
-                let number = self.next_u16();
-                self.register_store(register, number);
+                let register = self.next_byte()?;
+                let number = self.next_u16()?;
+                self.register_store(register, number, pc)?;
             }
             Opcode::ADD => {
-                let n1 = self.next_byte_as_register_lookup();
-                let n2 = self.next_byte_as_register_lookup();
-                self.next_byte_as_register_store(n1 + n2);
+                let n1 = self.next_byte_as_register_lookup(pc)?;
+                let n2 = self.next_byte_as_register_lookup(pc)?;
+                self.next_byte_as_register_store(n1.wrapping_add(n2), pc)?;
             }
             Opcode::SUB => {
-                let n1 = self.next_byte_as_register_lookup();
-                let n2 = self.next_byte_as_register_lookup();
-                self.next_byte_as_register_store(n1 - n2);
+                let n1 = self.next_byte_as_register_lookup(pc)?;
+                let n2 = self.next_byte_as_register_lookup(pc)?;
+                self.next_byte_as_register_store(n1.wrapping_sub(n2), pc)?;
             }
             Opcode::MUL => {
-                let n1 = self.next_byte_as_register_lookup();
-                let n2 = self.next_byte_as_register_lookup();
-                self.next_byte_as_register_store(n1 * n2);
+                let n1 = self.next_byte_as_register_lookup(pc)?;
+                let n2 = self.next_byte_as_register_lookup(pc)?;
+                self.next_byte_as_register_store(n1.wrapping_mul(n2), pc)?;
             }
             Opcode::DIV => {
-                let n1 = self.next_byte_as_register_lookup();
-                let n2 = self.next_byte_as_register_lookup();
-                self.next_byte_as_register_store(n1 / n2);
-                self.remainder = (n1 % n2) as u32;
+                let n1 = self.next_byte_as_register_lookup(pc)?;
+                let n2 = self.next_byte_as_register_lookup(pc)?;
+                if n2 == 0 {
+                    return Err(VmError::DivideByZero { pc });
+                }
+                self.next_byte_as_register_store(n1.wrapping_div(n2), pc)?;
+                self.remainder = n1.wrapping_rem(n2) as u32;
             }
             Opcode::JMP => {
-                self.pc = self.next_byte_as_register_lookup() as usize;
+                self.pc = self.next_byte_as_register_lookup(pc)? as usize;
             }
             Opcode::JMPF => {
-                self.pc += self.next_byte() as usize;
+                self.pc += self.next_byte()? as usize;
             }
             Opcode::JMPB => {
-                self.pc -= self.next_byte() as usize;
-            }
-            Opcode::EQ => {
-                // Flag if the values at the bytecode specified registers are equal.
-                self.equal_flag =
-                    self.next_byte_as_register_lookup() == self.next_byte_as_register_lookup();
-                // Move pc out of the last instruction byte so that the next
-                // instruction can be processed.
-                self.next_byte();
+                // Jumping back past the start leaves the pc out of bounds.
+                let distance = self.next_byte()? as usize;
+                self.pc = self.pc.wrapping_sub(distance);
             }
+            Opcode::EQ => self.compare(|a, b| a == b, pc)?,
+            Opcode::NEQ => self.compare(|a, b| a != b, pc)?,
+            Opcode::GT => self.compare(|a, b| a > b, pc)?,
+            Opcode::LT => self.compare(|a, b| a < b, pc)?,
+            Opcode::GTE => self.compare(|a, b| a >= b, pc)?,
+            Opcode::LTE => self.compare(|a, b| a <= b, pc)?,
             Opcode::JEQ => {
                 if self.equal_flag {
-                    self.pc = self.next_byte_as_register_lookup() as usize;
+                    self.pc = self.next_byte_as_register_lookup(pc)? as usize;
+                } else {
+                    self.pc += 3;
+                }
+            }
+            Opcode::JNEQ => {
+                if !self.equal_flag {
+                    self.pc = self.next_byte_as_register_lookup(pc)? as usize;
                 } else {
                     self.pc += 3;
                 }
             }
+            Opcode::ALOC => {
+                let bytes = self.next_byte_as_register_lookup(pc)?;
+                let len = usize::try_from(bytes)
+                    .ok()
+                    .and_then(|bytes| self.heap.len().checked_add(bytes))
+                    .filter(|&len| len <= MAX_HEAP)
+                    .ok_or(VmError::BadAllocation { bytes, pc })?;
+                self.heap.resize(len, 0);
+                self.skip_padding(2);
+            }
+            Opcode::INC => {
+                let register = self.next_byte()?;
+                let val = self.register_lookup(register, pc)?;
+                self.register_store(register, val.wrapping_add(1), pc)?;
+                self.skip_padding(2);
+            }
+            Opcode::DEC => {
+                let register = self.next_byte()?;
+                let val = self.register_lookup(register, pc)?;
+                self.register_store(register, val.wrapping_sub(1), pc)?;
+                self.skip_padding(2);
+            }
+            Opcode::PUSH => {
+                let val = self.next_byte_as_register_lookup(pc)?;
+                self.stack.push(val);
+                self.skip_padding(2);
+            }
+            Opcode::POP => {
+                let val = self.pop(pc)?;
+                self.next_byte_as_register_store(val, pc)?;
+                self.skip_padding(2);
+            }
+            Opcode::CALL => {
+                let target = self.next_byte_as_register_lookup(pc)? as usize;
+                self.skip_padding(2);
+                self.stack.push(self.pc as i32);
+                self.pc = target;
+            }
+            Opcode::RET => {
+                self.pc = self.pop(pc)? as usize;
+            }
+            Opcode::LOADM => {
+                let register = self.next_byte()?;
+                let address = self.next_byte_as_register_lookup(pc)? as usize;
+                let mut word = [0; 4];
+                word.copy_from_slice(self.heap_word(address, pc)?);
+                self.register_store(register, i32::from_be_bytes(word), pc)?;
+                self.skip_padding(1);
+            }
+            Opcode::STOREM => {
+                let val = self.next_byte_as_register_lookup(pc)?;
+                let address = self.next_byte_as_register_lookup(pc)? as usize;
+                if address < self.readonly {
+                    return Err(VmError::ReadOnly { address, pc });
+                }
+                self.heap_word(address, pc)?
+                    .copy_from_slice(&val.to_be_bytes());
+                self.skip_padding(1);
+            }
+            Opcode::SEND => {
+                let to = self.next_byte_as_register_lookup(pc)?;
+                let val = self.next_byte_as_register_lookup(pc)?;
+                self.outbox.push((to, val));
+                self.skip_padding(1);
+            }
+            Opcode::RECV => {
+                let register = self.next_byte()?;
+                match self.inbox.pop_front() {
+                    Some(val) => {
+                        self.register_store(register, val, pc)?;
+                        self.skip_padding(2);
+                    }
+                    None => {
+                        // Back to the start of the RECV, to try again later.
+                        self.pc -= 2;
+                        return Err(VmError::WouldBlock { pc });
+                    }
+                }
+            }
         }
-        true
+        Ok(true)
+    }
+
+    /// Sets the equality flag to the result of comparing the next two
+    /// registers.
+    fn compare(&mut self, op: impl Fn(i32, i32) -> bool, pc: usize) -> Result<(), VmError> {
+        let n1 = self.next_byte_as_register_lookup(pc)?;
+        let n2 = self.next_byte_as_register_lookup(pc)?;
+        self.equal_flag = op(n1, n2);
+        // Move pc out of the last instruction byte so that the next
+        // instruction can be processed.
+        self.skip_padding(1);
+        Ok(())
+    }
+
+    /// Moves past the unused bytes at the end of an instruction.
+    fn skip_padding(&mut self, bytes: usize) {
+        self.pc += bytes;
+    }
+
+    fn pop(&mut self, pc: usize) -> Result<i32, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow { pc })
+    }
+
+    /// The 4-byte word at `address` on the heap.
+    fn heap_word(&mut self, address: usize, pc: usize) -> Result<&mut [u8], VmError> {
+        self.heap
+            .get_mut(address..address.saturating_add(4))
+            .ok_or(VmError::HeapOutOfBounds { address, pc })
     }
 
     /// Reads the next byte as a register, and stores the given value at that
     /// register.
-    fn next_byte_as_register_store(&mut self, val: impl Into<i32>) {
-        let register = self.next_byte();
-        self.register_store(register, val);
+    fn next_byte_as_register_store(
+        &mut self,
+        val: impl Into<i32>,
+        pc: usize,
+    ) -> Result<(), VmError> {
+        let register = self.next_byte()?;
+        self.register_store(register, val, pc)
     }
 
     /// Stores the value at the given register.
-    fn register_store(&mut self, register: impl Into<usize>, val: impl Into<i32>) {
-        self.registers[register.into()] = val.into();
+    fn register_store(
+        &mut self,
+        register: u8,
+        val: impl Into<i32>,
+        pc: usize,
+    ) -> Result<(), VmError> {
+        *self
+            .registers
+            .get_mut(register as usize)
+            .ok_or(VmError::BadRegister { register, pc })? = val.into();
+        Ok(())
+    }
+
+    fn register_lookup(&self, register: u8, pc: usize) -> Result<i32, VmError> {
+        self.registers
+            .get(register as usize)
+            .copied()
+            .ok_or(VmError::BadRegister { register, pc })
     }
 
     /// Reads the next byte as a register, and looks up the value at that
     /// register.
-    fn next_byte_as_register_lookup(&mut self) -> i32 {
-        self.registers[self.next_byte() as usize]
+    fn next_byte_as_register_lookup(&mut self, pc: usize) -> Result<i32, VmError> {
+        let register = self.next_byte()?;
+        self.register_lookup(register, pc)
     }
 
     /// Reads a byte at the current counter, advancing the program counter.
-    /// Panics if program counter exceeds the length of our program (which
-    /// indicates an invalid program).
-    fn next_byte(&mut self) -> u8 {
-        let ret = self.program[self.pc]; // This may panic.
+    fn next_byte(&mut self) -> Result<u8, VmError> {
+        let ret = *self
+            .program
+            .get(self.pc)
+            .ok_or(VmError::PcOutOfBounds { pc: self.pc })?;
         self.pc += 1;
-        ret
+        Ok(ret)
     }
 
-    fn next_u16(&mut self) -> u16 {
-        let (upper, lower) = (self.next_byte(), self.next_byte());
-        ((upper as u16) << 8) | (lower as u16)
+    fn next_u16(&mut self) -> Result<u16, VmError> {
+        let (upper, lower) = (self.next_byte()?, self.next_byte()?);
+        Ok(((upper as u16) << 8) | (lower as u16))
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        Opcode::from(self.next_byte())
+    fn decode_opcode(&mut self) -> Result<Opcode, VmError> {
+        Ok(Opcode::from(self.next_byte()?))
     }
 }
 
@@ -152,7 +406,7 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![0, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -161,7 +415,10 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![200, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::IllegalOpcode { opcode: 200, pc: 0 })
+        );
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -169,7 +426,7 @@ mod tests {
     fn test_opcode_load() {
         let mut vm = VM::new();
         vm.program = vec![Opcode::LOAD as u8, 10, 1, 244];
-        vm.execute_instruction();
+        vm.step().unwrap();
         assert_eq!(vm.registers[10], 500);
     }
 
@@ -178,7 +435,7 @@ mod tests {
         let mut vm = VM::new();
         vm.registers[0] = 99;
         vm.program = vec![Opcode::JMP as u8, 0, 0, 0];
-        vm.execute_instruction();
+        vm.step().unwrap();
         assert_eq!(vm.pc, 99);
     }
 
@@ -186,7 +443,7 @@ mod tests {
     fn test_opcode_jmpf() {
         let mut vm = VM::new();
         vm.program = vec![Opcode::JMPF as u8, 10, 0, 0];
-        vm.execute_instruction();
+        vm.step().unwrap();
         assert_eq!(vm.pc, 2 + 10);
     }
 
@@ -195,12 +452,12 @@ mod tests {
         let mut vm = VM::new();
         // The 2 moves us up to JMPB, then the 6 moves us back to the start.
         vm.program = vec![Opcode::JMPF as u8, 2, 0, 0, Opcode::JMPB as u8, 6, 0, 0];
-        vm.execute_instruction();
-        vm.execute_instruction();
+        vm.step().unwrap();
+        vm.step().unwrap();
         assert_eq!(vm.pc, 0);
         // This means we should be able to loop.
-        vm.execute_instruction();
-        vm.execute_instruction();
+        vm.step().unwrap();
+        vm.step().unwrap();
         assert_eq!(vm.pc, 0);
     }
 
@@ -210,23 +467,345 @@ mod tests {
         vm.registers[0] = 10;
         vm.registers[1] = 10;
         vm.program = vec![Opcode::EQ as u8, 0, 1, 0, Opcode::EQ as u8, 0, 1, 0];
-        vm.execute_instruction();
-        assert_eq!(vm.equal_flag, true);
+        vm.step().unwrap();
+        assert!(vm.equal_flag);
         vm.registers[1] = 20;
-        vm.execute_instruction();
-        assert_eq!(vm.equal_flag, false);
+        vm.step().unwrap();
+        assert!(!vm.equal_flag);
+    }
+
+    #[test]
+    fn test_comparison_opcodes() {
+        let mut vm = VM::new();
+        vm.registers[0] = -3;
+        vm.registers[1] = 5;
+        let cases = [
+            (Opcode::NEQ, true, true),
+            (Opcode::GT, false, false),
+            (Opcode::LT, true, false),
+            (Opcode::GTE, false, true),
+            (Opcode::LTE, true, true),
+        ];
+        for &(opcode, less, equal) in &cases {
+            vm.program = vec![opcode as u8, 0, 1, 0, opcode as u8, 1, 1, 0];
+            vm.pc = 0;
+            vm.step().unwrap();
+            assert_eq!(vm.equal_flag, less, "{:?} -3 5", opcode);
+            vm.step().unwrap();
+            assert_eq!(
+                vm.equal_flag,
+                equal != (opcode == Opcode::NEQ),
+                "{:?} 5 5",
+                opcode
+            );
+            assert_eq!(vm.pc, 8);
+        }
+    }
+
+    #[test]
+    fn test_opcode_jneq() {
+        let mut vm = VM::new();
+        vm.registers[0] = 99;
+        vm.program = vec![Opcode::JNEQ as u8, 0, 0, 0, Opcode::JNEQ as u8, 0, 0, 0];
+        vm.equal_flag = true;
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 4);
+        vm.equal_flag = false;
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 99);
+    }
+
+    #[test]
+    fn test_opcode_aloc() {
+        let mut vm = VM::new();
+        vm.registers[0] = 1024;
+        vm.program = vec![Opcode::ALOC as u8, 0, 0, 0, Opcode::ALOC as u8, 0, 0, 0, 0];
+        vm.run().unwrap();
+        assert_eq!(vm.heap.len(), 2048);
+        assert_eq!(vm.pc, 9);
+    }
+
+    #[test]
+    fn test_opcode_aloc_rejects_bad_sizes() {
+        let mut vm = VM::new();
+        vm.program = assembler::assemble("load $0 #1\nload $1 #2\nsub $0 $1 $2\naloc $2").unwrap();
+        assert_eq!(vm.run(), Err(VmError::BadAllocation { bytes: -1, pc: 12 }));
+        assert!(vm.heap.is_empty());
+
+        let mut vm = VM::new();
+        vm.registers[0] = MAX_HEAP as i32;
+        vm.program = vec![Opcode::ALOC as u8, 0, 0, 0, Opcode::ALOC as u8, 0, 0, 0];
+        assert_eq!(
+            vm.run(),
+            Err(VmError::BadAllocation {
+                bytes: MAX_HEAP as i32,
+                pc: 4
+            })
+        );
+        assert_eq!(vm.heap.len(), MAX_HEAP);
+    }
+
+    #[test]
+    fn test_opcodes_inc_and_dec() {
+        let mut vm = VM::new();
+        vm.program = [
+            [Opcode::INC as u8, 0, 0, 0],
+            [Opcode::INC as u8, 0, 0, 0],
+            [Opcode::DEC as u8, 1, 0, 0],
+            [Opcode::HLT as u8, 0, 0, 0],
+        ]
+        .concat();
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 2);
+        assert_eq!(vm.registers[1], -1);
+    }
+
+    #[test]
+    fn test_opcodes_push_and_pop() {
+        let mut vm = VM::new();
+        vm.registers[0] = 7;
+        vm.registers[1] = 8;
+        vm.program = [
+            [Opcode::PUSH as u8, 0, 0, 0],
+            [Opcode::PUSH as u8, 1, 0, 0],
+            [Opcode::POP as u8, 0, 0, 0],
+            [Opcode::POP as u8, 1, 0, 0],
+            [Opcode::HLT as u8, 0, 0, 0],
+        ]
+        .concat();
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 8);
+        assert_eq!(vm.registers[1], 7);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_opcodes_call_and_ret() {
+        let mut vm = VM::new();
+        vm.registers[0] = 8;
+        vm.program = [
+            [Opcode::CALL as u8, 0, 0, 0],
+            [Opcode::HLT as u8, 0, 0, 0],
+            [Opcode::INC as u8, 1, 0, 0],
+            [Opcode::RET as u8, 0, 0, 0],
+        ]
+        .concat();
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 8);
+        assert_eq!(vm.stack, vec![4]);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], 1);
+        assert_eq!(vm.pc, 5);
+    }
+
+    #[test]
+    fn test_opcodes_loadm_and_storem() {
+        let mut vm = VM::new();
+        vm.heap = vec![0; 8];
+        vm.registers[0] = -2;
+        vm.registers[1] = 4;
+        vm.program = [
+            [Opcode::STOREM as u8, 0, 1, 0],
+            [Opcode::LOADM as u8, 2, 1, 0],
+            [Opcode::HLT as u8, 0, 0, 0],
+        ]
+        .concat();
+        vm.run().unwrap();
+        assert_eq!(vm.heap, vec![0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFE]);
+        assert_eq!(vm.registers[2], -2);
+    }
+
+    #[test]
+    fn test_opcodes_send_and_recv() {
+        let mut vm = VM::new();
+        vm.registers[0] = 3;
+        vm.registers[1] = 42;
+        vm.program = [
+            [Opcode::SEND as u8, 0, 1, 0],
+            [Opcode::RECV as u8, 2, 0, 0],
+            [Opcode::HLT as u8, 0, 0, 0],
+        ]
+        .concat();
+        vm.step().unwrap();
+        assert_eq!(vm.outbox, vec![(3, 42)]);
+
+        // Without a message, RECV stays put until one arrives.
+        assert_eq!(vm.run(), Err(VmError::WouldBlock { pc: 4 }));
+        assert_eq!(vm.pc, 4);
+        vm.inbox.push_back(7);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], 7);
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        let mut vm = VM::new();
+        vm.registers[0] = 7;
+        vm.program = [[Opcode::HLT as u8, 0, 0, 0], [Opcode::DIV as u8, 0, 1, 2]].concat();
+        vm.pc = 4;
+        assert_eq!(vm.run(), Err(VmError::DivideByZero { pc: 4 }));
+        assert_eq!(vm.registers[2], 0);
+
+        vm.registers[1] = 2;
+        vm.pc = 4;
+        assert_eq!(vm.step(), Ok(true));
+        assert_eq!(vm.registers[2], 3);
+        assert_eq!(vm.remainder, 1);
+    }
+
+    #[test]
+    fn test_pc_out_of_bounds() {
+        // Running off the end of the program...
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::INC as u8, 0, 0, 0];
+        assert_eq!(vm.run(), Err(VmError::PcOutOfBounds { pc: 4 }));
+        assert_eq!(vm.registers[0], 1);
+
+        // ...or jumping away from it, in either direction.
+        vm.program = vec![Opcode::JMPB as u8, 10, 0, 0];
+        vm.pc = 0;
+        assert!(matches!(vm.run(), Err(VmError::PcOutOfBounds { .. })));
+        vm.program = vec![Opcode::LOAD as u8, 0, 0];
+        vm.pc = 0;
+        assert_eq!(vm.run(), Err(VmError::PcOutOfBounds { pc: 3 }));
+    }
+
+    #[test]
+    fn test_bad_register() {
+        let mut vm = VM::new();
+        vm.program = [[Opcode::HLT as u8, 0, 0, 0], [Opcode::ADD as u8, 0, 32, 1]].concat();
+        vm.pc = 4;
+        assert_eq!(
+            vm.run(),
+            Err(VmError::BadRegister {
+                register: 32,
+                pc: 4
+            })
+        );
+        assert_eq!(
+            VmError::BadRegister {
+                register: 32,
+                pc: 4
+            }
+            .to_string(),
+            "no register $32 (at pc 4)"
+        );
+    }
+
+    #[test]
+    fn test_heap_and_stack_faults() {
+        let mut vm = VM::new();
+        vm.heap = vec![0; 4];
+        vm.registers[1] = 1;
+        vm.program = vec![Opcode::LOADM as u8, 0, 1, 0];
+        assert_eq!(
+            vm.run(),
+            Err(VmError::HeapOutOfBounds { address: 1, pc: 0 })
+        );
+
+        vm.program = vec![Opcode::RET as u8, 0, 0, 0];
+        vm.pc = 0;
+        assert_eq!(vm.run(), Err(VmError::StackUnderflow { pc: 0 }));
+    }
+
+    #[test]
+    fn test_errors_report_their_pc() {
+        let mut vm = VM::new();
+        vm.program = assembler::assemble("load $0 #1\nload $1 #0\ndiv $0 $1 $2").unwrap();
+        vm.pc = 8;
+        assert_eq!(
+            vm.execute_instruction(8),
+            Err(VmError::DivideByZero { pc: 8 })
+        );
+    }
+
+    fn run_source(source: &str) -> VM {
+        let mut vm = VM::new();
+        vm.program = assembler::assemble(source).unwrap();
+        vm.run().unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_recursion() {
+        let vm = run_source(
+            "
+                load $0 #5
+                load $2 #1
+                load $5 @fact
+                call $5
+                hlt
+            ; $1 = $0!, using $2 = 1 and $5 = @fact
+            fact:
+                load $6 @base
+                lte $0 $2
+                jeq $6
+                push $0
+                dec $0
+                call $5
+                pop $0
+                mul $0 $1 $1
+                ret
+            base:
+                load $1 #1
+                ret
+            ",
+        );
+        assert_eq!(vm.registers[1], 120);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_memory_loops() {
+        // Store the first four squares on the heap, then sum them.
+        let vm = run_source(
+            "
+                load $0 #16
+                aloc $0
+                load $1 #0
+                load $2 #4
+                load $3 #0
+                load $4 #4
+                load $7 @fill
+            fill:
+                mul $1 $1 $5
+                storem $5 $3
+                add $3 $4 $3
+                inc $1
+                lt $1 $2
+                jeq $7
+
+                load $1 #0
+                load $3 #0
+                load $6 #0
+                load $7 @sum
+            sum:
+                loadm $5 $3
+                add $6 $5 $6
+                add $3 $4 $3
+                inc $1
+                lt $1 $2
+                jeq $7
+                hlt
+            ",
+        );
+        assert_eq!(
+            vm.heap,
+            vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 9]
+        );
+        assert_eq!(vm.registers[6], 14);
     }
 
     #[test]
     fn test_u8_to_u16_conversion() {
         let mut vm = VM::new();
         vm.program = vec![0, u8::MAX];
-        assert_eq!(vm.next_u16(), u8::MAX as u16);
+        assert_eq!(vm.next_u16(), Ok(u8::MAX as u16));
 
         vm.program.extend_from_slice(&[2, 0]);
-        assert_eq!(vm.next_u16(), (u8::MAX as u16 + 1) * 2);
+        assert_eq!(vm.next_u16(), Ok((u8::MAX as u16 + 1) * 2));
 
         vm.program.extend_from_slice(&[u8::MAX, u8::MAX]);
-        assert_eq!(vm.next_u16(), u16::MAX);
+        assert_eq!(vm.next_u16(), Ok(u16::MAX));
     }
 }
@@ -0,0 +1,356 @@
+//! An interactive shell for the VM. Each line of input is either assembly
+//! (`load $0 #10`) or hex bytes (`01 00 00 0a`), and is appended to the
+//! program and executed straight away. Lines starting with a `.` are commands
+//! for looking around; see `.help`.
+
+use super::{
+    assembler::{assemble_at, INSTRUCTION_SIZE},
+    bytecode::{self, Bytecode},
+    VM,
+};
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{prelude::*, BufReader},
+};
+
+const HELP: &str = "\
+Enter assembly (`load $0 #10`) or hex bytes (`01 00 00 0a`) to run them.
+commands:
+  .registers        show every register, the equality flag and remainder
+  .program          dump the program, marking the pc
+  .pc               show the program counter
+  .load_file <path> assemble a file, append it to the program and run it;
+                    bytecode files replace the program instead
+  .save <path>      save the program as a bytecode file
+  .clear            start again with an empty VM
+  .help             show this message
+  .quit             exit";
+
+/// How many instructions a single line may run, so that an infinite loop
+/// doesn't hang the shell.
+const STEP_LIMIT: usize = 1_000_000;
+
+pub fn start(input: impl Read, mut out: impl Write) {
+    let mut br = BufReader::new(input);
+    let mut repl = Repl::new();
+
+    let _ = writeln!(out, "Register VM. Type `.help` for a list of commands.");
+    loop {
+        let _ = write!(out, ">> ");
+        let _ = out.flush();
+
+        let mut line = String::new();
+        match br.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        match repl.eval(&line) {
+            Some(output) if output.is_empty() => {}
+            Some(output) => {
+                let _ = writeln!(out, "{}", output.trim_end());
+            }
+            None => break,
+        }
+    }
+}
+
+pub struct Repl {
+    vm: VM,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self { vm: VM::new() }
+    }
+
+    /// Evaluates one line of input, returning what to show the user, or None
+    /// if they asked to quit.
+    pub fn eval(&mut self, line: &str) -> Option<String> {
+        let line = line.trim();
+        let mut words = line.split_whitespace();
+        let output = match words.next().unwrap_or("") {
+            "" => Ok(String::new()),
+            ".quit" | ".exit" => return None,
+            ".help" => Ok(HELP.to_string()),
+            ".registers" => Ok(self.registers()),
+            ".program" => Ok(self.program()),
+            ".pc" => Ok(self.vm.pc.to_string()),
+            ".clear" => {
+                self.vm = VM::new();
+                Ok("cleared".to_string())
+            }
+            ".load_file" => match words.next() {
+                Some(path) => self.load_file(path),
+                None => Err("usage: .load_file <path>".to_string()),
+            },
+            ".save" => match words.next() {
+                Some(path) => Bytecode::new(self.vm.program.clone())
+                    .write(path)
+                    .map(|_| format!("saved {} bytes to {}", self.vm.program.len(), path))
+                    .map_err(|e| format!("could not write {}: {}", path, e)),
+                None => Err("usage: .save <path>".to_string()),
+            },
+            command if command.starts_with('.') => {
+                Err(format!("unknown command `{}`; try `.help`", command))
+            }
+            _ => match parse_hex(line) {
+                Some(bytes) if bytes.len() % INSTRUCTION_SIZE != 0 => Err(format!(
+                    "instructions are {} bytes long, but got {} bytes",
+                    INSTRUCTION_SIZE,
+                    bytes.len()
+                )),
+                Some(bytes) => Ok(self.execute(bytes)),
+                None => self.assemble(line).map(|bytes| self.execute(bytes)),
+            },
+        };
+        Some(output.unwrap_or_else(|e| format!("error: {}", e)))
+    }
+
+    fn load_file(&mut self, path: &str) -> Result<String, String> {
+        let bytes = fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        if bytes.starts_with(bytecode::MAGIC) {
+            let bytecode = Bytecode::from_bytes(&bytes).map_err(|e| e.to_string())?;
+            self.vm.load(&bytecode).map_err(|e| e.to_string())?;
+            return Ok(self.run(0));
+        }
+        let source = String::from_utf8(bytes).map_err(|_| format!("{} is not text", path))?;
+        self.assemble(&source).map(|bytes| self.execute(bytes))
+    }
+
+    /// Assembles `source` to go on the end of the program.
+    fn assemble(&self, source: &str) -> Result<Vec<u8>, String> {
+        assemble_at(source, self.vm.program.len()).map_err(|errors| {
+            let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+            errors.join("\nerror: ")
+        })
+    }
+
+    /// Appends `bytes` to the program and runs up to the new end of it.
+    fn execute(&mut self, bytes: Vec<u8>) -> String {
+        let start = self.vm.program.len();
+        self.vm.program.extend(bytes);
+        self.run(start)
+    }
+
+    /// Runs up to the end of the program, reporting any registers that
+    /// changed. If anything goes wrong, the VM is put back how it was before
+    /// running, with the program cut back to `start` (the end of whatever ran
+    /// successfully before).
+    fn run(&mut self, start: usize) -> String {
+        let registers = self.vm.registers;
+        let snapshot = self.vm.clone();
+
+        let mut out = String::new();
+        let mut steps = 0;
+        while self.vm.pc < self.vm.program.len() {
+            if steps == STEP_LIMIT {
+                let _ = writeln!(out, "stopped after {} steps", STEP_LIMIT);
+                break;
+            }
+            steps += 1;
+            match self.vm.step() {
+                Ok(true) => {}
+                Ok(false) => {
+                    out.push_str("halted\n");
+                    break;
+                }
+                Err(e) => {
+                    self.vm = snapshot;
+                    self.vm.program.truncate(start);
+                    self.vm.pc = start;
+                    return format!("error: {}", e);
+                }
+            }
+        }
+        // Carry on from the end of the program next time, whether we halted
+        // part way through an instruction or jumped off somewhere else.
+        self.vm.pc = self.vm.program.len();
+
+        for (i, (old, new)) in registers.iter().zip(&self.vm.registers).enumerate() {
+            if old != new {
+                let _ = writeln!(out, "${} = {}", i, new);
+            }
+        }
+        out
+    }
+
+    fn registers(&self) -> String {
+        let mut out = String::new();
+        for (i, registers) in self.vm.registers.chunks(4).enumerate() {
+            for (j, val) in registers.iter().enumerate() {
+                let name = format!("${}", i * 4 + j);
+                let _ = write!(out, "{:>4} = {:<12}", name, val);
+            }
+            out.push('\n');
+        }
+        let _ = write!(
+            out,
+            "equal_flag = {}, remainder = {}",
+            self.vm.equal_flag, self.vm.remainder
+        );
+        out
+    }
+
+    fn program(&self) -> String {
+        let mut out = String::new();
+        for (i, instruction) in self.vm.program.chunks(INSTRUCTION_SIZE).enumerate() {
+            let address = i * INSTRUCTION_SIZE;
+            let marker = if address == self.vm.pc { '>' } else { ' ' };
+            let bytes: Vec<_> = instruction.iter().map(|b| format!("{:02x}", b)).collect();
+            let _ = writeln!(out, "{} {:04}: {}", marker, address, bytes.join(" "));
+        }
+        if self.vm.pc == self.vm.program.len() {
+            let _ = writeln!(out, "> {:04}:", self.vm.pc);
+        }
+        out
+    }
+}
+
+/// Parses a line made up entirely of two-digit hex bytes.
+fn parse_hex(line: &str) -> Option<Vec<u8>> {
+    line.split_whitespace()
+        .map(|byte| match byte.len() {
+            2 => u8::from_str_radix(byte, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(repl: &mut Repl, line: &str) -> String {
+        repl.eval(line).expect("repl should not quit")
+    }
+
+    #[test]
+    fn runs_assembly_incrementally() {
+        let mut repl = Repl::new();
+        assert_eq!(eval(&mut repl, "load $0 #10"), "$0 = 10\n");
+        assert_eq!(eval(&mut repl, "load $1 #32"), "$1 = 32\n");
+        assert_eq!(eval(&mut repl, "add $0 $1 $2"), "$2 = 42\n");
+        assert_eq!(eval(&mut repl, ".pc"), "12");
+        assert_eq!(eval(&mut repl, "hlt"), "halted\n");
+        // Carrying on after a halt works too.
+        assert_eq!(eval(&mut repl, "inc $2"), "$2 = 43\n");
+        assert_eq!(eval(&mut repl, ".pc"), "20");
+    }
+
+    #[test]
+    fn runs_hex_bytes() {
+        let mut repl = Repl::new();
+        assert_eq!(eval(&mut repl, "01 0a 01 f4"), "$10 = 500\n");
+        assert!(eval(&mut repl, "01 0a").contains("4 bytes long"));
+        // Anything that isn't all hex bytes is assembly.
+        assert!(eval(&mut repl, "01 0a 01 zz").contains("unknown opcode `01`"));
+    }
+
+    #[test]
+    fn faulting_input_is_discarded() {
+        let mut repl = Repl::new();
+        eval(&mut repl, "load $0 #1");
+        assert_eq!(
+            eval(&mut repl, "div $0 $1 $2"),
+            "error: division by zero at pc 4"
+        );
+        assert_eq!(eval(&mut repl, ".pc"), "4");
+        assert_eq!(
+            eval(&mut repl, "add $0 $99 $1"),
+            "error: line 1: `$99` is not a register ($0 to $31)"
+        );
+        assert_eq!(
+            eval(&mut repl, "l: jmpf @l"),
+            "error: line 1: `@l` is out of range"
+        );
+        assert_eq!(
+            eval(&mut repl, "l: load $9 @l\njmp $9"),
+            "stopped after 1000000 steps\n$9 = 4\n"
+        );
+    }
+
+    #[test]
+    fn faulting_input_leaves_the_vm_alone() {
+        let mut repl = Repl::new();
+        eval(&mut repl, "load $0 #1");
+        let registers = eval(&mut repl, ".registers");
+        let output = eval(
+            &mut repl,
+            "load $0 #9\npush $0\naloc $0\neq $0 $0\nload $3 #0\ndiv $0 $3 $2",
+        );
+        assert!(output.starts_with("error: division by zero"), "{}", output);
+        assert_eq!(eval(&mut repl, ".registers"), registers);
+        assert!(repl.vm.stack.is_empty() && repl.vm.heap.is_empty());
+    }
+
+    #[test]
+    fn inspects_the_vm() {
+        let mut repl = Repl::new();
+        eval(&mut repl, "load $5 #7");
+        let registers = eval(&mut repl, ".registers");
+        assert!(registers.contains("$5 = 7 "), "{}", registers);
+        assert!(registers.ends_with("equal_flag = false, remainder = 0"));
+        assert_eq!(
+            eval(&mut repl, ".program"),
+            "  0000: 01 05 00 07\n> 0004:\n"
+        );
+
+        assert_eq!(eval(&mut repl, ".clear"), "cleared");
+        assert_eq!(eval(&mut repl, ".program"), "> 0000:\n");
+        assert!(eval(&mut repl, ".bogus").contains("unknown command"));
+        assert!(repl.eval(".quit").is_none());
+    }
+
+    #[test]
+    fn loads_files() {
+        let name = format!("old_vm_repl_test_{}.asm", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        fs::write(
+            &path,
+            "load $1 #3\nl: dec $1\nload $2 @l\nload $3 #0\nneq $1 $3\njeq $2\n",
+        )
+        .unwrap();
+
+        let mut repl = Repl::new();
+        eval(&mut repl, "load $0 #1");
+        // Labels in the file point into the program after what's already there.
+        let output = eval(&mut repl, &format!(".load_file {}", path.display()));
+        assert_eq!(output, "$2 = 8\n");
+        assert!(eval(&mut repl, ".load_file /nonexistent").contains("could not read"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn saves_and_loads_bytecode() {
+        let name = format!("old_vm_repl_test_{}.rvm", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let path = path.display().to_string();
+
+        let mut repl = Repl::new();
+        eval(&mut repl, "load $0 #6");
+        eval(&mut repl, "load $1 #7");
+        eval(&mut repl, "mul $0 $1 $2");
+        assert_eq!(
+            eval(&mut repl, &format!(".save {}", path)),
+            format!("saved 12 bytes to {}", path)
+        );
+
+        // Loading it replaces the program, and runs it from the start.
+        eval(&mut repl, ".clear");
+        eval(&mut repl, "load $9 #1");
+        let output = eval(&mut repl, &format!(".load_file {}", path));
+        assert_eq!(output, "$0 = 6\n$1 = 7\n$2 = 42\n");
+        assert_eq!(eval(&mut repl, ".pc"), "12");
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.push(0);
+        fs::write(&path, bytes).unwrap();
+        let output = eval(&mut repl, &format!(".load_file {}", path));
+        assert_eq!(
+            output,
+            "error: bytecode file has 1 unexpected trailing bytes"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
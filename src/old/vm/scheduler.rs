@@ -0,0 +1,271 @@
+//! Runs several VMs on one machine, taking turns.
+//!
+//! Each VM gets up to `quantum` instructions at a time, in the order they were
+//! spawned, so the same programs always interleave in exactly the same way.
+//! VMs talk to each other with SEND and RECV: a VM's id is the order it was
+//! spawned in, and a message is delivered as soon as it's sent. A VM that
+//! RECVs with an empty inbox gives up the rest of its turn, and waits until a
+//! message arrives.
+
+use super::{assembler::INSTRUCTION_SIZE, VmError, VM};
+use std::{convert::TryFrom, fmt, mem};
+
+pub type VmId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// Stuck on a RECV until somebody sends it a message.
+    Waiting,
+    Halted,
+    /// Stopped by an error. Nothing else is affected.
+    Faulted(VmError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchedulerError {
+    /// Every VM left is waiting for a message that nobody can send.
+    Deadlock(Vec<VmId>),
+}
+
+impl fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedulerError::Deadlock(ids) => {
+                write!(f, "deadlock: VMs {:?} are all waiting for messages", ids)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SchedulerError {}
+
+#[derive(Debug)]
+struct Process {
+    vm: VM,
+    state: State,
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    processes: Vec<Process>,
+    quantum: usize,
+}
+
+impl Scheduler {
+    /// A scheduler that runs each VM for `quantum` instructions per turn.
+    pub fn new(quantum: usize) -> Self {
+        assert!(
+            quantum > 0,
+            "VMs need to run at least one instruction a turn"
+        );
+        Self {
+            processes: vec![],
+            quantum,
+        }
+    }
+
+    /// Adds a VM, ready to run from its current pc, and returns its id.
+    pub fn spawn(&mut self, vm: VM) -> VmId {
+        self.processes.push(Process {
+            vm,
+            state: State::Running,
+        });
+        self.processes.len() - 1
+    }
+
+    pub fn vm(&self, id: VmId) -> &VM {
+        &self.processes[id].vm
+    }
+
+    pub fn state(&self, id: VmId) -> State {
+        self.processes[id].state
+    }
+
+    /// Gives every VM that can run a turn, returning how many instructions
+    /// were executed altogether.
+    pub fn round(&mut self) -> usize {
+        let mut executed = 0;
+        for id in 0..self.processes.len() {
+            let process = &mut self.processes[id];
+            if process.state == State::Waiting && !process.vm.inbox.is_empty() {
+                process.state = State::Running;
+            }
+            if process.state != State::Running {
+                continue;
+            }
+            for _ in 0..self.quantum {
+                let state = match self.processes[id].vm.step() {
+                    Ok(running) => {
+                        executed += 1;
+                        match self.deliver(id) {
+                            Err(e) => State::Faulted(e),
+                            Ok(()) if running => continue,
+                            Ok(()) => State::Halted,
+                        }
+                    }
+                    Err(VmError::WouldBlock { .. }) => State::Waiting,
+                    Err(e) => State::Faulted(e),
+                };
+                self.processes[id].state = state;
+                break;
+            }
+        }
+        executed
+    }
+
+    /// Runs until every VM has halted or faulted.
+    pub fn run(&mut self) -> Result<(), SchedulerError> {
+        while self.round() > 0 {}
+        let waiting: Vec<_> = (0..self.processes.len())
+            .filter(|&id| self.state(id) == State::Waiting)
+            .collect();
+        if waiting.is_empty() {
+            Ok(())
+        } else {
+            Err(SchedulerError::Deadlock(waiting))
+        }
+    }
+
+    /// Moves anything VM `from` just sent into the recipients' inboxes.
+    fn deliver(&mut self, from: VmId) -> Result<(), VmError> {
+        let sender = &mut self.processes[from].vm;
+        if sender.outbox.is_empty() {
+            return Ok(());
+        }
+        // The only instruction that sends is the SEND that just ran.
+        let pc = sender.pc - INSTRUCTION_SIZE;
+        for (to, val) in mem::take(&mut sender.outbox) {
+            let recipient = usize::try_from(to)
+                .ok()
+                .and_then(|to| self.processes.get_mut(to))
+                .ok_or(VmError::NoSuchVm { id: to, pc })?;
+            recipient.vm.inbox.push_back(val);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::old::vm::assembler::assemble;
+
+    fn vm(source: &str) -> VM {
+        let mut vm = VM::new();
+        vm.program = assemble(source).unwrap();
+        vm
+    }
+
+    /// Sends `marker` to VM 2 three times.
+    fn sender(marker: i32) -> VM {
+        vm(&format!(
+            "load $0 #2\nload $1 #{}\nsend $0 $1\nsend $0 $1\nsend $0 $1\nhlt",
+            marker
+        ))
+    }
+
+    /// Receives six messages, and writes them down as the digits of $2.
+    fn logger() -> VM {
+        vm("
+            load $0 #6
+            load $9 #10
+        loop:
+            recv $1
+            mul $2 $9 $2
+            add $2 $1 $2
+            dec $0
+            load $3 #0
+            neq $0 $3
+            load $3 @loop
+            jeq $3
+            hlt
+        ")
+    }
+
+    #[test]
+    fn vms_pass_messages() {
+        // VM 0 sends 1 to 5 to VM 1, which sends back double, until it's sent 0.
+        let client = vm("
+            load $0 #1
+            load $1 #1
+            load $2 #6
+        loop:
+            send $0 $1
+            recv $4
+            add $3 $4 $3
+            inc $1
+            neq $1 $2
+            load $5 @loop
+            jeq $5
+            load $1 #0
+            send $0 $1
+            hlt
+        ");
+        let doubler = vm("
+            load $1 #2
+            load $3 #0
+            load $5 @done
+        loop:
+            recv $2
+            eq $2 $3
+            jeq $5
+            mul $2 $1 $2
+            send $0 $2
+            jmpb @loop
+        done:
+            hlt
+        ");
+
+        let mut scheduler = Scheduler::new(3);
+        assert_eq!(scheduler.spawn(client), 0);
+        assert_eq!(scheduler.spawn(doubler), 1);
+        scheduler.run().unwrap();
+        assert_eq!(scheduler.vm(0).registers[3], 30);
+        assert_eq!(scheduler.state(0), State::Halted);
+        assert_eq!(scheduler.state(1), State::Halted);
+    }
+
+    #[test]
+    fn scheduling_is_deterministic() {
+        let run = |quantum| {
+            let mut scheduler = Scheduler::new(quantum);
+            scheduler.spawn(sender(1));
+            scheduler.spawn(sender(2));
+            scheduler.spawn(logger());
+            scheduler.run().unwrap();
+            scheduler.vm(2).registers[2]
+        };
+        // Taking turns an instruction at a time, the senders' messages
+        // alternate; given long enough turns, the first sends everything first.
+        assert_eq!(run(1), 121212);
+        assert_eq!(run(1), 121212);
+        assert_eq!(run(4), 112212);
+        assert_eq!(run(100), 111222);
+    }
+
+    #[test]
+    fn reports_deadlocks() {
+        let mut scheduler = Scheduler::new(10);
+        scheduler.spawn(vm("recv $0\nhlt"));
+        scheduler.spawn(vm("hlt"));
+        scheduler.spawn(vm("recv $0\nhlt"));
+        assert_eq!(scheduler.run(), Err(SchedulerError::Deadlock(vec![0, 2])));
+        assert_eq!(scheduler.state(0), State::Waiting);
+        assert_eq!(scheduler.state(1), State::Halted);
+    }
+
+    #[test]
+    fn faults_only_stop_the_faulting_vm() {
+        let mut scheduler = Scheduler::new(1);
+        scheduler.spawn(vm("load $0 #5\nsend $0 $0\nhlt"));
+        scheduler.spawn(vm("inc $0\ninc $0\nhlt"));
+        scheduler.run().unwrap();
+        assert_eq!(
+            scheduler.state(0),
+            State::Faulted(VmError::NoSuchVm { id: 5, pc: 4 })
+        );
+        assert_eq!(scheduler.state(1), State::Halted);
+        assert_eq!(scheduler.vm(1).registers[0], 2);
+    }
+}